@@ -0,0 +1,398 @@
+use std::{
+    io::{ErrorKind, Write},
+    path::Path,
+    process::{Command, Stdio}
+};
+
+use image::{
+    codecs::gif::{GifEncoder, Repeat},
+    imageops, Delay, DynamicImage, Frame, ImageFormat, Rgb, RgbImage
+};
+
+use crate::{
+    img::write_atomically,
+    sort::{rgb8_key_map, rgb8_pixel_sort, SortOptions},
+    AnimateParams, AnimateValue, ArgumentList, ColorChannel, OutputTemplate, PixelSort, PxsortError, TemplateValues,
+    WalkPath
+};
+
+/// Delay between two frames of the GIF in milliseconds
+const FRAME_DELAY_MS: u32 = 100;
+
+/// Set the animated parameter on `options` to `value`
+fn apply_param(options: &mut SortOptions, param: ArgumentList, value: AnimateValue) -> Result<(), PxsortError> {
+    match (param, value) {
+        (ArgumentList::Interval, AnimateValue::Integer(value)) => options.interval = vec![(value as usize).max(1)],
+        (ArgumentList::Discretize, AnimateValue::Integer(value)) => options.discretize = value.max(1),
+        (ArgumentList::Direction, AnimateValue::Integer(value)) => {
+            options.direction = match value % 2 {
+                0 => WalkPath::Horizontal,
+                _ => WalkPath::Vertical
+            }
+        }
+        (ArgumentList::EdgeThreshold, AnimateValue::Integer(value)) => options.edge_threshold = Some(value),
+        (ArgumentList::ImageThreshold, AnimateValue::Integer(value)) => {
+            options.image_threshold = Some(value.min(u8::MAX as u64) as u8)
+        }
+        (ArgumentList::Channel, AnimateValue::Integer(value)) => {
+            options.channel = Some(match value % 3 {
+                0 => ColorChannel::Red,
+                1 => ColorChannel::Green,
+                _ => ColorChannel::Blue
+            })
+        }
+        (ArgumentList::Splice, AnimateValue::Float(value)) => options.splice = Some(value),
+        (ArgumentList::RedCoefficient, AnimateValue::Float(value)) => options.coefficients.red = value as f32,
+        (ArgumentList::GreenCoefficient, AnimateValue::Float(value)) => options.coefficients.green = value as f32,
+        (ArgumentList::BlueCoefficient, AnimateValue::Float(value)) => options.coefficients.blue = value as f32,
+        (ArgumentList::ImageMask, _) => return Err(PxsortError::NotAnimatable(param)),
+        (param, value) => return Err(PxsortError::AnimateValueMismatch { param, value })
+    }
+
+    Ok(())
+}
+
+/// Lazily sort `image` once for every value of the animated parameters
+///
+/// Every frame starts from the original `image`. All parameters are swept together,
+/// frame `n` gets the `n`th value of each. With `sort_key_cache` set the keys are
+/// extracted once up front, unless an animated parameter changes them
+pub fn frames<'a>(
+    image: &'a RgbImage,
+    params: &'a [AnimateParams],
+    base: &'a SortOptions
+) -> Result<impl Iterator<Item = RgbImage> + 'a, PxsortError> {
+    let sweeps = params.iter().map(|params| params.range.values()).collect::<Vec<_>>();
+
+    // all values of a range have the same type, so if one of them applies, all of them do
+    for (params, values) in params.iter().zip(&sweeps) {
+        if let Some(&value) = values.first() {
+            apply_param(&mut base.clone(), params.param, value)?;
+        }
+    }
+
+    // the diagonals are sorted on a sheared copy, which the cached keys don't fit
+    let cacheable = base.curve.is_none() && base.diagonals.is_none();
+    let keys = (base.sort_key_cache && cacheable && !params.iter().any(|params| params.param.affects_keys()))
+        .then(|| rgb8_key_map(image, base));
+    let count = sweeps.iter().map(Vec::len).min().unwrap_or(0);
+
+    Ok((0..count).map(move |index| {
+        let mut options = base.clone();
+        for (params, values) in params.iter().zip(&sweeps) {
+            apply_param(&mut options, params.param, values[index])
+                .expect("the value was checked before the first frame");
+        }
+
+        let mut frame = image.clone();
+        match &keys {
+            Some(keys) => {
+                rgb8_pixel_sort(&mut frame, options, Some(keys));
+            }
+            None => frame.sort_rgb8_pixels(options)
+        }
+
+        frame
+    }))
+}
+
+/// Sort `image` once for every value of the animated parameters, collecting all frames
+pub fn animate_frames(
+    image: &RgbImage,
+    options: &SortOptions,
+    params: &[AnimateParams]
+) -> Result<Vec<RgbImage>, PxsortError> {
+    Ok(frames(image, params, options)?.collect())
+}
+
+/// Center every frame on a canvas as large as the largest frame, filled with `color`
+///
+/// Frames that already have the canvas size are left as they are
+pub fn pad_frames(frames: Vec<RgbImage>, color: Rgb<u8>) -> Vec<RgbImage> {
+    let width = frames.iter().map(RgbImage::width).max().unwrap_or(0);
+    let height = frames.iter().map(RgbImage::height).max().unwrap_or(0);
+
+    frames
+        .into_iter()
+        .map(|frame| {
+            if frame.dimensions() == (width, height) {
+                return frame;
+            }
+
+            let mut canvas = RgbImage::from_pixel(width, height, color);
+            let x = (width - frame.width()) / 2;
+            let y = (height - frame.height()) / 2;
+            imageops::overlay(&mut canvas, &frame, x as i64, y as i64);
+
+            canvas
+        })
+        .collect()
+}
+
+/// Encode the frames into an infinitely looping GIF at `path`
+pub fn write_gif<P: AsRef<Path>>(frames: &[RgbImage], path: P) -> Result<(), PxsortError> {
+    write_atomically(path.as_ref(), |writer| {
+        let mut encoder = GifEncoder::new(writer);
+        encoder.set_repeat(Repeat::Infinite)?;
+        encoder.encode_frames(frames.iter().map(|frame| {
+            Frame::from_parts(
+                DynamicImage::ImageRgb8(frame.clone()).into_rgba8(),
+                0,
+                0,
+                Delay::from_numer_denom_ms(FRAME_DELAY_MS, 1)
+            )
+        }))?;
+
+        Ok(())
+    })
+}
+
+/// Encode already timed frames into an infinitely looping GIF at `path`, each shown for its own delay
+pub fn write_gif_frames<P: AsRef<Path>>(frames: Vec<Frame>, path: P) -> Result<(), PxsortError> {
+    write_atomically(path.as_ref(), |writer| {
+        let mut encoder = GifEncoder::new(writer);
+        encoder.set_repeat(Repeat::Infinite)?;
+        encoder.encode_frames(frames)?;
+
+        Ok(())
+    })
+}
+
+/// Extensions of the video formats [`write_video`] can encode into
+pub const VIDEO_EXTENSIONS: [&str; 4] = ["mp4", "webm", "mkv", "mov"];
+
+/// Whether `path` names one of the [`VIDEO_EXTENSIONS`]
+pub fn is_video(path: &Path) -> bool {
+    path.extension()
+        .map_or(false, |extension| VIDEO_EXTENSIONS.iter().any(|video| extension.eq_ignore_ascii_case(video)))
+}
+
+/// Encode the frames into a video at `path` by piping them to `ffmpeg` as raw RGB, in order
+///
+/// The frames have to be the same size, like the ones of [`pad_frames`]. Odd sizes are padded
+/// by a pixel, most codecs only take even ones
+pub fn write_video<P: AsRef<Path>>(frames: &[RgbImage], path: P) -> Result<(), PxsortError> {
+    let path = path.as_ref();
+    let io_error = |source| PxsortError::Io { path: path.to_path_buf(), source };
+    let (width, height) = frames.first().map_or((0, 0), RgbImage::dimensions);
+
+    let mut ffmpeg = Command::new("ffmpeg")
+        .args(["-y", "-loglevel", "error", "-f", "rawvideo", "-pixel_format", "rgb24"])
+        .args(["-video_size", &format!("{}x{}", width, height)])
+        .args(["-framerate", &format!("{}", 1000.0 / FRAME_DELAY_MS as f64)])
+        .args(["-i", "-", "-vf", "pad=ceil(iw/2)*2:ceil(ih/2)*2", "-pix_fmt", "yuv420p"])
+        .arg(path)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|error| match error.kind() {
+            ErrorKind::NotFound => PxsortError::FfmpegNotFound,
+            _ => io_error(error)
+        })?;
+
+    // closing stdin tells ffmpeg that there are no more frames
+    let mut stdin = ffmpeg.stdin.take().expect("the stdin of ffmpeg is piped");
+    let written = frames.iter().try_for_each(|frame| stdin.write_all(frame.as_raw()));
+    drop(stdin);
+
+    // if ffmpeg gave up early, its exit status says more than the broken pipe
+    let status = ffmpeg.wait().map_err(io_error)?;
+    if !status.success() {
+        return Err(PxsortError::FfmpegFailed { path: path.to_path_buf(), status });
+    }
+
+    written.map_err(io_error)
+}
+
+/// Write every frame into `dir`, named after `template`
+///
+/// The `{stem}` and `{ext}` tokens are taken from the `input` path.
+/// With two animated parameters `{param}` and `{value}` list both, joined by `+`.
+/// With `no_clobber` no frame is written if any of their files already exists
+pub fn write_frames<P: AsRef<Path>>(
+    frames: &[RgbImage],
+    dir: P,
+    template: &OutputTemplate,
+    input: &Path,
+    params: &[AnimateParams],
+    no_clobber: bool
+) -> Result<(), PxsortError> {
+    let dir = dir.as_ref();
+    std::fs::create_dir_all(dir).map_err(|source| PxsortError::Io { path: dir.to_path_buf(), source })?;
+
+    let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = input.extension().unwrap_or_default().to_string_lossy();
+    let param = params.iter().map(|params| params.param.to_string()).collect::<Vec<_>>().join("+");
+    let sweeps = params.iter().map(|params| params.range.values()).collect::<Vec<_>>();
+
+    let paths = (0..frames.len())
+        .map(|index| {
+            let value = sweeps.iter().map(|values| values[index].to_string()).collect::<Vec<_>>().join("+");
+            dir.join(template.render(&TemplateValues {
+                stem: &stem,
+                ext: &ext,
+                index,
+                param: &param,
+                value: &value
+            }))
+        })
+        .collect::<Vec<_>>();
+
+    if let Some(path) = paths.iter().find(|path| no_clobber && path.exists()) {
+        return Err(PxsortError::AlreadyExists { path: path.clone() });
+    }
+
+    for (frame, path) in frames.iter().zip(paths) {
+        write_atomically(&path, |writer| Ok(frame.write_to(writer, ImageFormat::from_path(&path)?)?))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sort::test_options;
+
+    #[test]
+    fn every_frame_gets_a_zero_padded_file() {
+        let options = test_options(&["luma", "-a", "interval 1 12 1", "--seed", "1"]);
+        let image = RgbImage::from_fn(6, 4, |x, y| Rgb([(x * 40) as u8, (y * 60) as u8, 90]));
+        let frames = animate_frames(&image, &options, &options.animate).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let template = OutputTemplate::new("frame_{index}.png").unwrap();
+        write_frames(&frames, dir.path(), &template, Path::new("photo.png"), &options.animate, false).unwrap();
+
+        let mut names = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+
+        let expected = (0..12).map(|index| format!("frame_{:04}.png", index)).collect::<Vec<_>>();
+        assert_eq!(frames.len(), 12);
+        assert_eq!(names, expected);
+        assert_eq!(names[10], "frame_0010.png");
+    }
+    #[test]
+    fn frames_are_not_written_over_existing_files_under_no_clobber() {
+        let options = test_options(&["luma", "-a", "interval 1 3 1", "--seed", "1"]);
+        let image = RgbImage::from_fn(6, 4, |x, y| Rgb([(x * 40) as u8, (y * 60) as u8, 90]));
+        let frames = animate_frames(&image, &options, &options.animate).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let existing = dir.path().join("frame_0001.png");
+        std::fs::write(&existing, "keep me").unwrap();
+
+        let template = OutputTemplate::new("frame_{index}.png").unwrap();
+        let written = write_frames(&frames, dir.path(), &template, Path::new("photo.png"), &options.animate, true);
+
+        assert!(matches!(written, Err(PxsortError::AlreadyExists { path }) if path == existing));
+        assert_eq!(std::fs::read(&existing).unwrap(), b"keep me");
+        assert!(!dir.path().join("frame_0000.png").exists());
+
+        write_frames(&frames, dir.path(), &template, Path::new("photo.png"), &options.animate, false).unwrap();
+        assert!(image::open(&existing).is_ok());
+    }
+
+    #[test]
+    fn the_template_names_the_frames_of_a_sweep() {
+        let options = test_options(&["luma", "-a", "interval 4 6 2", "--seed", "1"]);
+        let image = RgbImage::from_fn(6, 4, |x, y| Rgb([(x * 40) as u8, (y * 60) as u8, 90]));
+        let frames = animate_frames(&image, &options, &options.animate).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let template = OutputTemplate::new("{stem}_{param}_{value}_{index}.{ext}").unwrap();
+        write_frames(&frames, dir.path(), &template, Path::new("photo.png"), &options.animate, false).unwrap();
+
+        let mut names = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+
+        assert_eq!(names, ["photo_interval_4_0000.png", "photo_interval_6_0001.png"]);
+    }
+
+    #[test]
+    fn cached_keys_give_the_same_frames() {
+        let args = ["hue", "-a", "interval 2 10 2", "--seed", "4"];
+        let image = RgbImage::from_fn(12, 9, |x, y| Rgb([(x * 21) as u8, (y * 28) as u8, ((x ^ y) * 17) as u8]));
+
+        let uncached = test_options(&args);
+        let cached = test_options(&[&args[..], &["--sort-key-cache"]].concat());
+        assert!(cached.sort_key_cache);
+
+        let frames = animate_frames(&image, &uncached, &uncached.animate).unwrap();
+        assert_eq!(frames.len(), 5);
+        assert_eq!(animate_frames(&image, &cached, &cached.animate).unwrap(), frames);
+    }
+
+    #[test]
+    fn frames_yield_one_frame_per_value_of_the_shortest_sweep() {
+        let image = RgbImage::from_fn(6, 4, |x, y| Rgb([(x * 40) as u8, (y * 60) as u8, 90]));
+
+        let options = test_options(&["luma", "-a", "interval 2 8 2", "--seed", "1"]);
+        assert_eq!(frames(&image, &options.animate, &options).unwrap().count(), 4);
+
+        let options = test_options(&["luma", "-a", "interval 2 8 2", "-a", "discretize 1 2 1", "--seed", "1"]);
+        assert_eq!(frames(&image, &options.animate, &options).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn frames_of_different_sizes_are_padded_to_the_largest() {
+        let frames = vec![RgbImage::from_pixel(4, 2, Rgb([9; 3])), RgbImage::from_pixel(2, 6, Rgb([80; 3]))];
+        let padded = pad_frames(frames, Rgb([255, 0, 0]));
+
+        assert!(padded.iter().all(|frame| frame.dimensions() == (4, 6)));
+        assert_eq!(padded[0].get_pixel(0, 0).0, [255, 0, 0]);
+        assert_eq!(padded[0].get_pixel(0, 2).0, [9; 3]);
+        assert_eq!(padded[1].get_pixel(0, 0).0, [255, 0, 0]);
+        assert_eq!(padded[1].get_pixel(1, 0).0, [80; 3]);
+    }
+
+    #[test]
+    fn two_sweeps_pair_up_their_values_frame_by_frame() {
+        let image = RgbImage::from_fn(12, 9, |x, y| Rgb([(x * 21) as u8, (y * 28) as u8, ((x ^ y) * 17) as u8]));
+        let options = test_options(&["hue", "-a", "interval 2 6 2", "-a", "discretize 1 3 1", "--seed", "6"]);
+        let frames = animate_frames(&image, &options, &options.animate).unwrap();
+
+        let expected = [("2", "1"), ("4", "2"), ("6", "3")].map(|(interval, discretize)| {
+            let mut frame = image.clone();
+            frame.sort_rgb8_pixels(test_options(&["hue", "-i", interval, "-d", discretize, "--seed", "6"]));
+            frame
+        });
+        assert_eq!(frames, expected);
+    }
+
+    #[test]
+    fn frames_reach_the_video_in_order() {
+        if Command::new("ffmpeg").arg("-version").output().is_err() {
+            eprintln!("ffmpeg was not found, skipping");
+            return;
+        }
+
+        let grays = [30, 130, 230];
+        let frames = grays.map(|gray| RgbImage::from_pixel(8, 8, Rgb([gray; 3])));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.mkv");
+        write_video(&frames, &path).unwrap();
+
+        let decoded = Command::new("ffmpeg")
+            .args(["-loglevel", "error", "-i"])
+            .arg(&path)
+            .args(["-f", "rawvideo", "-pix_fmt", "rgb24", "-"])
+            .output()
+            .unwrap();
+        assert!(decoded.status.success());
+
+        // the codec is lossy, flat frames only come back close to their gray
+        let frame_size = 8 * 8 * 3;
+        assert_eq!(decoded.stdout.len(), grays.len() * frame_size);
+        for (frame, gray) in decoded.stdout.chunks(frame_size).zip(grays) {
+            let mean = frame.iter().map(|&value| value as u32).sum::<u32>() / frame_size as u32;
+            assert!(mean.abs_diff(gray as u32) <= 8, "a frame of {} came back as {}", gray, mean);
+        }
+    }
+}
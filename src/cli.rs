@@ -1,14 +1,17 @@
 use anyhow::{anyhow, bail, Context};
-use clap::ValueEnum;
+use clap::{parser::ValueSource, ArgAction, ArgMatches, CommandFactory, FromArgMatches, ValueEnum};
 use image::Rgb;
-use std::{path::PathBuf, fmt::Display};
+use itertools::Itertools;
+use std::{path::{Path, PathBuf}, fmt::Display};
 
 use crate::{
-    extractor::{luma, chroma, saturation, hue, brightness},
-    sort::SortOptions
+    extractor::{luma, chroma, saturation, hue, brightness, color_distance, dominant, flat, to_u8_key, Channel},
+    img::{load_image, palette_from_image, DEFAULT_MAX_PIXELS},
+    sort::SortOptions,
+    PxsortError
 };
 
-#[derive(Debug, Clone, Copy, ValueEnum)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
 pub enum ArgumentList {
     Interval,
     Discretize,
@@ -17,7 +20,46 @@ pub enum ArgumentList {
     EdgeThreshold,
     ImageThreshold,
     ImageMask,
-    Channel
+    Channel,
+    RedCoefficient,
+    GreenCoefficient,
+    BlueCoefficient
+}
+
+impl Display for ArgumentList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArgumentList::Interval => write!(f, "interval"),
+            ArgumentList::Discretize => write!(f, "discretize"),
+            ArgumentList::Direction => write!(f, "direction"),
+            ArgumentList::Splice => write!(f, "splice"),
+            ArgumentList::EdgeThreshold => write!(f, "edge_threshold"),
+            ArgumentList::ImageThreshold => write!(f, "image_threshold"),
+            ArgumentList::ImageMask => write!(f, "image_mask"),
+            ArgumentList::Channel => write!(f, "channel"),
+            ArgumentList::RedCoefficient => write!(f, "red_coefficient"),
+            ArgumentList::GreenCoefficient => write!(f, "green_coefficient"),
+            ArgumentList::BlueCoefficient => write!(f, "blue_coefficient")
+        }
+    }
+}
+
+impl ArgumentList {
+    /// Whether the parameter is swept with floating point values
+    pub fn is_float(&self) -> bool {
+        matches!(
+            self,
+            Self::Splice | Self::RedCoefficient | Self::GreenCoefficient | Self::BlueCoefficient
+        )
+    }
+
+    /// Whether the parameter changes the keys the pixels are sorted by
+    pub fn affects_keys(&self) -> bool {
+        matches!(
+            self,
+            Self::Channel | Self::RedCoefficient | Self::GreenCoefficient | Self::BlueCoefficient
+        )
+    }
 }
 
 impl TryFrom<&str> for ArgumentList {
@@ -33,7 +75,10 @@ impl TryFrom<&str> for ArgumentList {
             "image_threshold" => Ok(Self::ImageThreshold),
             "image_mask" => Ok(Self::ImageMask),
             "channel" => Ok(Self::Channel),
-            _ => bail!("'param' has to be one of: interval, discretize, direction, splice, edge_threshold, image_threshold, image_mask, channel")
+            "red_coefficient" => Ok(Self::RedCoefficient),
+            "green_coefficient" => Ok(Self::GreenCoefficient),
+            "blue_coefficient" => Ok(Self::BlueCoefficient),
+            _ => bail!("'param' has to be one of: interval, discretize, direction, splice, edge_threshold, image_threshold, image_mask, channel, red_coefficient, green_coefficient, blue_coefficient")
         }
     }
 }
@@ -59,248 +104,2054 @@ impl Display for WalkPath {
 ////////////////////////////////////////////////////////////////////////////////////////////////////////
 
 #[derive(Debug, Clone, Copy, ValueEnum)]
-pub enum ColorChannel {
-    Red,
-    Green,
-    Blue
+pub enum Curve {
+    Hilbert,
+    Snake
 }
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////////
 
 #[derive(Debug, Clone, Copy, ValueEnum)]
-pub enum SortingAlgorithm {
-    Luma,
-    Chroma,
-    Saturation,
-    Hue,
-    Brightness
+pub enum Diagonals {
+    /// From the top left to the bottom right, where `x - y` is the same
+    Down,
+    /// From the bottom left to the top right, where `x + y` is the same
+    Up,
+    /// Down first, then up
+    Both
 }
 
-impl SortingAlgorithm {
-    /// Returns the matching RGB8 key extractor function
-    ///
-    /// This function will be used to sort rows of pixels by key,
-    /// where key is `f(pixel) => key`
-    pub fn into_rgb_sorter(&self) -> impl Fn(&Rgb<u8>, &SortOptions) -> u8 + Copy {
+impl Display for Diagonals {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            SortingAlgorithm::Luma => luma,
-            SortingAlgorithm::Chroma => chroma,
-            SortingAlgorithm::Saturation => saturation,
-            SortingAlgorithm::Hue => hue,
-            SortingAlgorithm::Brightness => brightness
+            Diagonals::Down => write!(f, "down"),
+            Diagonals::Up => write!(f, "up"),
+            Diagonals::Both => write!(f, "both")
         }
     }
 }
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug, Clone)]
-pub struct AnimateParams {
-    pub param: ArgumentList,
-    pub start: u64,
-    pub stop: u64,
-    pub step: u64
+#[derive(Debug, Clone, Copy, ValueEnum, Default)]
+pub enum EdgeDetector {
+    #[default]
+    Sobel,
+    Prewitt,
+    Laplacian
 }
 
-fn into_animate_params(value: &str) -> anyhow::Result<AnimateParams> {
-    let mut divided = value.split_whitespace();
+impl Display for EdgeDetector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EdgeDetector::Sobel => write!(f, "sobel"),
+            EdgeDetector::Prewitt => write!(f, "prewitt"),
+            EdgeDetector::Laplacian => write!(f, "laplacian")
+        }
+    }
+}
 
-    let param = divided
-        .next()
-        .ok_or_else(|| anyhow!("'param' is missing"))?
-        .try_into()?;
-    let start = divided
-        .next()
-        .ok_or_else(|| anyhow!("'start' is missing"))?
-        .parse::<u64>()
-        .with_context(|| "failed to parse 'start' to a number")?;
-    let stop = divided
-        .next()
-        .ok_or_else(|| anyhow!("'stop' is missing"))?
-        .parse::<u64>()
-        .with_context(|| "failed to parse 'stop' to a number")?;
-    let step = divided
-        .next()
-        .ok_or_else(|| anyhow!("'step' is missing"))?
-        .parse::<u64>()
-        .with_context(|| "failed to parse 'step' to a number")?;
+////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, ValueEnum, Default)]
+pub enum IntervalUnit {
+    #[default]
+    Pixels,
+    Percent
+}
 
-    Ok(AnimateParams { param, start, stop, step })
+impl Display for IntervalUnit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IntervalUnit::Pixels => write!(f, "pixels"),
+            IntervalUnit::Percent => write!(f, "percent")
+        }
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug, Clone, Copy, PartialEq, Default)]
-pub struct Coefficients {
-    pub red: f32,
-    pub green: f32,
-    pub blue: f32
+/// How the spans of a line are laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Default)]
+pub enum IntervalSource {
+    /// Spans start every --interval pixels
+    #[default]
+    Value,
+    /// Like `value`, but spans are also broken at the edges found with --edge-threshold
+    Edge,
+    /// Every span gets a random length of up to --interval pixels
+    Random,
+    /// The interval swells and shrinks from line to line, once every --wave-period lines
+    Wave
 }
 
-impl Coefficients {
-    /// Default luma coefficients
-    pub fn luma() -> Self {
-        Self { red: 0.2126, green: 0.7152, blue: 0.0722 }
+impl Display for IntervalSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IntervalSource::Value => write!(f, "value"),
+            IntervalSource::Edge => write!(f, "edge"),
+            IntervalSource::Random => write!(f, "random"),
+            IntervalSource::Wave => write!(f, "wave")
+        }
     }
+}
 
-    /// Default hue coefficients
-    pub fn hue() -> Self {
-        Self { red: 0.0, green: 2.0, blue: 4.0 }
-    }
+////////////////////////////////////////////////////////////////////////////////////////////////////////
 
-    /// Default saturation coefficients
-    pub fn saturation() -> Self {
-        Self { red: 0.0, green: 0.0, blue: 0.0 }
-    }
+#[derive(Debug, Clone, Copy, ValueEnum, Default)]
+pub enum ProgressiveCurve {
+    #[default]
+    Linear,
+    Ease,
+    Exp
+}
 
-    /// Default hue coefficients
-    pub fn chroma() -> Self {
-        Self { red: 0.0, green: 0.0, blue: 0.0 }
+impl Display for ProgressiveCurve {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProgressiveCurve::Linear => write!(f, "linear"),
+            ProgressiveCurve::Ease => write!(f, "ease"),
+            ProgressiveCurve::Exp => write!(f, "exp")
+        }
     }
+}
 
-    /// Default hue coefficients
-    pub fn brightness() -> Self {
-        Self { red: 0.0, green: 0.0, blue: 0.0 }
-    }
+////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, ValueEnum, Default)]
+pub enum MaskCombine {
+    #[default]
+    And,
+    Or
 }
 
-impl Display for Coefficients {
+impl Display for MaskCombine {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{} {} {}", self.red, self.green, self.blue)
+        match self {
+            MaskCombine::And => write!(f, "and"),
+            MaskCombine::Or => write!(f, "or")
+        }
     }
 }
 
-impl From<&Cli> for Coefficients {
-    fn from(value: &Cli) -> Self {
-        match value.coefficients {
-            Some(coefficients) => coefficients,
-            None => match value.by {
-                SortingAlgorithm::Luma => Coefficients::luma(),
-                SortingAlgorithm::Chroma => Coefficients::chroma(),
-                SortingAlgorithm::Saturation => Coefficients::saturation(),
-                SortingAlgorithm::Hue => Coefficients::hue(),
-                SortingAlgorithm::Brightness => Coefficients::brightness()
-            }
+////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// How a mask of a different size than the image is laid over it
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum MaskMode {
+    /// Resize the mask to the size of the image
+    Stretch,
+    /// Repeat the mask from the top left corner
+    Tile,
+    /// Place the mask in the middle, with nothing sorted around it
+    Center
+}
+
+impl Display for MaskMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MaskMode::Stretch => write!(f, "stretch"),
+            MaskMode::Tile => write!(f, "tile"),
+            MaskMode::Center => write!(f, "center")
         }
     }
 }
 
-/// Parse the input string into [`Coefficients`]
-pub fn coefficients_value_parser(input: &str) -> anyhow::Result<Coefficients> {
-    let mut coefficients = Coefficients::default();
+////////////////////////////////////////////////////////////////////////////////////////////////////////
 
-    for value in input.split_whitespace() {
-        match value.split_once('=') {
-            Some((color, value)) => match color {
-                "red" => coefficients.red = value.parse()?,
-                "green" => coefficients.green = value.parse()?,
-                "blue" => coefficients.blue = value.parse()?,
-                name => bail!("invalid name: {}. has to be one of: red, green, blue", name)
-            },
-            None => bail!("invalid format: has to be 'color=value'")
+/// Relabeling of the color channels around the sort, undone after it
+#[derive(Debug, Clone, Copy, ValueEnum, Default)]
+pub enum Reinterpret {
+    #[default]
+    None,
+    /// Read the RGB channels as hue, saturation and value
+    RgbAsHsv,
+    /// Store the hue, saturation and value of a pixel in its RGB channels
+    HsvAsRgb
+}
+
+impl Display for Reinterpret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reinterpret::None => write!(f, "none"),
+            Reinterpret::RgbAsHsv => write!(f, "rgb-as-hsv"),
+            Reinterpret::HsvAsRgb => write!(f, "hsv-as-rgb")
         }
     }
-
-    Ok(coefficients)
 }
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////////
 
-/// Don't allow zero values (for interval)
-fn no_negative_values(input: &str) -> anyhow::Result<usize> {
-    match input.parse::<usize>() {
-        Ok(value) if value != 0 => Ok(value),
-        Err(error) => bail!(error),
-        _ => bail!("interval cannot be less than 1")
+#[derive(Debug, Clone, Copy, ValueEnum, Default)]
+pub enum PreviewQuality {
+    #[default]
+    Fast,
+    Good
+}
+
+impl Display for PreviewQuality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PreviewQuality::Fast => write!(f, "fast"),
+            PreviewQuality::Good => write!(f, "good")
+        }
     }
 }
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////////
 
-/// Parse a str into a [`WalkPath`]
-fn walkpath_value_parser(input: &str) -> anyhow::Result<WalkPath> {
-    if input.is_empty() {
-        bail!("empty input not allowed. allowed values are 'horizontal' or 'vertical'")
+#[derive(Debug, Clone, Copy, ValueEnum, Default)]
+pub enum PartialAnchor {
+    #[default]
+    Start,
+    Random
+}
+
+impl Display for PartialAnchor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PartialAnchor::Start => write!(f, "start"),
+            PartialAnchor::Random => write!(f, "random")
+        }
     }
+}
 
-    match input.get(..1) {
-        Some(first) => match first {
-            "h" | "H" | "horizontal" => Ok(WalkPath::Horizontal),
-            "v" | "V" | "vertical" => Ok(WalkPath::Vertical),
-            other => bail!("'{}' not an allowed value. allowed values are: 'h', 'H', 'horizontal', 'v', 'V', 'vertical'", other)
-        },
-        None => Ok(WalkPath::default())
+////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, ValueEnum, Default)]
+pub enum SpanOrder {
+    #[default]
+    None,
+    Length,
+    Brightness
+}
+
+impl Display for SpanOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpanOrder::None => write!(f, "none"),
+            SpanOrder::Length => write!(f, "length"),
+            SpanOrder::Brightness => write!(f, "brightness")
+        }
     }
 }
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////////
 
-#[derive(clap::Parser, Debug)]
-#[command(author, version, about, long_about, arg_required_else_help = true)]
-pub struct Cli {
-    /// Use a predefined sorting algorithm
-    #[clap(name = "EXTRACTOR")]
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Style {
+    Vaporwave,
+    Glitch,
+    Subtle
+}
+
+/// The options a [`Style`] sets
+#[derive(Debug, Clone)]
+pub struct Preset {
     pub by: SortingAlgorithm,
+    pub then: Option<SortingAlgorithm>,
+    pub coefficients: Option<Coefficients>,
+    pub interval: Vec<usize>,
+    pub discretize: u64,
+    pub reverse: bool,
+    pub direction: WalkPath
+}
 
-    /// Input file
-    pub input: PathBuf,
+impl Style {
+    /// The options of the style, see [`named_preset`]
+    pub fn preset(&self) -> Preset {
+        match self {
+            Style::Vaporwave => Preset {
+                by: SortingAlgorithm::Hue,
+                then: Some(SortingAlgorithm::Brightness),
+                coefficients: None,
+                interval: vec![60],
+                discretize: 30,
+                reverse: true,
+                direction: WalkPath::Vertical
+            },
+            Style::Glitch => Preset {
+                by: SortingAlgorithm::Chroma,
+                then: Some(SortingAlgorithm::Luma),
+                coefficients: Some(Coefficients { red: 1.5, green: 0.0, blue: 2.0 }),
+                interval: vec![5, 80, 20],
+                discretize: 12,
+                reverse: false,
+                direction: WalkPath::Horizontal
+            },
+            Style::Subtle => Preset {
+                by: SortingAlgorithm::Luma,
+                then: None,
+                coefficients: None,
+                interval: vec![4],
+                discretize: 4,
+                reverse: false,
+                direction: WalkPath::Horizontal
+            }
+        }
+    }
+}
 
-    /// Output file
-    pub output: PathBuf,
+/// The options of a style by its name, applied to the defaults of every other flag
+///
+/// - vaporwave: long vertical streaks by hue and then brightness, sorted in reverse
+/// - glitch: horizontal blocks of wildly varying intervals by boosted red and blue chroma and then luma
+/// - subtle: short horizontal spans by luma that keep most of the image intact
+pub fn named_preset(name: &str) -> anyhow::Result<SortOptions> {
+    let style = Style::from_str(name, true)
+        .map_err(|_| anyhow!("unknown style: '{}'. has to be one of: vaporwave, glitch, subtle", name))?;
+    let by = style.preset().by.to_string();
 
-    /// Interval to sort pixels by
-    #[arg(short = 'i', long = "interval", default_value_t = 1, value_parser(no_negative_values))]
-    pub interval: usize,
+    let cli = Cli::try_parse_styled_from(["pxsort", by.as_str(), "--style", name, "in.png", "out.png"])?;
+    Ok(cli.into())
+}
 
-    /// Sort in reverse
-    #[arg(short = 'r', long = "reverse", default_value_t = false)]
-    pub reverse: bool,
+////////////////////////////////////////////////////////////////////////////////////////////////////////
 
-    /// red green blue coefficients for sorting pixels by luma.
-    #[arg(short = 'f', long = "coefficients", value_parser(coefficients_value_parser))]
-    pub coefficients: Option<Coefficients>,
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Flip {
+    H,
+    V,
+    Both
+}
 
-    #[arg(short = 'd', long = "discretize", default_value_t = 1)]
-    pub discretize: u64,
+#[derive(Debug, Clone, Copy, ValueEnum, Default)]
+pub enum FlipAt {
+    #[default]
+    Pre,
+    Post
+}
 
-    #[arg(short = 'p', long = "progressive-amount")]
-    pub progressive_amount: Option<u64>,
+impl Display for FlipAt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FlipAt::Pre => write!(f, "pre"),
+            FlipAt::Post => write!(f, "post")
+        }
+    }
+}
 
-    /// The direction to sort pixels by
-    #[arg(short = 't', long = "direction", default_value_t = WalkPath::default(), value_parser(walkpath_value_parser))]
-    pub direction: WalkPath,
+////////////////////////////////////////////////////////////////////////////////////////////////////////
 
-    #[arg(short = 's', long = "splice")]
-    pub splice: Option<f64>,
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum AlphaOrder {
+    Primary,
+    Tiebreaker
+}
 
-    #[arg(short = 'e', long = "edge-threshold")]
-    pub edge_threshold: Option<u64>,
+////////////////////////////////////////////////////////////////////////////////////////////////////////
 
-    #[arg(long = "image-threshold")]
-    pub image_threshold: Option<u64>,
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ColorChannel {
+    Red,
+    Green,
+    Blue
+}
 
-    #[arg(long = "image-mask")]
-    pub image_mask: Option<PathBuf>,
+impl ColorChannel {
+    /// Position of the channel in an RGB pixel
+    pub fn index(self) -> usize {
+        match self {
+            ColorChannel::Red => 0,
+            ColorChannel::Green => 1,
+            ColorChannel::Blue => 2
+        }
+    }
+}
 
-    #[arg(short = 'c', long = "channel")]
-    pub channel: Option<ColorChannel>,
+impl Display for ColorChannel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ColorChannel::Red => write!(f, "red"),
+            ColorChannel::Green => write!(f, "green"),
+            ColorChannel::Blue => write!(f, "blue")
+        }
+    }
+}
 
-    /// Passing shuffle will result in shuffling the red green blue values
-    #[arg(long = "shuffle", default_value_t = false)]
-    pub shuffle: bool,
+/// Parse the names [`Display`] prints, ignoring case
+impl TryFrom<&str> for ColorChannel {
+    type Error = anyhow::Error;
 
-    /// Parameters for animation.
-    /// PARAM must be one of:
-    /// interval, reverse, discretize, direction,
-    /// mirror, splice, edge_threshold, image_threshold,
-    /// image_mask, channel
-    /// and START STOP STEP must be positive integers
-    #[arg(
-        short = 'a',
-        long = "animate",
-        value_parser(into_animate_params),
-        name = "PARAM START STOP STEP",
-        verbatim_doc_comment
-    )]
-    pub animate: Option<AnimateParams>
+    fn try_from(value: &str) -> anyhow::Result<Self> {
+        <Self as ValueEnum>::from_str(value, true).map_err(anyhow::Error::msg)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Only pixels with a `channel` of at least `value` pass
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelThreshold {
+    pub channel: ColorChannel,
+    pub value: u8
+}
+
+fn channel_threshold_value_parser(input: &str) -> anyhow::Result<ChannelThreshold> {
+    let (channel, value) = input
+        .split_once(':')
+        .ok_or_else(|| anyhow!("'{}' has to be a channel and a value like red:100", input))?;
+
+    let channel = ColorChannel::from_str(channel, true).map_err(|error| anyhow!("'{}': {}", channel, error))?;
+    let value = value
+        .parse::<u8>()
+        .with_context(|| format!("'{}' has to be a number between 0 and 255", value))?;
+
+    Ok(ChannelThreshold { channel, value })
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum SortingAlgorithm {
+    Luma,
+    Chroma,
+    Saturation,
+    Hue,
+    Brightness,
+    ColorDistance,
+    Dominant,
+    Texture
+}
+
+impl Display for SortingAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SortingAlgorithm::Luma => write!(f, "luma"),
+            SortingAlgorithm::Chroma => write!(f, "chroma"),
+            SortingAlgorithm::Saturation => write!(f, "saturation"),
+            SortingAlgorithm::Hue => write!(f, "hue"),
+            SortingAlgorithm::Brightness => write!(f, "brightness"),
+            SortingAlgorithm::ColorDistance => write!(f, "color-distance"),
+            SortingAlgorithm::Dominant => write!(f, "dominant"),
+            SortingAlgorithm::Texture => write!(f, "texture")
+        }
+    }
+}
+
+/// Parse the names [`Display`] prints, ignoring case
+impl TryFrom<&str> for SortingAlgorithm {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> anyhow::Result<Self> {
+        <Self as ValueEnum>::from_str(value, true).map_err(anyhow::Error::msg)
+    }
+}
+
+impl SortingAlgorithm {
+    /// Returns the matching key extractor function for any channel width
+    ///
+    /// The returned key is normalized into `0.0..=1.0`
+    pub fn into_sorter<T: Channel>(&self) -> fn(&Rgb<T>, &SortOptions) -> f32 {
+        match self {
+            SortingAlgorithm::Luma => luma,
+            SortingAlgorithm::Chroma => chroma,
+            SortingAlgorithm::Saturation => saturation,
+            SortingAlgorithm::Hue => hue,
+            SortingAlgorithm::Brightness => brightness,
+            SortingAlgorithm::ColorDistance => color_distance,
+            SortingAlgorithm::Dominant => dominant,
+            SortingAlgorithm::Texture => flat
+        }
+    }
+
+    /// Returns the matching RGB8 key extractor function
+    ///
+    /// This function will be used to sort rows of pixels by key,
+    /// where key is `f(pixel) => key`
+    pub fn into_rgb_sorter(&self) -> impl Fn(&Rgb<u8>, &SortOptions) -> u8 + Copy {
+        let sorter = self.into_sorter::<u8>();
+        move |pixel: &Rgb<u8>, options: &SortOptions| to_u8_key(sorter(pixel, options))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy)]
+pub enum AnimateRange {
+    Integer { start: u64, stop: u64, step: u64 },
+    Float { start: f64, stop: f64, step: f64 }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum AnimateValue {
+    Integer(u64),
+    Float(f64)
+}
+
+impl Display for AnimateValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AnimateValue::Integer(value) => write!(f, "{}", value),
+            AnimateValue::Float(value) => {
+                // round away the noise of summing up steps, `0.30000000000000004` => `0.3`
+                let value = format!("{:.6}", value);
+                write!(f, "{}", value.trim_end_matches('0').trim_end_matches('.'))
+            }
+        }
+    }
+}
+
+impl AnimateRange {
+    /// Every value of the sweep from start to stop (inclusive)
+    pub fn values(&self) -> Vec<AnimateValue> {
+        match *self {
+            AnimateRange::Integer { start, stop, step } => (start..=stop)
+                .step_by(step.max(1) as usize)
+                .map(AnimateValue::Integer)
+                .collect(),
+            AnimateRange::Float { start, stop, step } => {
+                // a tiny epsilon keeps `stop` reachable despite rounding errors
+                let frames = ((stop - start) / step + 1e-9).floor() as usize + 1;
+                (0..frames)
+                    .map(|frame| AnimateValue::Float(start + frame as f64 * step))
+                    .collect()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnimateParams {
+    pub param: ArgumentList,
+    pub range: AnimateRange
+}
+
+/// Parse the next value of the animation parameters
+fn next_animate_value<'a, T>(divided: &mut impl Iterator<Item = &'a str>, name: &str) -> anyhow::Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static
+{
+    divided
+        .next()
+        .ok_or_else(|| anyhow!("'{}' is missing", name))?
+        .parse::<T>()
+        .with_context(|| format!("failed to parse '{}' to a number", name))
+}
+
+fn into_animate_params(value: &str) -> anyhow::Result<AnimateParams> {
+    let mut divided = value.split_whitespace();
+
+    let param: ArgumentList = divided
+        .next()
+        .ok_or_else(|| anyhow!("'param' is missing"))?
+        .try_into()?;
+
+    let range = if param.is_float() {
+        let start: f64 = next_animate_value(&mut divided, "start")?;
+        let stop: f64 = next_animate_value(&mut divided, "stop")?;
+        let step: f64 = next_animate_value(&mut divided, "step")?;
+
+        if !(step > 0.0 && step.is_finite()) {
+            bail!("'step' has to be greater than 0, otherwise the animation never ends")
+        }
+
+        if !(start.is_finite() && stop.is_finite()) || stop < start {
+            bail!("'start' ({}) cannot be greater than 'stop' ({})", start, stop)
+        }
+
+        AnimateRange::Float { start, stop, step }
+    } else {
+        let start: u64 = next_animate_value(&mut divided, "start")?;
+        let stop: u64 = next_animate_value(&mut divided, "stop")?;
+        let step: u64 = next_animate_value(&mut divided, "step")?;
+
+        if step == 0 {
+            bail!("'step' has to be at least 1, otherwise the animation never ends")
+        }
+
+        if stop < start {
+            bail!("'start' ({}) cannot be greater than 'stop' ({})", start, stop)
+        }
+
+        AnimateRange::Integer { start, stop, step }
+    };
+
+    Ok(AnimateParams { param, range })
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, PartialEq)]
+enum TemplatePiece {
+    Text(String),
+    Stem,
+    Ext,
+    Index,
+    Param,
+    Value
+}
+
+/// A file name with `{stem}`, `{ext}`, `{index}`, `{param}` and `{value}` tokens
+#[derive(Debug, Clone)]
+pub struct OutputTemplate {
+    pieces: Vec<TemplatePiece>
+}
+
+/// The values substituted into the tokens of an [`OutputTemplate`]
+#[derive(Debug, Clone, Copy)]
+pub struct TemplateValues<'a> {
+    pub stem: &'a str,
+    pub ext: &'a str,
+    pub index: usize,
+    pub param: &'a str,
+    pub value: &'a str
+}
+
+impl OutputTemplate {
+    /// Parse a template, rejecting unknown or unclosed tokens
+    pub fn new(template: &str) -> anyhow::Result<Self> {
+        let mut pieces = Vec::new();
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| anyhow!("unclosed '{{' in template '{}'", template))?
+                + start;
+
+            if start > 0 {
+                pieces.push(TemplatePiece::Text(rest[..start].to_string()));
+            }
+
+            pieces.push(match &rest[start + 1..end] {
+                "stem" => TemplatePiece::Stem,
+                "ext" => TemplatePiece::Ext,
+                "index" => TemplatePiece::Index,
+                "param" => TemplatePiece::Param,
+                "value" => TemplatePiece::Value,
+                token => bail!(
+                    "unknown token '{{{}}}'. has to be one of: {{stem}}, {{ext}}, {{index}}, {{param}}, {{value}}",
+                    token
+                )
+            });
+
+            rest = &rest[end + 1..];
+        }
+
+        if !rest.is_empty() {
+            pieces.push(TemplatePiece::Text(rest.to_string()));
+        }
+
+        Ok(Self { pieces })
+    }
+
+    /// Substitute the tokens with `values`
+    ///
+    /// `{index}` is zero padded to four digits, so the names sort in order
+    pub fn render(&self, values: &TemplateValues) -> String {
+        self.pieces
+            .iter()
+            .map(|piece| match piece {
+                TemplatePiece::Text(text) => text.clone(),
+                TemplatePiece::Stem => values.stem.to_string(),
+                TemplatePiece::Ext => values.ext.to_string(),
+                TemplatePiece::Index => format!("{:04}", values.index),
+                TemplatePiece::Param => values.param.to_string(),
+                TemplatePiece::Value => values.value.to_string()
+            })
+            .collect()
+    }
+}
+
+/// Parse the input string into an [`OutputTemplate`]
+pub fn output_template_value_parser(input: &str) -> anyhow::Result<OutputTemplate> {
+    OutputTemplate::new(input)
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy)]
+pub struct BrightnessClamp {
+    pub low: u8,
+    pub high: u8
+}
+
+fn brightness_clamp_value_parser(value: &str) -> anyhow::Result<BrightnessClamp> {
+    let mut divided = value.split_whitespace();
+
+    let low = divided
+        .next()
+        .ok_or_else(|| anyhow!("'low' is missing"))?
+        .parse::<u8>()
+        .with_context(|| "failed to parse 'low' to a number between 0 and 255")?;
+    let high = divided
+        .next()
+        .ok_or_else(|| anyhow!("'high' is missing"))?
+        .parse::<u8>()
+        .with_context(|| "failed to parse 'high' to a number between 0 and 255")?;
+
+    if low > high {
+        bail!("'low' ({}) cannot be greater than 'high' ({})", low, high)
+    }
+
+    Ok(BrightnessClamp { low, high })
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy)]
+pub struct SamplePoint {
+    pub x: u32,
+    pub y: u32
+}
+
+fn sample_point_value_parser(value: &str) -> anyhow::Result<SamplePoint> {
+    let mut divided = value.split_whitespace();
+
+    let x = divided
+        .next()
+        .ok_or_else(|| anyhow!("'x' is missing"))?
+        .parse::<u32>()
+        .with_context(|| "failed to parse 'x' to a positive number")?;
+    let y = divided
+        .next()
+        .ok_or_else(|| anyhow!("'y' is missing"))?
+        .parse::<u32>()
+        .with_context(|| "failed to parse 'y' to a positive number")?;
+
+    Ok(SamplePoint { x, y })
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// The corners of a polygon in pixel coordinates, in order
+#[derive(Debug, Clone, PartialEq)]
+pub struct Polygon(pub Vec<(f32, f32)>);
+
+fn polygon_value_parser(value: &str) -> anyhow::Result<Polygon> {
+    let points = value
+        .split_whitespace()
+        .map(|point| {
+            let (x, y) = point.split_once(',').ok_or_else(|| anyhow!("'{}' has to be a point like x,y", point))?;
+            let x = x.parse::<f32>().with_context(|| format!("failed to parse 'x' of '{}' to a number", point))?;
+            let y = y.parse::<f32>().with_context(|| format!("failed to parse 'y' of '{}' to a number", point))?;
+
+            Ok((x, y))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    if points.len() < 3 {
+        bail!("a polygon needs at least 3 points, got {}", points.len())
+    }
+
+    Ok(Polygon(points))
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Coefficients {
+    pub red: f32,
+    pub green: f32,
+    pub blue: f32
+}
+
+impl Coefficients {
+    /// Default luma coefficients
+    pub fn luma() -> Self {
+        Self { red: 0.2126, green: 0.7152, blue: 0.0722 }
+    }
+
+    /// Default hue coefficients
+    pub fn hue() -> Self {
+        Self { red: 0.0, green: 2.0, blue: 4.0 }
+    }
+
+    /// Default saturation coefficients
+    pub fn saturation() -> Self {
+        Self { red: 0.0, green: 0.0, blue: 0.0 }
+    }
+
+    /// Default hue coefficients
+    pub fn chroma() -> Self {
+        Self { red: 0.0, green: 0.0, blue: 0.0 }
+    }
+
+    /// Default hue coefficients
+    pub fn brightness() -> Self {
+        Self { red: 0.0, green: 0.0, blue: 0.0 }
+    }
+
+    /// Default color distance coefficients
+    pub fn color_distance() -> Self {
+        Self { red: 0.0, green: 0.0, blue: 0.0 }
+    }
+
+    /// Default dominant color coefficients
+    pub fn dominant() -> Self {
+        Self { red: 0.0, green: 0.0, blue: 0.0 }
+    }
+
+    /// Default texture coefficients
+    pub fn texture() -> Self {
+        Self { red: 0.0, green: 0.0, blue: 0.0 }
+    }
+
+    /// Default coefficients of the extractor of `by`
+    pub fn for_algorithm(by: SortingAlgorithm) -> Self {
+        match by {
+            SortingAlgorithm::Luma => Coefficients::luma(),
+            SortingAlgorithm::Chroma => Coefficients::chroma(),
+            SortingAlgorithm::Saturation => Coefficients::saturation(),
+            SortingAlgorithm::Hue => Coefficients::hue(),
+            SortingAlgorithm::Brightness => Coefficients::brightness(),
+            SortingAlgorithm::ColorDistance => Coefficients::color_distance(),
+            SortingAlgorithm::Dominant => Coefficients::dominant(),
+            SortingAlgorithm::Texture => Coefficients::texture()
+        }
+    }
+
+    /// Whether these look like weights of a luma sum, between 0 and 1 and adding up to about 1
+    fn looks_like_weights(&self) -> bool {
+        let channels = [self.red, self.green, self.blue];
+        channels.iter().all(|channel| (0.0..=1.0).contains(channel)) && (channels.iter().sum::<f32>() - 1.0).abs() < 0.1
+    }
+
+    /// Why these coefficients are unlikely to do what was meant when sorting `by`, if they are
+    ///
+    /// Luma weighs the channels, hue offsets the sectors of the hue circle, chroma, saturation
+    /// and brightness scale the channels first (0 leaves a channel alone) and the others ignore them
+    pub fn mismatch(&self, by: SortingAlgorithm) -> Option<String> {
+        let channels = [self.red, self.green, self.blue];
+
+        match by {
+            SortingAlgorithm::Luma if channels.iter().all(|&channel| channel == 0.0) => {
+                Some("luma coefficients of 0 0 0 give every pixel the same key".into())
+            }
+            SortingAlgorithm::Luma if channels.iter().sum::<f32>() > 3.0 => Some(format!(
+                "the luma coefficients {} add up to more than 3, so most keys saturate. Luma weighs the channels, \
+                 these look like hue sector offsets",
+                self
+            )),
+            SortingAlgorithm::Hue if self.looks_like_weights() => Some(format!(
+                "the hue coefficients {} look like luma weights, hue takes the offsets of the red, green and blue \
+                 sectors (0 2 4 by default)",
+                self
+            )),
+            SortingAlgorithm::Chroma | SortingAlgorithm::Saturation | SortingAlgorithm::Brightness
+                if self.looks_like_weights() =>
+            {
+                Some(format!(
+                    "the coefficients {} look like luma weights, {} scales the channels by them before \
+                     extracting its key, 0 leaves a channel as it is",
+                    self, by
+                ))
+            }
+            SortingAlgorithm::ColorDistance | SortingAlgorithm::Dominant | SortingAlgorithm::Texture
+                if channels.iter().any(|&channel| channel != 0.0) =>
+            {
+                Some(format!("{} doesn't use the coefficients, {} are ignored", by, self))
+            }
+            _ => None
+        }
+    }
+}
+
+impl Display for Coefficients {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.red, self.green, self.blue)
+    }
+}
+
+impl From<&Cli> for Coefficients {
+    fn from(value: &Cli) -> Self {
+        // mismatched coefficients are still used as given, `Cli::validate` warns about them once
+        value.coefficients.unwrap_or_else(|| Coefficients::for_algorithm(value.by))
+    }
+}
+
+/// Parse the input string into [`Coefficients`]
+pub fn coefficients_value_parser(input: &str) -> Result<Coefficients, PxsortError> {
+    let mut coefficients = Coefficients::default();
+
+    let parse = |value: &str| {
+        value
+            .parse()
+            .map_err(|_| PxsortError::InvalidCoefficients(format!("'{}' is not a number", value)))
+    };
+
+    for value in input.split_whitespace() {
+        match value.split_once('=') {
+            Some((color, value)) => match color {
+                "red" => coefficients.red = parse(value)?,
+                "green" => coefficients.green = parse(value)?,
+                "blue" => coefficients.blue = parse(value)?,
+                name => {
+                    return Err(PxsortError::InvalidCoefficients(format!(
+                        "invalid name: {}. has to be one of: red, green, blue",
+                        name
+                    )))
+                }
+            },
+            None => return Err(PxsortError::InvalidCoefficients("invalid format: has to be 'color=value'".into()))
+        }
+    }
+
+    Ok(coefficients)
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Parse a `RRGGBB` hex string (optionally prefixed with `#`) into an [`Rgb`] color
+pub fn hex_color_value_parser(input: &str) -> anyhow::Result<Rgb<u8>> {
+    let hex = input.strip_prefix('#').unwrap_or(input);
+
+    if hex.len() != 6 || !hex.is_ascii() {
+        bail!("invalid color: '{}'. has to be in the format 'RRGGBB'", input)
+    }
+
+    let channel = |start: usize| {
+        u8::from_str_radix(&hex[start..start + 2], 16)
+            .with_context(|| format!("invalid hex color: '{}'", input))
+    };
+
+    Ok(Rgb([channel(0)?, channel(2)?, channel(4)?]))
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone)]
+pub struct Palette {
+    pub colors: Vec<Rgb<u8>>
+}
+
+/// Parse a comma separated list of `RRGGBB` colors into a [`Palette`],
+/// or read the unique colors of the image if the input names an existing file
+pub fn palette_value_parser(input: &str) -> anyhow::Result<Palette> {
+    let colors = match input
+        .split(',')
+        .map(hex_color_value_parser)
+        .collect::<anyhow::Result<Vec<_>>>()
+    {
+        Ok(colors) => colors,
+        Err(_) if Path::new(input).is_file() => palette_from_image(&load_image(input)?.into_rgb8()),
+        Err(error) => return Err(error)
+    };
+
+    if colors.is_empty() {
+        bail!("the palette has no colors")
+    }
+
+    Ok(Palette { colors })
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Only allow positive values (for interval and other counts)
+fn positive_usize(input: &str) -> anyhow::Result<usize> {
+    match input.parse::<usize>() {
+        Ok(value) if value != 0 => Ok(value),
+        Err(error) => bail!("'{}' is not a positive number: {}", input, error),
+        _ => bail!("has to be at least 1")
+    }
+}
+
+/// Parse the number of dominant colors, which the 8-bit key can tell at most 256 of apart
+fn dominant_k_value_parser(input: &str) -> anyhow::Result<usize> {
+    match positive_usize(input)? {
+        k if k <= 256 => Ok(k),
+        _ => bail!("at most 256 dominant colors are supported")
+    }
+}
+
+/// Parse the number of buckets of --key-quantize, the 8-bit key has at most 256 levels to merge
+fn key_quantize_value_parser(input: &str) -> anyhow::Result<u16> {
+    match positive_usize(input)? {
+        levels if levels <= 256 => Ok(levels as u16),
+        _ => bail!("the key can be quantized into at most 256 buckets")
+    }
+}
+
+/// Parse an `R,G,B` triple of positive intervals, one for every color channel
+fn channel_intervals_value_parser(input: &str) -> anyhow::Result<[usize; 3]> {
+    match input.split(',').map(positive_usize).collect::<anyhow::Result<Vec<_>>>()?[..] {
+        [red, green, blue] => Ok([red, green, blue]),
+        _ => bail!("'{}' has to be three comma separated intervals, one for red, green and blue", input)
+    }
+}
+
+/// Only allow positive values (for discretize and other counts)
+fn positive_u64(input: &str) -> anyhow::Result<u64> {
+    match input.parse::<u64>() {
+        Ok(value) if value != 0 => Ok(value),
+        Err(error) => bail!("'{}' is not a positive number: {}", input, error),
+        _ => bail!("has to be at least 1")
+    }
+}
+
+/// Only allow positive values (for scale factors)
+fn positive_u32(input: &str) -> anyhow::Result<u32> {
+    match input.parse::<u32>() {
+        Ok(value) if value != 0 => Ok(value),
+        Err(error) => bail!("'{}' is not a positive number: {}", input, error),
+        _ => bail!("has to be at least 1")
+    }
+}
+
+/// Only allow finite numbers greater than 0.0 (for gamma)
+fn positive_f32(input: &str) -> anyhow::Result<f32> {
+    match input.parse::<f32>() {
+        Ok(value) if value > 0.0 && value.is_finite() => Ok(value),
+        Err(error) => bail!("'{}' is not a number: {}", input, error),
+        _ => bail!("has to be greater than 0")
+    }
+}
+
+/// Only allow values that fit into a channel (for brightness thresholds)
+fn byte_0_255(input: &str) -> anyhow::Result<u8> {
+    input
+        .parse::<u8>()
+        .with_context(|| format!("'{}' has to be a number between 0 and 255", input))
+}
+
+/// Only allow fractions between 0.0 and 1.0 (for blending)
+fn fraction_0_1(input: &str) -> anyhow::Result<f32> {
+    match input.parse::<f32>() {
+        Ok(value) if (0.0..=1.0).contains(&value) => Ok(value),
+        _ => bail!("'{}' has to be a number between 0.0 and 1.0", input)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Parse a str into a [`WalkPath`]
+fn walkpath_value_parser(input: &str) -> anyhow::Result<WalkPath> {
+    if input.is_empty() {
+        bail!("empty input not allowed. allowed values are 'horizontal' or 'vertical'")
+    }
+
+    match input.get(..1) {
+        Some(first) => match first {
+            "h" | "H" | "horizontal" => Ok(WalkPath::Horizontal),
+            "v" | "V" | "vertical" => Ok(WalkPath::Vertical),
+            other => bail!("'{}' not an allowed value. allowed values are: 'h', 'H', 'horizontal', 'v', 'V', 'vertical'", other)
+        },
+        None => Ok(WalkPath::default())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Cargo features of pxsort and whether this build has them enabled
+pub const FEATURES: &[(&str, bool)] = &[("webp", cfg!(feature = "webp")), ("gpu", cfg!(feature = "gpu"))];
+
+/// The version followed by the features of this build
+fn features_version() -> String {
+    let features = FEATURES
+        .iter()
+        .map(|(name, enabled)| format!("  {}: {}", name, if *enabled { "enabled" } else { "disabled" }))
+        .join("\n");
+
+    format!("{}\nfeatures:\n{}", env!("CARGO_PKG_VERSION"), features)
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(clap::Parser, Debug, Clone)]
+#[command(author, version, long_version = features_version(), about, long_about, arg_required_else_help = true)]
+pub struct Cli {
+    /// Use a predefined sorting algorithm
+    #[clap(name = "EXTRACTOR")]
+    pub by: SortingAlgorithm,
+
+    /// Input file, may be left out when --input-list is given
+    #[arg(required_unless_present = "input_list")]
+    pub input: Option<PathBuf>,
+
+    /// Read the input files from this file instead, one path per line, and sort all of them into --output-dir
+    #[arg(long = "input-list", conflicts_with_all = ["input", "output"], requires = "output_dir")]
+    pub input_list: Option<PathBuf>,
+
+    /// Refuse to load inputs with more than N pixels, instead of allocating whatever their header asks for
+    #[arg(long = "max-pixels", value_name = "N", default_value_t = DEFAULT_MAX_PIXELS, value_parser(positive_u64))]
+    pub max_pixels: u64,
+
+    /// Sort frame N of an animated GIF or APNG input instead of the first one, counting from 0
+    #[arg(long = "input-frame", value_name = "N", conflicts_with_all = ["stream", "lenient"])]
+    pub input_frame: Option<usize>,
+
+    /// Sort every frame of an animated GIF or APNG input the same way and save them as an animated GIF,
+    /// keeping the delay of every frame
+    #[arg(
+        long = "sort-all-frames",
+        default_value_t = false,
+        conflicts_with_all = [
+            "input_frame", "PARAM START STOP STEP", "stream", "lenient", "interactive", "benchmark", "multi_by",
+            "show_key", "sample", "self_test"
+        ]
+    )]
+    pub sort_all_frames: bool,
+
+    /// Keep the rows of a corrupt PNG input that could be decoded instead of failing, filling the rest with black
+    #[arg(long = "lenient", default_value_t = false)]
+    pub lenient: bool,
+
+    /// Output file, may be left out when --output-dir is given
+    #[arg(required_unless_present_any = ["output_dir", "interactive", "benchmark"])]
+    pub output: Option<PathBuf>,
+
+    /// Also sort the decoded input by each of these extractors instead of EXTRACTOR,
+    /// saving one output per extractor with its name appended, like out_luma.png and out_hue.png
+    #[arg(
+        long = "multi-by",
+        value_delimiter = ',',
+        conflicts_with_all = ["stream", "interactive", "benchmark", "show_key", "sample", "self_test"]
+    )]
+    pub multi_by: Vec<SortingAlgorithm>,
+
+    /// Save into this directory under a name generated from the input and the options
+    #[arg(long = "output-dir", conflicts_with = "output")]
+    pub output_dir: Option<PathBuf>,
+
+    /// Declare the sRGB color space in the output file (PNG only)
+    #[arg(long = "embed-srgb", default_value_t = false)]
+    pub embed_srgb: bool,
+
+    /// Copy the EXIF metadata of the input into the output (PNG only).
+    /// Not with --respect-exif, the copied orientation would turn the output a second time
+    #[arg(long = "keep-metadata", default_value_t = false, conflicts_with = "respect_exif")]
+    pub keep_metadata: bool,
+
+    /// Print the version and the cargo features this binary was built with
+    #[arg(long = "features", action = ArgAction::Version)]
+    pub features: Option<bool>,
+
+    /// Rotate and flip the input upright by its EXIF orientation before sorting
+    #[arg(long = "respect-exif", default_value_t = false)]
+    pub respect_exif: bool,
+
+    /// Fail instead of overwriting an existing output file
+    #[arg(long = "no-clobber", default_value_t = false, overrides_with = "force")]
+    pub no_clobber: bool,
+
+    /// Overwrite an existing output file, even if --no-clobber was passed before
+    #[arg(long = "force", default_value_t = false, overrides_with = "no_clobber")]
+    pub force: bool,
+
+    /// Interval to sort pixels by. A comma separated list is cycled through span by span
+    #[arg(short = 'i', long = "interval", default_value = "1", value_delimiter = ',', value_parser(positive_usize))]
+    pub interval: Vec<usize>,
+
+    /// Unit of the interval, either pixels or percent of the line length
+    #[arg(long = "interval-unit", default_value_t = IntervalUnit::default())]
+    pub interval_unit: IntervalUnit,
+
+    /// Sort the red, green and blue channels independently of each other, each with its own interval
+    #[arg(long = "channel-intervals", value_name = "R,G,B", value_parser(channel_intervals_value_parser))]
+    pub channel_intervals: Option<[usize; 3]>,
+
+    /// Move the start of every span by a random offset of up to AMOUNT pixels either way
+    #[arg(long = "interval-jitter", default_value_t = 0, value_name = "AMOUNT")]
+    pub interval_jitter: u32,
+
+    /// Leave N pixels unsorted after every span, for a striped look
+    #[arg(long = "span-gap", default_value_t = 0, value_name = "N")]
+    pub span_gap: u32,
+
+    /// Leave spans shorter than N pixels unsorted, like the short pieces edges split off
+    #[arg(
+        long = "min-span",
+        visible_alias = "span-min-length",
+        default_value_t = 1,
+        value_name = "N",
+        value_parser(positive_u32)
+    )]
+    pub min_span: u32,
+
+    /// How the spans are laid out. Without it, spans start every --interval pixels
+    /// and are broken at edges if --edge-threshold is given
+    #[arg(long = "interval-source")]
+    pub interval_source: Option<IntervalSource>,
+
+    /// Number of lines one swell of --interval-source wave takes
+    #[arg(long = "wave-period", value_name = "LINES", value_parser(positive_u32))]
+    pub wave_period: Option<u32>,
+
+    /// Seed for everything random, so that sorting the same image again gives the same result
+    #[arg(long = "seed")]
+    pub seed: Option<u64>,
+
+    /// Sort in reverse
+    #[arg(short = 'r', long = "reverse", default_value_t = false)]
+    pub reverse: bool,
+
+    /// Put the lowest key in the middle of every span and the higher ones toward both ends
+    /// for a symmetric gradient, the highest one with --reverse
+    #[arg(long = "center-out", visible_alias = "sort-center-out", default_value_t = false)]
+    pub center_out: bool,
+
+    /// Break ties between pixels with equal keys by this second extractor
+    #[arg(long = "then", value_name = "EXTRACTOR")]
+    pub then: Option<SortingAlgorithm>,
+
+    /// red green blue coefficients for sorting pixels by luma.
+    #[arg(short = 'f', long = "coefficients", value_parser(coefficients_value_parser))]
+    pub coefficients: Option<Coefficients>,
+
+    /// Size of the block that gets sorted, gathered at the start of every interval.
+    /// Clamped to the line length if larger
+    #[arg(short = 'd', long = "discretize", default_value_t = 1, value_parser(positive_u64))]
+    pub discretize: u64,
+
+    /// Treat every line as circular, so that spans reaching past the end
+    /// continue from the start of the line
+    #[arg(long = "wrap", default_value_t = false)]
+    pub wrap: bool,
+
+    #[arg(short = 'p', long = "progressive-amount")]
+    pub progressive_amount: Option<u64>,
+
+    /// How the progressive amount grows from line to line.
+    /// linear grows by one every line, ease and exp reach the same amount at the last line
+    #[arg(long = "progressive-curve", default_value_t = ProgressiveCurve::default())]
+    pub progressive_curve: ProgressiveCurve,
+
+    /// Start from a curated set of --then, --coefficients, interval, discretize, reverse and direction.
+    /// Flags given on the command line take precedence over the style,
+    /// and its coefficients are only used with the extractor it was made for
+    #[arg(long = "style")]
+    pub style: Option<Style>,
+
+    /// The direction to sort pixels by
+    #[arg(short = 't', long = "direction", default_value_t = WalkPath::default(), value_parser(walkpath_value_parser))]
+    pub direction: WalkPath,
+
+    /// Sort the whole image as one sequence along a space-filling curve,
+    /// ignoring interval, discretize and direction
+    #[arg(long = "curve")]
+    pub curve: Option<Curve>,
+
+    #[arg(short = 's', long = "splice")]
+    pub splice: Option<f64>,
+
+    /// Break spans at pixels whose gradient magnitude is above this threshold
+    #[arg(short = 'e', long = "edge-threshold", value_parser(positive_u64))]
+    pub edge_threshold: Option<u64>,
+
+    /// The operator used to find edges for the edge threshold
+    #[arg(long = "edge-detector", default_value_t = EdgeDetector::default())]
+    pub edge_detector: EdgeDetector,
+
+    /// Only sort pixels with a luma of at least this value
+    #[arg(long = "image-threshold", value_parser(byte_0_255))]
+    pub image_threshold: Option<u8>,
+
+    /// Pick the --image-threshold with Otsu's method, splitting the image into its dark and bright pixels.
+    /// The chosen value is logged with --verbose
+    #[arg(long = "auto-threshold", default_value_t = false, conflicts_with = "image_threshold")]
+    pub auto_threshold: bool,
+
+    /// Pick a threshold for every line on its own with Otsu's method, from the line's luma
+    /// (or --threshold-metric), so shaded and bright parts of unevenly lit images both get spans
+    #[arg(
+        long = "adaptive-threshold",
+        default_value_t = false,
+        conflicts_with_all = ["image_threshold", "auto_threshold"]
+    )]
+    pub adaptive_threshold: bool,
+
+    /// Measure pixels by this extractor instead of luma for --image-threshold and --edge-threshold,
+    /// the sort key is still chosen by EXTRACTOR
+    #[arg(long = "threshold-metric")]
+    pub threshold_metric: Option<SortingAlgorithm>,
+
+    /// Only sort pixels that are light in this grayscale image of the same size,
+    /// dark pixels stay where they are
+    #[arg(long = "image-mask")]
+    pub image_mask: Option<PathBuf>,
+
+    /// Only sort the pixels inside of this polygon, given as space separated x,y points like "0,0 100,0 50,80".
+    /// The points are in the coordinates of the image as it is sorted
+    #[arg(long = "polygon", value_name = "POINTS", value_parser(polygon_value_parser), conflicts_with = "image_mask")]
+    pub polygon: Option<Polygon>,
+
+    /// Sort the pixels outside of --polygon instead of the ones inside
+    #[arg(long = "polygon-outside", default_value_t = false, requires = "polygon")]
+    pub polygon_outside: bool,
+
+    /// Fit an --image-mask of a different size onto the image instead of failing
+    #[arg(long = "mask-mode", requires = "image_mask")]
+    pub mask_mode: Option<MaskMode>,
+
+    /// Mask pixels at least this light get sorted
+    #[arg(long = "mask-threshold", default_value_t = 128, requires = "image_mask")]
+    pub mask_threshold: u8,
+
+    /// Save the output with the --image-mask as its alpha channel, so the masked out pixels become transparent.
+    /// The output has to be a format with alpha, like PNG
+    #[arg(
+        long = "alpha-from-mask",
+        default_value_t = false,
+        requires = "image_mask",
+        conflicts_with_all = ["PARAM START STOP STEP", "stream", "interactive"]
+    )]
+    pub alpha_from_mask: bool,
+
+    /// Only sort pixels whose CHANNEL is at least VALUE, given as CHANNEL:VALUE like red:100
+    #[arg(long = "channel-threshold", value_name = "CHANNEL:VALUE", value_parser(channel_threshold_value_parser))]
+    pub channel_threshold: Option<ChannelThreshold>,
+
+    /// How --image-mask, --image-threshold and --channel-threshold are combined if more than one is given,
+    /// a pixel gets sorted if all (and) or any (or) of them pass
+    #[arg(long = "mask-combine", default_value_t = MaskCombine::default())]
+    pub mask_combine: MaskCombine,
+
+    /// Leave the pixels within --key-tolerance of this color (RRGGBB) unsorted, like a chroma key.
+    /// Also spelled --ignore-color, to keep a solid background crisp without a mask
+    #[arg(long = "key-color", visible_alias = "ignore-color", value_parser(hex_color_value_parser))]
+    pub key_color: Option<Rgb<u8>>,
+
+    /// Largest RGB distance from --key-color that still counts as the key color
+    #[arg(long = "key-tolerance", visible_alias = "ignore-tolerance", default_value_t = 0, requires = "key_color")]
+    pub key_tolerance: u32,
+
+    /// Sort only the pixels matching --key-color instead of leaving them unsorted
+    #[arg(long = "key-sort-only", default_value_t = false, requires = "key_color")]
+    pub key_sort_only: bool,
+
+    #[arg(short = 'c', long = "channel")]
+    pub channel: Option<ColorChannel>,
+
+    /// Rotate the hue circle by DEGREES before sorting by hue,
+    /// moving the point where reds wrap around.
+    /// Any origin other than 0 sorts by the angle on the full hue circle
+    #[arg(long = "hue-origin", default_value_t = 0.0, value_name = "DEGREES")]
+    pub hue_origin: f32,
+
+    /// Target color (RRGGBB) to measure distance to when sorting by color distance
+    #[arg(long = "target", value_parser(hex_color_value_parser), required_if_eq("EXTRACTOR", "color-distance"))]
+    pub target: Option<Rgb<u8>>,
+
+    /// Number of dominant colors to cluster the image into when sorting by dominant, at most 256
+    #[arg(long = "k", default_value_t = 8, value_parser(dominant_k_value_parser))]
+    pub k: usize,
+
+    /// Clamp the brightness of every pixel into LOW HIGH (0-255) before sorting.
+    /// Unlike the thresholds, this changes the pixel values
+    #[arg(long = "clamp-brightness", value_parser(brightness_clamp_value_parser), name = "LOW HIGH")]
+    pub clamp_brightness: Option<BrightnessClamp>,
+
+    /// Match the color histogram of the input to this image before sorting, channel by channel
+    #[arg(long = "match-histogram", value_name = "REFERENCE")]
+    pub match_histogram: Option<PathBuf>,
+
+    /// Relabel the color channels between RGB and HSV before sorting, and back after it
+    #[arg(long = "reinterpret", default_value_t = Reinterpret::default())]
+    pub reinterpret: Reinterpret,
+
+    /// Median-filter the image with this radius before sorting,
+    /// so noise doesn't break edges into jagged spans
+    #[arg(long = "denoise", default_value_t = 0, value_name = "RADIUS")]
+    pub denoise: u32,
+
+    /// Flip the image horizontally (h), vertically (v) or both ways.
+    /// Before sorting this changes the side the lines are walked from, after sorting it's cosmetic
+    #[arg(long = "flip")]
+    pub flip: Option<Flip>,
+
+    /// Whether --flip happens before (pre) or after (post) sorting
+    #[arg(long = "flip-at", default_value_t = FlipAt::default(), requires = "flip")]
+    pub flip_at: FlipAt,
+
+    /// Rotate the image clockwise by this many degrees before sorting, for sorts at an angle
+    #[arg(long = "rotate", value_name = "DEGREES", allow_negative_numbers = true)]
+    pub rotate: Option<f32>,
+
+    /// Rotate the sorted image back by --rotate and crop it to its original size
+    #[arg(long = "rotate-back", default_value_t = false, requires = "rotate")]
+    pub rotate_back: bool,
+
+    /// Color of the corners --rotate uncovers, as RRGGBB
+    #[arg(long = "rotate-fill", default_value = "000000", value_parser(hex_color_value_parser), requires = "rotate")]
+    pub rotate_fill: Rgb<u8>,
+
+    /// Raise every color channel (scaled to 0.0-1.0) to the power of VALUE before sorting,
+    /// above 1.0 darkens the image and below 1.0 lightens it
+    #[arg(long = "gamma", value_name = "VALUE", value_parser(positive_f32))]
+    pub gamma: Option<f32>,
+
+    /// Undo --gamma after sorting
+    #[arg(long = "gamma-restore", default_value_t = false, requires = "gamma")]
+    pub gamma_restore: bool,
+
+    /// Invert all channels before sorting, which changes which pixels group together
+    #[arg(long = "invert-pre", default_value_t = false)]
+    pub invert_pre: bool,
+
+    /// Invert all channels after sorting
+    #[arg(long = "invert-post", default_value_t = false)]
+    pub invert_post: bool,
+
+    /// Map every sorted pixel to the nearest color of a palette,
+    /// given as a comma separated list of RRGGBB colors or an image to take the colors of
+    #[arg(long = "palette", value_parser(palette_value_parser))]
+    pub palette: Option<Palette>,
+
+    /// Sort along the diagonals instead of --direction, both of them for a crosshatched diamond texture
+    #[arg(long = "diagonals", conflicts_with_all = ["curve", "cross", "stream"])]
+    pub diagonals: Option<Diagonals>,
+
+    /// Sort the image a second time across the first pass, producing a cross-hatched smear
+    #[arg(long = "cross", default_value_t = false)]
+    pub cross: bool,
+
+    /// Interval of the second pass of --cross, the interval of the first pass if not given
+    #[arg(long = "cross-interval", value_delimiter = ',', value_parser(positive_usize), requires = "cross")]
+    pub cross_interval: Option<Vec<usize>>,
+
+    /// Keep sorting the sorted image again until it stops changing, at most MAX times
+    #[arg(long = "until-stable", value_name = "MAX", value_parser(positive_u32))]
+    pub until_stable: Option<u32>,
+
+    /// Mix the sorted image with the original, from 0.0 (the original) to 1.0 (fully sorted)
+    #[arg(long = "blend", value_parser(fraction_0_1))]
+    pub blend: Option<f32>,
+
+    /// Limit the larger side of the output to MAX pixels, for quick previews
+    #[arg(long = "limit", value_name = "MAX", value_parser(positive_u32))]
+    pub limit: Option<u32>,
+
+    /// How --limit shrinks the image. fast shrinks it with nearest-neighbor before sorting,
+    /// good sorts at full size and shrinks the result with Lanczos3 afterwards
+    #[arg(long = "preview-quality", default_value_t = PreviewQuality::default(), requires = "limit")]
+    pub preview_quality: PreviewQuality,
+
+    /// Upscale the sorted image by this factor with nearest-neighbor filtering
+    #[arg(long = "output-scale", default_value_t = 1, value_parser(positive_u32))]
+    pub output_scale: u32,
+
+    /// Shrink the image by FACTOR before sorting and blow it back up after it, for blocky spans.
+    /// Short for --limit and --output-scale with matching sizes
+    #[arg(long = "chunky", value_name = "FACTOR", value_parser(positive_u32), conflicts_with_all = ["limit", "output_scale"])]
+    pub chunky: Option<u32>,
+
+    /// Let the alpha channel of RGBA images take part in the sort key,
+    /// either before (primary) or after (tiebreaker) the color
+    #[arg(long = "sort-alpha", num_args = 0..=1, default_missing_value = "primary")]
+    pub sort_alpha: Option<AlphaOrder>,
+
+    /// Premultiply the colors of RGBA images by their alpha while sorting,
+    /// so translucent pixels don't leave fringes when the result is composited
+    #[arg(long = "premultiply", default_value_t = false)]
+    pub premultiply: bool,
+
+    /// Sort pixels with a luma above THRESHOLD descending after the darker ones,
+    /// which are sorted ascending, instead of sorting the whole span one way
+    #[arg(long = "split-order", value_name = "THRESHOLD", value_parser(byte_0_255))]
+    pub split_order: Option<u8>,
+
+    /// Blend the sort key with the position of the pixel in its span, from 0.0 (only the key)
+    /// to 1.0 (only the position, which leaves the span as it was)
+    #[arg(long = "position-weight", default_value_t = 0.0, value_parser(fraction_0_1))]
+    pub position_weight: f32,
+
+    /// Only swap every pair of pixels that is out of order with this probability (0.0-1.0),
+    /// for a partially ordered, noisier sort. 1.0 sorts fully and 0.0 leaves the spans alone
+    #[arg(long = "sort-strength", default_value_t = 1.0, value_parser(fraction_0_1))]
+    pub sort_strength: f32,
+
+    /// Round the sort key down into N evenly sized buckets (1-256) before sorting,
+    /// so similar pixels compare equal and keep their order as stable groups
+    #[arg(
+        long = "key-quantize",
+        visible_alias = "sort-key-quantize",
+        value_name = "N",
+        value_parser(key_quantize_value_parser)
+    )]
+    pub key_quantize: Option<u16>,
+
+    /// Leave every pixel after N sorted ones in its original place,
+    /// breaking the spans into sorted runs of at most N pixels to keep some texture
+    #[arg(long = "max-run", value_name = "N", value_parser(positive_usize))]
+    pub max_run: Option<usize>,
+
+    /// Only sort this fraction (0.0-1.0) of every span, the rest of it stays as it was
+    #[arg(long = "partial", value_name = "FRACTION", value_parser(fraction_0_1))]
+    pub partial: Option<f32>,
+
+    /// Where the sorted part of a --partial span is, at its start or at a random offset
+    #[arg(long = "partial-anchor", default_value_t = PartialAnchor::default(), requires = "partial")]
+    pub partial_anchor: PartialAnchor,
+
+    /// Reorder the sorted spans of every line by their length or their average brightness,
+    /// moving the blocks instead of just their pixels
+    #[arg(long = "reorder-spans", default_value_t = SpanOrder::default())]
+    pub reorder_spans: SpanOrder,
+
+    /// Randomly reorder whole rows (or columns with a vertical direction) before sorting
+    #[arg(long = "row-shuffle", default_value_t = false)]
+    pub row_shuffle: bool,
+
+    /// Passing shuffle will result in shuffling the red green blue values
+    #[arg(long = "shuffle", default_value_t = false)]
+    pub shuffle: bool,
+
+    /// Gather the pixels of every span in parallel too, not just the lines.
+    /// Off by default, since spans are usually only a few pixels long and the nested
+    /// parallelism competes with the lines for the thread pool. `just bench` compares both
+    #[arg(long = "parallel-gather", default_value_t = false)]
+    pub parallel_gather: bool,
+
+    /// Write a grayscale image of the sort key of every pixel instead of sorting
+    #[arg(long = "show-key", default_value_t = false, conflicts_with = "PARAM START STOP STEP")]
+    pub show_key: bool,
+
+    /// Draw the first pixel of every span onto the sorted output, to show how the image was carved up
+    #[arg(long = "debug-spans", default_value_t = false)]
+    pub debug_spans: bool,
+
+    /// Color of the span boundaries drawn with --debug-spans, as RRGGBB
+    #[arg(long = "debug-spans-color", default_value = "ff00ff", value_parser(hex_color_value_parser))]
+    pub debug_spans_color: Rgb<u8>,
+
+    /// Print statistics about the sort, like the number of spans and how many pixels moved
+    #[arg(long = "stats", default_value_t = false)]
+    pub stats: bool,
+
+    /// Also print a small preview of the sorted image to the terminal, in 24-bit color
+    #[arg(long = "term-preview", default_value_t = false, conflicts_with_all = ["PARAM START STOP STEP", "stream"])]
+    pub term_preview: bool,
+
+    /// Log more about what is going on, given up to three times:
+    /// the loaded images, then the resolved options and timings, then every span
+    #[arg(short = 'v', long = "verbose", action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Sort the input twice with the same seed and fail if the results differ, instead of saving it
+    #[arg(long = "self-test", default_value_t = false, hide = true)]
+    pub self_test: bool,
+
+    /// Decode the input once, then sort copies of it N times and print the fastest,
+    /// median and slowest sort instead of saving anything
+    #[arg(
+        long = "benchmark",
+        value_name = "N",
+        value_parser(positive_u32),
+        conflicts_with_all = ["input_list", "PARAM START STOP STEP", "stream", "interactive", "show_key", "sample", "self_test"]
+    )]
+    pub benchmark: Option<u32>,
+
+    /// Sort a PNG row by row without loading it whole, for images too large for memory.
+    /// Experimental, only the horizontal sort itself runs, without any of the image transformations
+    #[arg(
+        long = "stream",
+        default_value_t = false,
+        conflicts_with_all = ["PARAM START STOP STEP", "curve", "edge_threshold", "cross", "row_shuffle", "channel_intervals"]
+    )]
+    pub stream: bool,
+
+    /// Read commands like `interval 30`, `by hue` or `render out.png` from stdin,
+    /// re-sorting the loaded image with the changed options on every render
+    #[arg(
+        long = "interactive",
+        default_value_t = false,
+        conflicts_with_all = ["input_list", "PARAM START STOP STEP", "stream", "show_key", "sample"]
+    )]
+    pub interactive: bool,
+
+    /// Also write a grayscale image of the sort key of every pixel to this path, next to the sorted output
+    #[arg(long = "write-key-map", value_name = "PATH", conflicts_with = "show_key")]
+    pub write_key_map: Option<PathBuf>,
+
+    /// Print the color, the coefficient-applied color and the sort key of the pixel at X Y,
+    /// then exit without sorting
+    #[arg(long = "sample", value_parser(sample_point_value_parser), name = "X Y", conflicts_with = "PARAM START STOP STEP")]
+    pub sample: Option<SamplePoint>,
+
+    /// Parameters for animation.
+    /// PARAM must be one of:
+    /// interval, reverse, discretize, direction,
+    /// mirror, splice, edge_threshold, image_threshold,
+    /// image_mask, channel,
+    /// red_coefficient, green_coefficient, blue_coefficient
+    /// and START STOP STEP must be positive numbers
+    /// (floating point for splice and the coefficients),
+    /// with START not greater than STOP and STEP greater than 0.
+    /// Given twice, both parameters are swept together
+    /// and have to have the same number of frames
+    #[arg(
+        short = 'a',
+        long = "animate",
+        value_parser(into_animate_params),
+        action = ArgAction::Append,
+        name = "PARAM START STOP STEP",
+        verbatim_doc_comment
+    )]
+    pub animate: Vec<AnimateParams>,
+
+    /// Also write every animation frame into this directory as numbered PNGs
+    #[arg(long = "frames-dir", requires = "PARAM START STOP STEP")]
+    pub frames_dir: Option<PathBuf>,
+
+    /// Background of the canvas that animation frames of different sizes are centered on, as RRGGBB
+    #[arg(long = "pad-color", default_value = "000000", value_parser(hex_color_value_parser))]
+    pub pad_color: Rgb<u8>,
+
+    /// Extract the sort keys once and reuse them for every frame,
+    /// if the animated parameter doesn't change the keys
+    #[arg(long = "sort-key-cache", default_value_t = false, requires = "PARAM START STOP STEP")]
+    pub sort_key_cache: bool,
+
+    /// Name of the frames written with --frames-dir.
+    /// Supports {stem} and {ext} of the input, {index}, {param} and {value} of the frame
+    #[arg(long = "output-template", default_value = "frame_{index}.png", value_parser(output_template_value_parser))]
+    pub output_template: OutputTemplate
+}
+
+impl Cli {
+    /// Check for flags that make no sense together, before any work is done
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.shuffle && self.reverse {
+            bail!("--shuffle and --reverse cannot be combined, the shuffled order is lost to the reversed sort")
+        }
+
+        if self.position_weight > 0.0 && self.split_order.is_some() {
+            bail!("--position-weight and --split-order cannot be combined, the position weight replaces the split order")
+        }
+
+        if self.curve.is_some() && self.channel_intervals.is_some() {
+            bail!("--curve and --channel-intervals cannot be combined, the channels are sorted line by line")
+        }
+
+        if matches!(self.by, SortingAlgorithm::Texture) && self.curve.is_some() {
+            bail!("texture keys are measured around every pixel, so --by texture cannot be combined with --curve")
+        }
+
+        if matches!(self.then, Some(SortingAlgorithm::Texture)) {
+            bail!("--then texture isn't supported, ties are broken one pixel at a time")
+        }
+
+        if matches!(self.threshold_metric, Some(SortingAlgorithm::Texture)) {
+            bail!("--threshold-metric texture isn't supported, the thresholds look at one pixel at a time")
+        }
+
+        if self.diagonals.is_some() && !matches!(self.reorder_spans, SpanOrder::None) {
+            bail!("--reorder-spans cannot be combined with --diagonals, the reordered spans would run off the diagonals")
+        }
+
+        if self.diagonals.is_some() && matches!(self.by, SortingAlgorithm::Texture) {
+            bail!("--by texture cannot be combined with --diagonals, the texture is measured around every pixel")
+        }
+
+        if self.curve.is_some() && (self.wrap || self.cross) {
+            bail!("--curve sorts the whole image as one sequence, so it cannot be combined with --wrap or --cross")
+        }
+
+        if let Some(source) = self.interval_source {
+            match (source, self.edge_threshold) {
+                (IntervalSource::Edge, None) => bail!("--interval-source edge needs an --edge-threshold"),
+                (IntervalSource::Value | IntervalSource::Random | IntervalSource::Wave, Some(_)) => {
+                    bail!("--edge-threshold only applies to --interval-source edge, not {}", source)
+                }
+                _ => {}
+            }
+
+            if matches!(source, IntervalSource::Random | IntervalSource::Wave) && self.interval_jitter > 0 {
+                bail!("--interval-jitter cannot be combined with --interval-source {}", source)
+            }
+        }
+
+        if self.wave_period.is_some() && self.interval_source != Some(IntervalSource::Wave) {
+            bail!("--wave-period only applies to --interval-source wave")
+        }
+
+        // only the header is read here, the reference is decoded once when it is matched
+        if let Some(path) = &self.match_histogram {
+            image::image_dimensions(path)
+                .with_context(|| format!("failed to read the histogram reference '{}'", path.display()))?;
+        }
+
+        match self.animate.as_slice() {
+            [_, _, _, ..] => bail!("--animate can be given at most twice"),
+            [first, second] if first.param == second.param => {
+                bail!("--animate was given twice for '{}'", first.param)
+            }
+            [first, second] if first.range.values().len() != second.range.values().len() => bail!(
+                "the --animate sweeps of '{}' ({} frames) and '{}' ({} frames) need the same number of frames",
+                first.param,
+                first.range.values().len(),
+                second.param,
+                second.range.values().len()
+            ),
+            _ => {}
+        }
+
+        if self.auto_threshold && self.animate.iter().any(|params| matches!(params.param, ArgumentList::ImageThreshold)) {
+            bail!("--auto-threshold picks the image threshold, so it cannot be animated")
+        }
+
+        if self.adaptive_threshold && self.animate.iter().any(|params| matches!(params.param, ArgumentList::ImageThreshold)) {
+            bail!("--adaptive-threshold picks the threshold of every line, so the image threshold cannot be animated")
+        }
+
+        if self.stream && !matches!(self.direction, WalkPath::Horizontal) {
+            bail!("--stream only supports horizontal sorts, the rows are read one after the other")
+        }
+
+        if let Some(path) = &self.image_mask {
+            if !path.exists() {
+                bail!("the mask '{}' does not exist", path.display())
+            }
+        }
+
+        if let Some(mismatch) = self.coefficients.and_then(|coefficients| coefficients.mismatch(self.by)) {
+            log::warn!("{}", mismatch);
+        }
+
+        Ok(())
+    }
+
+    /// The level to log at for the number of times --verbose was given, only warnings without it
+    pub fn log_level(&self) -> log::LevelFilter {
+        match self.verbose {
+            0 => log::LevelFilter::Warn,
+            1 => log::LevelFilter::Info,
+            2 => log::LevelFilter::Debug,
+            _ => log::LevelFilter::Trace
+        }
+    }
+
+    /// Parse the command line `args` like [`clap::Parser::try_parse_from`] and apply its `--style`
+    pub fn try_parse_styled_from<I, T>(args: I) -> Result<Cli, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone
+    {
+        let matches = Cli::command().try_get_matches_from(args)?;
+        let mut cli = Cli::from_arg_matches(&matches)?;
+        cli.apply_style(&matches);
+        Ok(cli)
+    }
+
+    /// Apply the options of `--style`, except for the ones given explicitly in `matches`
+    pub fn apply_style(&mut self, matches: &ArgMatches) {
+        let style = match self.style {
+            Some(style) => style,
+            None => return
+        };
+
+        let preset = style.preset();
+        let explicit = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        if !explicit("then") {
+            self.then = preset.then;
+        }
+
+        // coefficients only mean the same thing to the extractor they were picked for
+        if !explicit("coefficients") && self.by == preset.by {
+            self.coefficients = preset.coefficients;
+        }
+
+        if !explicit("interval") {
+            self.interval = preset.interval;
+        }
+
+        if !explicit("discretize") {
+            self.discretize = preset.discretize;
+        }
+
+        // a shuffled order is only ever sorted forwards, so the style can't reverse it
+        if !explicit("reverse") && !self.shuffle {
+            self.reverse = preset.reverse;
+        }
+
+        if !explicit("direction") {
+            self.direction = preset.direction;
+        }
+    }
+
+    /// Convert into [`SortOptions`] after checking the flags with [`Cli::validate`]
+    ///
+    /// This isn't a `TryFrom<Cli>` impl, the standard library already derives one with an
+    /// infallible error from `From<Cli> for SortOptions`, which can't be implemented twice
+    pub fn try_into_options(&self) -> anyhow::Result<SortOptions> {
+        self.validate()?;
+        Ok(self.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    /// Check the command line `pxsort ARGS in.png out.png`, where ARGS starts with the extractor
+    fn validate(args: &[&str]) -> anyhow::Result<SortOptions> {
+        let args = ["pxsort"].into_iter().chain(args.iter().copied()).chain(["in.png", "out.png"]);
+        Cli::try_parse_styled_from(args)?.try_into_options()
+    }
+
+    #[test]
+    fn conflicting_flags_are_rejected() {
+        let conflicts: &[&[&str]] = &[
+            &["luma", "--shuffle", "--reverse"],
+            &["luma", "--position-weight", "0.5", "--split-order", "100"],
+            &["luma", "--curve", "hilbert", "--channel-intervals", "1,2,3"],
+            &["texture", "--curve", "snake"],
+            &["luma", "--then", "texture"],
+            &["luma", "--threshold-metric", "texture"],
+            &["luma", "--diagonals", "down", "--reorder-spans", "length"],
+            &["texture", "--diagonals", "up"],
+            &["luma", "--curve", "hilbert", "--wrap"],
+            &["luma", "--interval-source", "edge"],
+            &["luma", "--interval-source", "random", "--edge-threshold", "10"],
+            &["luma", "--interval-source", "wave", "--interval-jitter", "2"],
+            &["luma", "--wave-period", "10"],
+            &["luma", "--match-histogram", "no-such-reference.png"],
+            &["luma", "-a", "interval 1 3 1", "-a", "discretize 1 3 1", "-a", "splice 0 1 0.5"],
+            &["luma", "-a", "interval 1 3 1", "-a", "interval 2 4 1"],
+            &["luma", "-a", "interval 1 3 1", "-a", "discretize 1 5 1"],
+            &["luma", "--auto-threshold", "-a", "image_threshold 0 255 5"],
+            &["luma", "--adaptive-threshold", "-a", "image_threshold 0 255 5"],
+            &["luma", "--stream", "-t", "vertical"],
+            &["luma", "--image-mask", "no-such-mask.png"]
+        ];
+
+        for args in conflicts {
+            assert!(validate(args).is_err(), "{:?} was accepted", args);
+        }
+    }
+
+    #[test]
+    fn compatible_flags_are_accepted() {
+        let accepted: &[&[&str]] = &[
+            &["luma"],
+            &["luma", "--shuffle"],
+            &["luma", "--reverse", "--split-order", "100"],
+            &["texture", "--then", "luma"],
+            &["luma", "--interval-source", "edge", "--edge-threshold", "10"],
+            &["luma", "--interval-source", "wave", "--wave-period", "10"],
+            &["luma", "-a", "interval 1 3 1", "-a", "discretize 1 3 1"],
+            &["luma", "--stream"]
+        ];
+
+        for args in accepted {
+            if let Err(error) = validate(args) {
+                panic!("{:?} was rejected: {}", args, error);
+            }
+        }
+    }
+
+    #[test]
+    fn positive_parsers_accept_one_and_up() {
+        assert_eq!(positive_usize("1").unwrap(), 1);
+        assert_eq!(positive_usize("640").unwrap(), 640);
+        assert_eq!(positive_u64("1").unwrap(), 1);
+        assert_eq!(positive_u64("18446744073709551615").unwrap(), u64::MAX);
+
+        for input in ["0", "-3", "2.5", "", "many"] {
+            assert!(positive_usize(input).is_err(), "{:?}", input);
+            assert!(positive_u64(input).is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn byte_parser_accepts_0_to_255() {
+        assert_eq!(byte_0_255("0").unwrap(), 0);
+        assert_eq!(byte_0_255("128").unwrap(), 128);
+        assert_eq!(byte_0_255("255").unwrap(), 255);
+
+        for input in ["256", "-1", "12.5", "", "light"] {
+            assert!(byte_0_255(input).is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn invalid_animations_are_rejected() {
+        let invalid = [
+            "interval 1 10 0",
+            "interval 10 1 1",
+            "interval 1 10",
+            "interval",
+            "",
+            "interval one 10 1",
+            "interval -1 10 1",
+            "brightness 1 10 1",
+            "splice 0.0 1.0 0.0",
+            "splice 0.0 1.0 -0.1",
+            "splice 1.0 0.0 0.1",
+            "splice 0.0 inf 0.1",
+            "splice NaN 1.0 0.1",
+            "red_coefficient 0.0 1.0 inf"
+        ];
+
+        for value in invalid {
+            assert!(into_animate_params(value).is_err(), "{:?} was accepted", value);
+        }
+    }
+
+    #[test]
+    fn single_frame_animations_are_accepted() {
+        assert_eq!(into_animate_params("interval 5 5 1").unwrap().range.values().len(), 1);
+        assert_eq!(into_animate_params("discretize 1 3 10").unwrap().range.values().len(), 1);
+        assert_eq!(into_animate_params("splice 0.5 0.5 0.1").unwrap().range.values().len(), 1);
+    }
+
+    #[test]
+    fn float_sweeps_reach_their_stop() {
+        let splice = into_animate_params("splice 0.0 1.0 0.1").unwrap();
+        let values = splice.range.values();
+
+        assert!(matches!(splice.range, AnimateRange::Float { .. }));
+        assert_eq!(values.len(), 11);
+        assert!(matches!(values[0], AnimateValue::Float(value) if value == 0.0));
+        assert!(matches!(values[10], AnimateValue::Float(value) if (value - 1.0).abs() < 1e-9));
+
+        assert_eq!(into_animate_params("red_coefficient 0.5 2 0.5").unwrap().range.values().len(), 4);
+        assert_eq!(into_animate_params("blue_coefficient 0 1 0.3").unwrap().range.values().len(), 4);
+    }
+
+    #[test]
+    fn integer_params_reject_float_sweeps() {
+        assert!(into_animate_params("interval 1.5 3 1").is_err());
+        assert!(into_animate_params("discretize 1 3 0.5").is_err());
+    }
+
+    #[test]
+    fn named_presets_set_the_documented_options() {
+        let vaporwave = named_preset("vaporwave").unwrap();
+        assert_eq!(vaporwave.by, SortingAlgorithm::Hue);
+        assert_eq!(vaporwave.then, Some(SortingAlgorithm::Brightness));
+        assert_eq!(vaporwave.coefficients, Coefficients::hue());
+        assert_eq!(vaporwave.interval, vec![60]);
+        assert_eq!(vaporwave.discretize, 30);
+        assert!(vaporwave.reverse);
+        assert!(matches!(vaporwave.direction, WalkPath::Vertical));
+
+        let glitch = named_preset("Glitch").unwrap();
+        assert_eq!(glitch.by, SortingAlgorithm::Chroma);
+        assert_eq!(glitch.then, Some(SortingAlgorithm::Luma));
+        assert_eq!(glitch.coefficients, Coefficients { red: 1.5, green: 0.0, blue: 2.0 });
+        assert_eq!(glitch.interval, vec![5, 80, 20]);
+        assert_eq!(glitch.discretize, 12);
+        assert!(!glitch.reverse);
+        assert!(matches!(glitch.direction, WalkPath::Horizontal));
+
+        let subtle = named_preset("subtle").unwrap();
+        assert_eq!(subtle.by, SortingAlgorithm::Luma);
+        assert_eq!(subtle.then, None);
+        assert_eq!(subtle.coefficients, Coefficients::luma());
+        assert_eq!(subtle.interval, vec![4]);
+        assert_eq!(subtle.discretize, 4);
+        assert!(!subtle.reverse);
+        assert!(matches!(subtle.direction, WalkPath::Horizontal));
+
+        assert!(named_preset("noisy").is_err());
+    }
+
+    #[test]
+    fn explicit_flags_take_precedence_over_the_style() {
+        let cli = Cli::try_parse_styled_from([
+            "pxsort", "chroma", "--style", "glitch", "-d", "3", "--then", "hue", "-f", "red=2", "in.png", "out.png"
+        ])
+        .unwrap();
+
+        assert_eq!(cli.discretize, 3);
+        assert_eq!(cli.then, Some(SortingAlgorithm::Hue));
+        assert_eq!(cli.coefficients, Some(Coefficients { red: 2.0, green: 0.0, blue: 0.0 }));
+        assert_eq!(cli.interval, vec![5, 80, 20]);
+    }
+
+    #[test]
+    fn style_coefficients_are_left_out_for_other_extractors() {
+        let cli = Cli::try_parse_styled_from(["pxsort", "hue", "--style", "glitch", "in.png", "out.png"]).unwrap();
+
+        assert_eq!(cli.coefficients, None);
+        assert_eq!(cli.then, Some(SortingAlgorithm::Luma));
+    }
+
+    #[test]
+    fn style_reverse_is_left_out_when_shuffling() {
+        let cli = Cli::try_parse_styled_from(["pxsort", "luma", "--style", "vaporwave", "--shuffle", "in.png", "out.png"])
+            .unwrap();
+
+        assert!(!cli.reverse);
+        assert!(cli.try_into_options().is_ok());
+    }
+    #[test]
+    fn palettes_that_are_no_file_fail_on_the_colors() {
+        let error = palette_value_parser("ff0000,00ff0").unwrap_err();
+
+        assert!(error.to_string().contains("invalid color: '00ff0'"), "{}", error);
+    }
+
+    #[test]
+    fn mapping_to_a_two_color_palette_leaves_only_those_colors() {
+        let palette = palette_value_parser("ff0000,0000ff").unwrap();
+        let mut image = image::RgbImage::from_fn(16, 16, |x, y| Rgb([(x * 16) as u8, (x * y) as u8, (y * 16) as u8]));
+
+        crate::quantize_to_palette(&mut image, &palette.colors);
+
+        let colors = image.pixels().map(|pixel| pixel.0).collect::<HashSet<_>>();
+        assert_eq!(colors, HashSet::from([[255, 0, 0], [0, 0, 255]]));
+    }
+
+    #[test]
+    fn the_features_are_listed_after_the_version() {
+        let status = |enabled: bool| if enabled { "enabled" } else { "disabled" };
+        let version = features_version();
+        let mut lines = version.lines();
+
+        assert_eq!(lines.next(), Some(env!("CARGO_PKG_VERSION")));
+        assert_eq!(lines.next(), Some("features:"));
+        assert_eq!(
+            lines.collect::<Vec<_>>(),
+            [
+                format!("  webp: {}", status(cfg!(feature = "webp"))),
+                format!("  gpu: {}", status(cfg!(feature = "gpu")))
+            ]
+        );
+    }
+
+    #[test]
+    fn every_verbose_flag_logs_one_level_more() {
+        let level = |flags: &[&str]| {
+            let args = ["pxsort", "luma"].into_iter().chain(flags.iter().copied()).chain(["in.png", "out.png"]);
+            Cli::try_parse_styled_from(args).unwrap().log_level()
+        };
+
+        assert_eq!(level(&[]), log::LevelFilter::Warn);
+        assert_eq!(level(&["-v"]), log::LevelFilter::Info);
+        assert_eq!(level(&["-vv"]), log::LevelFilter::Debug);
+        assert_eq!(level(&["-v", "--verbose", "-v"]), log::LevelFilter::Trace);
+        assert_eq!(level(&["-vvvvv"]), log::LevelFilter::Trace);
+    }
+
+    #[test]
+    fn coefficients_meant_for_another_extractor_are_a_mismatch() {
+        let zeros = Coefficients::default();
+        for &by in SortingAlgorithm::value_variants() {
+            assert_eq!(Coefficients::for_algorithm(by).mismatch(by), None, "the defaults of {}", by);
+
+            // which of the luma weights, the hue offsets and all zeros don't fit `by`
+            let expected = match by {
+                SortingAlgorithm::Luma => [false, true, true],
+                SortingAlgorithm::Hue | SortingAlgorithm::Chroma | SortingAlgorithm::Saturation | SortingAlgorithm::Brightness => {
+                    [true, false, false]
+                }
+                SortingAlgorithm::ColorDistance | SortingAlgorithm::Dominant | SortingAlgorithm::Texture => [true, true, false]
+            };
+            let mismatched = [Coefficients::luma(), Coefficients::hue(), zeros].map(|coefficients| coefficients.mismatch(by).is_some());
+            assert_eq!(mismatched, expected, "{}", by);
+        }
+    }
+
+    #[test]
+    fn printed_names_parse_back_into_the_same_variant() {
+        for &by in SortingAlgorithm::value_variants() {
+            let name = by.to_string();
+            assert_eq!(SortingAlgorithm::try_from(name.as_str()).unwrap(), by);
+            assert_eq!(SortingAlgorithm::try_from(name.to_uppercase().as_str()).unwrap(), by);
+            assert_eq!(by.to_possible_value().unwrap().get_name(), name);
+        }
+
+        for &channel in ColorChannel::value_variants() {
+            let name = channel.to_string();
+            assert_eq!(ColorChannel::try_from(name.as_str()).unwrap().index(), channel.index());
+            assert_eq!(channel.to_possible_value().unwrap().get_name(), name);
+        }
+    }
 }
@@ -0,0 +1,101 @@
+use image::Rgb;
+use rand::{rngs::StdRng, seq::index, SeedableRng};
+
+/// Most pixels k-means looks at, larger images are sampled evenly
+const SAMPLE_LIMIT: usize = 64 * 1024;
+
+/// Most rounds of k-means before it stops, even if the clusters are still moving
+const MAX_ROUNDS: usize = 16;
+
+/// Find the `k` dominant colors of `pixels` with k-means, ordered from dark to bright
+///
+/// The first centers are picked among the pixels with `seed`, so the same seed gives the same colors.
+/// Fewer than `k` colors are returned if there are fewer pixels than that
+pub fn dominant_colors<I>(pixels: I, k: usize, seed: u64) -> Vec<Rgb<u8>>
+where
+    I: ExactSizeIterator<Item = Rgb<u8>>
+{
+    let step = (pixels.len() / SAMPLE_LIMIT).max(1);
+    let samples = pixels.step_by(step).map(|Rgb(pixel)| pixel.map(f32::from)).collect::<Vec<_>>();
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut centers = index::sample(&mut rng, samples.len(), k.min(samples.len()))
+        .into_iter()
+        .map(|index| samples[index])
+        .collect::<Vec<_>>();
+
+    let mut labels = vec![usize::MAX; samples.len()];
+
+    for _ in 0..MAX_ROUNDS {
+        let mut changed = false;
+        for (label, sample) in labels.iter_mut().zip(&samples) {
+            let nearest = nearest(centers.iter().copied(), *sample);
+            changed |= *label != nearest;
+            *label = nearest;
+        }
+
+        if !changed {
+            break;
+        }
+
+        let mut sums = vec![([0.0; 3], 0usize); centers.len()];
+        for (&label, sample) in labels.iter().zip(&samples) {
+            let (sum, count) = &mut sums[label];
+            for (sum, channel) in sum.iter_mut().zip(sample) {
+                *sum += channel;
+            }
+            *count += 1;
+        }
+
+        // a cluster that lost all of its pixels keeps its center
+        for (center, (sum, count)) in centers.iter_mut().zip(sums) {
+            if count > 0 {
+                *center = sum.map(|sum| sum / count as f32);
+            }
+        }
+    }
+
+    let mut colors = centers
+        .into_iter()
+        .map(|center| Rgb(center.map(|channel| channel.round() as u8)))
+        .collect::<Vec<_>>();
+    colors.sort_by_key(|Rgb([red, green, blue])| 299 * *red as u32 + 587 * *green as u32 + 114 * *blue as u32);
+
+    colors
+}
+
+/// Index of the color in `colors` closest to `pixel` in RGB space, `0` if there are none
+pub fn nearest_color(colors: &[Rgb<u8>], pixel: [f32; 3]) -> usize {
+    nearest(colors.iter().map(|Rgb(color)| color.map(f32::from)), pixel)
+}
+
+/// Index of the center closest to `pixel`, `0` if there are none
+fn nearest(centers: impl IntoIterator<Item = [f32; 3]>, pixel: [f32; 3]) -> usize {
+    centers
+        .into_iter()
+        .map(|center| center.iter().zip(&pixel).map(|(center, channel)| (center - channel).powi(2)).sum::<f32>())
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map_or(0, |(index, _)| index)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbImage;
+
+    use super::*;
+    use crate::{sort::test_options, PixelSort};
+
+    #[test]
+    fn one_cluster_gives_every_pixel_the_same_key() {
+        let original = RgbImage::from_fn(12, 8, |x, y| Rgb([(x * 21) as u8, (y * 31) as u8, ((x ^ y) * 17) as u8]));
+
+        let colors = dominant_colors(original.pixels().copied(), 1, 4);
+        assert_eq!(colors.len(), 1);
+        assert!(original.pixels().all(|Rgb(pixel)| nearest_color(&colors, pixel.map(f32::from)) == 0));
+
+        let mut image = original.clone();
+        image.sort_rgb8_pixels(test_options(&["dominant", "--k", "1", "-i", "4", "-d", "4", "-p", "1000", "--seed", "4"]));
+        assert_eq!(image, original);
+    }
+}
@@ -0,0 +1,119 @@
+use image::{GrayImage, ImageBuffer, Luma};
+
+use crate::EdgeDetector;
+
+/// Gradient magnitude of every pixel of an image
+pub type EdgeMap = ImageBuffer<Luma<u16>, Vec<u16>>;
+
+type Kernel = [[i32; 3]; 3];
+
+const SOBEL_X: Kernel = [[-1, 0, 1], [-2, 0, 2], [-1, 0, 1]];
+const SOBEL_Y: Kernel = [[-1, -2, -1], [0, 0, 0], [1, 2, 1]];
+
+const PREWITT_X: Kernel = [[-1, 0, 1], [-1, 0, 1], [-1, 0, 1]];
+const PREWITT_Y: Kernel = [[-1, -1, -1], [0, 0, 0], [1, 1, 1]];
+
+const LAPLACIAN: Kernel = [[0, 1, 0], [1, -4, 1], [0, 1, 0]];
+
+/// Convolve the 3x3 neighbourhood of a pixel with `kernel`, clamping at the image edges
+fn convolve(image: &GrayImage, x: u32, y: u32, kernel: &Kernel) -> i32 {
+    let (width, height) = image.dimensions();
+    let mut sum = 0;
+
+    for (ky, row) in kernel.iter().enumerate() {
+        for (kx, weight) in row.iter().enumerate() {
+            let sample_x = (x + kx as u32).saturating_sub(1).min(width - 1);
+            let sample_y = (y + ky as u32).saturating_sub(1).min(height - 1);
+            sum += weight * image.get_pixel(sample_x, sample_y).0[0] as i32;
+        }
+    }
+
+    sum
+}
+
+/// Magnitude of the gradient in both directions
+fn magnitude(image: &GrayImage, x: u32, y: u32, kernel_x: &Kernel, kernel_y: &Kernel) -> u16 {
+    let gx = convolve(image, x, y, kernel_x) as f32;
+    let gy = convolve(image, x, y, kernel_y) as f32;
+    (gx * gx + gy * gy).sqrt() as u16
+}
+
+/// Compute the gradient magnitude of every pixel of a grayscale image with `detector`
+///
+/// Sobel and Prewitt magnitudes reach roughly 1440 and 1080, Laplacian ones 1020
+pub fn gradient_magnitude(image: &GrayImage, detector: EdgeDetector) -> EdgeMap {
+    let (width, height) = image.dimensions();
+
+    EdgeMap::from_fn(width, height, |x, y| {
+        Luma([match detector {
+            EdgeDetector::Sobel => magnitude(image, x, y, &SOBEL_X, &SOBEL_Y),
+            EdgeDetector::Prewitt => magnitude(image, x, y, &PREWITT_X, &PREWITT_Y),
+            EdgeDetector::Laplacian => convolve(image, x, y, &LAPLACIAN).unsigned_abs() as u16
+        }])
+    })
+}
+
+/// Compute the standard deviation of the 3x3 neighbourhood of every pixel of a grayscale image,
+/// clamping at the image edges
+///
+/// Deviations are doubled, so the largest possible one of 127.5 maps to 255 and flat regions to 0
+pub fn local_deviation(image: &GrayImage) -> GrayImage {
+    let (width, height) = image.dimensions();
+
+    GrayImage::from_fn(width, height, |x, y| {
+        let (mut sum, mut squares) = (0.0, 0.0);
+
+        for ky in 0..3 {
+            for kx in 0..3 {
+                let sample_x = (x + kx).saturating_sub(1).min(width - 1);
+                let sample_y = (y + ky).saturating_sub(1).min(height - 1);
+                let value = image.get_pixel(sample_x, sample_y).0[0] as f32;
+                sum += value;
+                squares += value * value;
+            }
+        }
+
+        let mean = sum / 9.0;
+        let deviation = (squares / 9.0 - mean * mean).max(0.0).sqrt();
+        Luma([(deviation * 2.0).round().min(255.0) as u8])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+    use crate::sort::{span_boundaries, test_options};
+
+    #[test]
+    fn the_edge_detectors_break_a_gradient_at_different_pixels() {
+        // a ramp that starts gently and ends on a plateau
+        let values = [0, 0, 10, 50, 90, 130, 170, 170, 170];
+        let image = RgbImage::from_fn(9, 1, |x, _| Rgb([values[x as usize]; 3]));
+
+        let starts = |detector: &str| {
+            let options = test_options(&[
+                "luma", "-i", "9", "-d", "9", "-p", "1000", "--interval-source", "edge", "-e", "30", "--edge-detector", detector
+            ]);
+            span_boundaries(&image, &options).into_iter().map(|(x, _)| x).collect::<Vec<_>>()
+        };
+
+        // the gentle start only passes Sobel, the end of the ramp is the only curve Laplacian sees
+        assert_eq!(starts("sobel"), [0, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(starts("prewitt"), [0, 2, 3, 4, 5, 6]);
+        assert_eq!(starts("laplacian"), [0, 6]);
+    }
+
+    #[test]
+    fn a_flat_image_has_no_texture() {
+        for value in [0, 93, 173, 255] {
+            let flat = GrayImage::from_pixel(7, 5, Luma([value]));
+            assert!(local_deviation(&flat).pixels().all(|deviation| deviation.0[0] == 0), "{}", value);
+        }
+
+        let flat = RgbImage::from_pixel(7, 5, Rgb([200, 40, 90]));
+        let keys = crate::sort::texture_key_map(&flat);
+        assert!(keys.pixels().all(|key| key == keys.get_pixel(0, 0)));
+    }
+}
@@ -0,0 +1,216 @@
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use crate::{AnimateValue, ArgumentList};
+
+/// Everything that can go wrong while loading, sorting or saving an image
+#[derive(Debug, Error)]
+pub enum PxsortError {
+    #[error("I/O error on '{path}': {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error
+    },
+
+    #[error("'{path}' already exists, pass --force to overwrite it")]
+    AlreadyExists {
+        path: PathBuf
+    },
+
+    #[error("unsupported image format: {0}")]
+    UnsupportedFormat(#[source] image::ImageError),
+
+    #[error("error decoding image: {0}")]
+    Decode(#[source] image::ImageError),
+
+    #[error("'{path}' is {width}x{height}, more than the limit of {max_pixels} pixels")]
+    TooLarge {
+        path: PathBuf,
+        width: u32,
+        height: u32,
+        max_pixels: u64
+    },
+
+    #[error("failed to encode the image: {0}")]
+    Encode(#[from] image::ImageError),
+
+    #[error("failed to encode the PNG: {0}")]
+    PngEncode(#[from] png::EncodingError),
+
+    #[error("failed to decode the PNG: {0}")]
+    PngDecode(#[from] png::DecodingError),
+
+    #[error("scaling the {width}x{height} image by {factor} makes it too large")]
+    ScaleOverflow {
+        width: u32,
+        height: u32,
+        factor: u32
+    },
+
+    #[error("frame {index} is out of range, '{path}' has {frames} frames counting from 0")]
+    FrameOutOfRange {
+        path: PathBuf,
+        index: usize,
+        frames: usize
+    },
+
+    #[error("cannot stream the image: {0}")]
+    NotStreamable(String),
+
+    #[error("ffmpeg was not found on the PATH, it is needed to encode videos. Install it or save the animation as a GIF")]
+    FfmpegNotFound,
+
+    #[error("ffmpeg failed to encode '{path}': {status}")]
+    FfmpegFailed {
+        path: PathBuf,
+        status: std::process::ExitStatus
+    },
+
+    #[error("the mask is {found:?} but the image is {expected:?}")]
+    DimensionMismatch {
+        expected: (u32, u32),
+        found: (u32, u32)
+    },
+
+    #[error("invalid coefficients: {0}")]
+    InvalidCoefficients(String),
+
+    #[error("failed to load the mask '{path}': {source}")]
+    MaskLoadFailed {
+        path: PathBuf,
+        source: Box<PxsortError>
+    },
+
+    #[error("'{0}' cannot be animated")]
+    NotAnimatable(ArgumentList),
+
+    #[error("'{param}' cannot be animated with {value}")]
+    AnimateValueMismatch {
+        param: ArgumentList,
+        value: AnimateValue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, RgbImage};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        animate::animate_frames, coefficients_value_parser, load_frame, load_image, load_image_limited, load_mask,
+        save_image, sort::test_options, stream::stream_sort, AnimateParams, AnimateRange
+    };
+
+    /// A directory with a 4x4 PNG in `photo.png` and a file that isn't one in `broken.png`
+    fn files() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        RgbImage::new(4, 4).save(dir.path().join("photo.png")).unwrap();
+        std::fs::write(dir.path().join("broken.png"), "not a png").unwrap();
+        std::fs::write(dir.path().join("photo.xyz"), "not an image either").unwrap();
+        dir
+    }
+
+    #[test]
+    fn loading_fails_with_the_matching_variant() {
+        let dir = files();
+        let path = |name: &str| dir.path().join(name);
+
+        assert!(matches!(load_image(path("missing.png")), Err(PxsortError::Io { .. })));
+        assert!(matches!(load_image(path("photo.xyz")), Err(PxsortError::UnsupportedFormat(_))));
+        assert!(matches!(load_image(path("broken.png")), Err(PxsortError::Decode(_))));
+        assert!(matches!(
+            load_image_limited(path("photo.png"), 15),
+            Err(PxsortError::TooLarge { width: 4, height: 4, max_pixels: 15, .. })
+        ));
+        assert!(matches!(
+            load_frame(path("photo.png"), 2, u64::MAX),
+            Err(PxsortError::FrameOutOfRange { index: 2, frames: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn masks_fail_with_the_matching_variant() {
+        let dir = files();
+
+        assert!(matches!(
+            load_mask(dir.path().join("missing.png"), (4, 4), None),
+            Err(PxsortError::MaskLoadFailed { source, .. }) if matches!(*source, PxsortError::Io { .. })
+        ));
+        assert!(matches!(
+            load_mask(dir.path().join("photo.png"), (8, 4), None),
+            Err(PxsortError::DimensionMismatch { expected: (8, 4), found: (4, 4) })
+        ));
+    }
+
+    #[test]
+    fn saving_fails_with_the_matching_variant() {
+        let dir = files();
+        let image = DynamicImage::ImageRgb8(RgbImage::new(4, 4));
+
+        assert!(matches!(save_image(&image, dir.path().join("out.xyz"), false, None), Err(PxsortError::Encode(_))));
+        assert!(matches!(
+            save_image(&image, dir.path().join("missing").join("out.png"), false, None),
+            Err(PxsortError::Io { .. })
+        ));
+
+        let error = save_image(&image, dir.path().join("missing").join("out.png"), false, None).unwrap_err();
+        assert!(error.to_string().starts_with("I/O error on '"), "{}", error);
+
+        let encoding = png::EncodingError::IoError(std::io::ErrorKind::WriteZero.into());
+        assert!(matches!(PxsortError::from(encoding), PxsortError::PngEncode(_)));
+    }
+
+    #[test]
+    fn streaming_fails_with_the_matching_variant() {
+        let dir = files();
+        let output = dir.path().join("out.png");
+
+        assert!(matches!(
+            stream_sort(&dir.path().join("photo.png"), &output, test_options(&["luma", "-t", "v"])),
+            Err(PxsortError::NotStreamable(_))
+        ));
+        assert!(matches!(
+            stream_sort(&dir.path().join("broken.png"), &output, test_options(&["luma"])),
+            Err(PxsortError::PngDecode(_))
+        ));
+    }
+
+    #[test]
+    fn parsing_and_animating_fail_with_the_matching_variant() {
+        assert!(matches!(coefficients_value_parser("red=much"), Err(PxsortError::InvalidCoefficients(_))));
+        assert!(matches!(coefficients_value_parser("purple=1"), Err(PxsortError::InvalidCoefficients(_))));
+
+        let image = RgbImage::new(4, 4);
+        let options = test_options(&["luma"]);
+
+        let mask = AnimateParams { param: ArgumentList::ImageMask, range: AnimateRange::Integer { start: 1, stop: 2, step: 1 } };
+        assert!(matches!(
+            animate_frames(&image, &options, &[mask]),
+            Err(PxsortError::NotAnimatable(ArgumentList::ImageMask))
+        ));
+
+        let interval = AnimateParams {
+            param: ArgumentList::Interval,
+            range: AnimateRange::Float { start: 0.0, stop: 1.0, step: 0.5 }
+        };
+        assert!(matches!(
+            animate_frames(&image, &options, &[interval]),
+            Err(PxsortError::AnimateValueMismatch { param: ArgumentList::Interval, value: AnimateValue::Float(_) })
+        ));
+    }
+
+    #[test]
+    fn ffmpeg_errors_name_the_problem() {
+        assert!(PxsortError::FfmpegNotFound.to_string().contains("ffmpeg was not found"));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+
+            let error = PxsortError::FfmpegFailed { path: "out.mp4".into(), status: ExitStatusExt::from_raw(256) };
+            assert!(error.to_string().starts_with("ffmpeg failed to encode 'out.mp4'"));
+        }
+    }
+}
@@ -1,97 +1,369 @@
-use image::Rgb;
+use image::{Primitive, Rgb};
 use itertools::Itertools;
-use crate::sort::SortOptions;
+use num_traits::{NumCast, ToPrimitive, WrappingAdd};
+use crate::{cluster::nearest_color, sort::SortOptions};
 
-/// Update the RGB8 pixel with the [`Coefficient`]s
-///
-/// If any of the coefficients are zero, leaves the pixel values as they were
+/// Largest possible Euclidean distance between two RGB8 colors (`255 * sqrt(3)`)
+const MAX_COLOR_DISTANCE: f32 = 441.672_94;
+
+/// Channel types the extractors are generic over (`u8`, `u16`)
+pub trait Channel: Primitive + WrappingAdd {}
+
+impl<T: Primitive + WrappingAdd> Channel for T {}
+
+/// The maximum value of the channel type as `f32`
+fn channel_max<T: Channel>() -> f32 {
+    T::DEFAULT_MAX_VALUE.to_f32().unwrap_or(1.0)
+}
+
+/// Convert `value` into the channel type, saturating at the channel bounds
 ///
-/// Only used in `intensity`, `brightness`, `chroma` and `saturation`
-fn update_pixel(pixel: &[u8; 3], options: &SortOptions) -> [u8; 3] {
-    let red = if options.coefficients.red != 0.0 {
-        (pixel[0] as f32 * options.coefficients.red) as u8
-    } else {
-        pixel[0]
-    };
+/// Behaves like an `as` cast, so fractions are truncated
+fn saturating_cast<T: Channel>(value: f32) -> T {
+    NumCast::from(value.clamp(0.0, channel_max::<T>())).unwrap_or(T::DEFAULT_MIN_VALUE)
+}
 
-    let green = if options.coefficients.green != 0.0 {
-        (pixel[1] as f32 * options.coefficients.green) as u8
-    } else {
-        pixel[1]
-    };
+/// Normalize a key from the channel's scale into `0.0..=1.0`
+fn normalize<T: Channel>(key: T) -> f32 {
+    key.to_f32().unwrap_or(0.0) / channel_max::<T>()
+}
+
+/// Turn a normalized key back into an 8-bit key
+///
+/// Keys are quantized to the channel scale before normalizing, so rounding here
+/// gives back the exact 8-bit value
+pub fn to_u8_key(key: f32) -> u8 {
+    (key * u8::MAX as f32).round() as u8
+}
 
-    let blue = if options.coefficients.blue != 0.0 {
-        (pixel[2] as f32 * options.coefficients.blue) as u8
+/// Scale a channel by a [`Coefficient`], leaving it as it was if the coefficient is zero
+fn update_channel<T: Channel>(channel: T, coefficient: f32) -> T {
+    if coefficient != 0.0 {
+        saturating_cast(channel.to_f32().unwrap_or(0.0) * coefficient)
     } else {
-        pixel[2]
-    };
+        channel
+    }
+}
 
-    [red, green, blue]
+/// Update the RGB pixel with the [`Coefficient`]s
+///
+/// If any of the coefficients are zero, leaves the pixel values as they were
+///
+/// Only used in `intensity`, `brightness`, `chroma` and `saturation`
+pub fn update_pixel<T: Channel>(pixel: &[T; 3], options: &SortOptions) -> [T; 3] {
+    [
+        update_channel(pixel[0], options.coefficients.red),
+        update_channel(pixel[1], options.coefficients.green),
+        update_channel(pixel[2], options.coefficients.blue)
+    ]
 }
 
 /// Calculate the intensity of an `RGB` pixel
-pub fn intensity(Rgb(pixel): &Rgb<u8>, options: &SortOptions) -> u8 {
+pub fn intensity<T: Channel>(Rgb(pixel): &Rgb<T>, options: &SortOptions) -> f32 {
     let pixel = update_pixel(pixel, options);
-    (pixel
+    let sum = pixel
         .iter()
-        .map(|i| *i as u16)
-        .sum::<u16>()
-        .wrapping_div(3)) as u8
+        .map(|channel| channel.to_f32().unwrap_or(0.0))
+        .sum::<f32>();
+    normalize(saturating_cast::<T>(sum / 3.0))
 }
 
 /// Calculcate the brightness of an `RGB` pixel
-pub fn brightness(Rgb(pixel): &Rgb<u8>, options: &SortOptions) -> u8 {
+pub fn brightness<T: Channel>(Rgb(pixel): &Rgb<T>, options: &SortOptions) -> f32 {
     let pixel = update_pixel(pixel, options);
     let (&min, &max) = pixel.iter().minmax().into_option().unwrap();
-    max.wrapping_add(min).wrapping_div(2)
+    normalize(max.wrapping_add(&min) / (T::one() + T::one()))
 }
 
 /// Calculate the luma value of an `RGB` pixel
-pub fn luma(Rgb([r, g, b]): &Rgb<u8>, options: &SortOptions) -> u8 {
-    (options.coefficients.red * (*r as f32)
-        + options.coefficients.green * (*g as f32)
-        + options.coefficients.blue * (*b as f32)) as u8
+pub fn luma<T: Channel>(Rgb([r, g, b]): &Rgb<T>, options: &SortOptions) -> f32 {
+    let [r, g, b] = [r, g, b].map(|channel| channel.to_f32().unwrap_or(0.0));
+    normalize(saturating_cast::<T>(
+        options.coefficients.red * r + options.coefficients.green * g + options.coefficients.blue * b
+    ))
 }
 
 /// Calculate the chroma value of an `RGB` pixel
-pub fn chroma(Rgb(pixel): &Rgb<u8>, options: &SortOptions) -> u8 {
+pub fn chroma<T: Channel>(Rgb(pixel): &Rgb<T>, options: &SortOptions) -> f32 {
     let pixel = update_pixel(pixel, options);
     let (&min, &max) = pixel.iter().minmax().into_option().unwrap();
-    max.wrapping_sub(min)
+    normalize(max - min)
 }
 
 /// Calculate the hue value of an `Rgb` pixel
-pub fn hue(Rgb(pixel): &Rgb<u8>, options: &SortOptions) -> u8 {
-    let [red, green, blue] = pixel.map(|channel| channel as f32 / 255.0);
-    let (&min, &max) = pixel.iter().minmax().into_option().unwrap();
+///
+/// With a hue origin the key is the angle on the hue circle as a fraction of a full turn. The hue
+/// origin is subtracted from the angle first, so the point where the hue circle wraps around from
+/// `1.0` back to `0.0` can be moved. Without one the key is the one hue always had, see [`unrotated_hue`]
+pub fn hue<T: Channel>(Rgb(pixel): &Rgb<T>, options: &SortOptions) -> f32 {
+    if options.hue_origin == 0.0 {
+        return unrotated_hue(pixel, options);
+    }
+
+    let [red, green, blue] = pixel.map(|channel| channel.to_f32().unwrap_or(0.0) / channel_max::<T>());
+    let max = red.max(green).max(blue);
+    let min = red.min(green).min(blue);
 
     if max == min {
         // hue is undefined for grayscale colors, return arbitrary value
-        return 0;
+        return 0.0;
     }
 
-    let diff = (max - min) as f32;
-    let mut hue = match max {
-        r if r == max => options.coefficients.red + (green - blue) / diff,
-        g if g == max => options.coefficients.green + (blue - red) / diff,
-        _ => options.coefficients.blue + (red - green) / diff
+    // the sector of the largest channel, with the channels and their difference on the same scale
+    let diff = max - min;
+    let hue = if red == max {
+        options.coefficients.red + (green - blue) / diff
+    } else if green == max {
+        options.coefficients.green + (blue - red) / diff
+    } else {
+        options.coefficients.blue + (red - green) / diff
     };
 
-    hue *= 60.0;
+    (hue * 60.0 - options.hue_origin).rem_euclid(360.0) / 360.0
+}
+
+/// The hue key of images sorted without a hue origin, kept exactly as it was
+///
+/// It always takes the sector of the red channel and divides the normalized channels by their
+/// difference on the channel scale, then saturates the angle at `u8::MAX` degrees. So it only
+/// tells apart whether green or blue is larger, but sorts the same way it always has
+fn unrotated_hue<T: Channel>(pixel: &[T; 3], options: &SortOptions) -> f32 {
+    let [_, green, blue] = pixel.map(|channel| channel.to_f32().unwrap_or(0.0) / channel_max::<T>());
+    let (&min, &max) = pixel.iter().minmax().into_option().unwrap();
+
+    if max == min {
+        // hue is undefined for grayscale colors, return arbitrary value
+        return 0.0;
+    }
+
+    let diff = (max - min).to_f32().unwrap_or(1.0);
+    let mut hue = (options.coefficients.red + (green - blue) / diff) * 60.0;
     if hue < 0.0 {
         hue += 360.0;
     }
 
-    hue as u8
+    hue.clamp(0.0, u8::MAX as f32).trunc() / u8::MAX as f32
 }
 
 /// Calculate the saturation of an `RGB` pixel
-pub fn saturation(Rgb(pixel): &Rgb<u8>, options: &SortOptions) -> u8 {
+pub fn saturation<T: Channel>(Rgb(pixel): &Rgb<T>, options: &SortOptions) -> f32 {
     let pixel = update_pixel(pixel, options);
     let (&min, &max) = pixel.iter().minmax().into_option().unwrap();
-    if max != 0 {
-        max.wrapping_sub(min) / max
+    if max != T::zero() {
+        normalize((max - min) / max)
     } else {
-        0
+        0.0
+    }
+}
+
+/// Calculate the Euclidean distance of an `RGB` pixel to the target color
+///
+/// The distance is scaled so that the furthest possible color maps to the channel maximum,
+/// so pixels close to the target get low keys
+pub fn color_distance<T: Channel>(Rgb(pixel): &Rgb<T>, options: &SortOptions) -> f32 {
+    let Rgb(target) = options.target.unwrap_or(Rgb([0, 0, 0]));
+    let scale = channel_max::<T>() / u8::MAX as f32;
+
+    // measured on the 8-bit scale, which `scale` leaves exactly as it is for 8-bit channels
+    let distance = pixel
+        .iter()
+        .zip(target.iter())
+        .map(|(channel, &target)| (channel.to_f32().unwrap_or(0.0) / scale - target as f32).powi(2))
+        .sum::<f32>()
+        .sqrt();
+
+    normalize(saturating_cast::<T>(distance / MAX_COLOR_DISTANCE * channel_max::<T>()))
+}
+
+/// Calculate the index of the dominant color nearest to an `RGB` pixel
+///
+/// The dominant colors are ordered from dark to bright, so the image is sorted into its palette bands
+pub fn dominant<T: Channel>(Rgb(pixel): &Rgb<T>, options: &SortOptions) -> f32 {
+    let colors = options.dominant_colors.as_deref().unwrap_or_default();
+    let scale = u8::MAX as f32 / channel_max::<T>();
+    let index = nearest_color(colors, pixel.map(|channel| channel.to_f32().unwrap_or(0.0) * scale));
+
+    index as f32 / (colors.len().max(2) - 1) as f32
+}
+
+/// The texture of an `RGB` pixel on its own, which is always flat
+///
+/// Sorting by texture looks at the neighbourhood of every pixel, so its keys come from
+/// [`crate::sort::texture_key_map`] instead
+pub fn flat<T: Channel>(_: &Rgb<T>, _: &SortOptions) -> f32 {
+    0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::ValueEnum;
+    use image::Rgb;
+    use rayon::prelude::*;
+
+    use super::*;
+    use crate::{sort::test_options, Coefficients, SortingAlgorithm};
+
+    /// Command lines covering every extractor, with and without coefficients that update the pixel first
+    const COMMAND_LINES: &[&[&str]] = &[
+        &["luma"],
+        &["luma", "-f", "red=0.3 green=0.6 blue=0.1"],
+        &["chroma"],
+        &["chroma", "-f", "red=0.5 green=1.5"],
+        &["saturation"],
+        &["saturation", "-f", "red=0.5 green=1.5"],
+        &["brightness"],
+        &["brightness", "-f", "red=0.5 green=1.5"],
+        &["hue"],
+        &["hue", "--hue-origin", "100"],
+        &["color-distance", "--target", "ff8000"],
+        &["dominant"],
+        &["texture"]
+    ];
+
+    /// The options of `args`, with dominant colors that no 8-bit color is equally near to,
+    /// since their squared lengths differ in parity
+    fn options(args: &[&str]) -> SortOptions {
+        let mut options = test_options(args);
+        options.dominant_colors = Some(vec![Rgb([10, 10, 10]), Rgb([200, 60, 31])]);
+        options
+    }
+
+    /// Every 8-bit color
+    fn all_colors() -> impl ParallelIterator<Item = Rgb<u8>> {
+        (0..1u32 << 24).into_par_iter().map(|color| {
+            let [red, green, blue, _] = color.to_le_bytes();
+            Rgb([red, green, blue])
+        })
+    }
+
+    /// The 8-bit key as the extractors computed it before they were generic over the channel type,
+    /// with their truncation and wrapping. Hue with a hue origin is the full circle it was fixed to for it
+    fn reference_key(&Rgb(pixel): &Rgb<u8>, options: &SortOptions) -> u8 {
+        let Coefficients { red, green, blue } = options.coefficients;
+        let updated = [(pixel[0], red), (pixel[1], green), (pixel[2], blue)]
+            .map(|(channel, coefficient)| if coefficient != 0.0 { (channel as f32 * coefficient) as u8 } else { channel });
+        let (min, max) = (*updated.iter().min().unwrap(), *updated.iter().max().unwrap());
+
+        match options.by {
+            SortingAlgorithm::Luma => (red * pixel[0] as f32 + green * pixel[1] as f32 + blue * pixel[2] as f32) as u8,
+            SortingAlgorithm::Chroma => max.wrapping_sub(min),
+            SortingAlgorithm::Saturation if max != 0 => max.wrapping_sub(min) / max,
+            SortingAlgorithm::Saturation => 0,
+            SortingAlgorithm::Brightness => max.wrapping_add(min).wrapping_div(2),
+            SortingAlgorithm::Hue if options.hue_origin == 0.0 => {
+                let (low, high) = (*pixel.iter().min().unwrap(), *pixel.iter().max().unwrap());
+                if high == low {
+                    return 0;
+                }
+
+                let [_, g, b] = pixel.map(|channel| channel as f32 / 255.0);
+                let mut hue = red + (g - b) / (high - low) as f32;
+                hue *= 60.0;
+                if hue < 0.0 {
+                    hue += 360.0;
+                }
+
+                hue as u8
+            }
+            SortingAlgorithm::Hue => {
+                let [r, g, b] = pixel.map(|channel| channel as f32 / 255.0);
+                let (high, low) = (r.max(g).max(b), r.min(g).min(b));
+                if high == low {
+                    return 0;
+                }
+
+                let sector = if r == high {
+                    red + (g - b) / (high - low)
+                } else if g == high {
+                    green + (b - r) / (high - low)
+                } else {
+                    blue + (r - g) / (high - low)
+                };
+
+                ((sector * 60.0 - options.hue_origin).rem_euclid(360.0) / 360.0 * 255.0).round() as u8
+            }
+            SortingAlgorithm::ColorDistance => {
+                let Rgb(target) = options.target.unwrap();
+                let distance = pixel
+                    .iter()
+                    .zip(target)
+                    .map(|(&channel, target)| (channel as f32 - target as f32).powi(2))
+                    .sum::<f32>()
+                    .sqrt();
+
+                (distance / 441.672_94 * 255.0) as u8
+            }
+            SortingAlgorithm::Dominant => {
+                let colors = options.dominant_colors.as_deref().unwrap();
+                let distance = |Rgb(color): &Rgb<u8>| {
+                    color.iter().zip(pixel).map(|(&a, b)| (a as i32 - b as i32).pow(2)).sum::<i32>()
+                };
+                let nearest = (0..colors.len()).min_by_key(|&index| distance(&colors[index])).unwrap();
+
+                (nearest as f32 / (colors.len() - 1) as f32 * 255.0).round() as u8
+            }
+            SortingAlgorithm::Texture => 0
+        }
+    }
+
+    #[test]
+    fn every_extractor_is_covered() {
+        for by in SortingAlgorithm::value_variants() {
+            assert!(COMMAND_LINES.iter().any(|args| args[0] == by.to_string()), "{} is not tested", by);
+        }
+    }
+
+    #[test]
+    fn eight_bit_keys_match_the_reference_for_every_color() {
+        for args in COMMAND_LINES {
+            let options = options(args);
+            let sorter = options.by.into_rgb_sorter();
+
+            let mismatch = all_colors().find_any(|pixel| sorter(pixel, &options) != reference_key(pixel, &options));
+            assert_eq!(mismatch, None, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn sixteen_bit_keys_follow_the_eight_bit_ones() {
+        // updated channels truncate at different points in the two widths, and the wrapping
+        // of brightness then falls on different colors, so only the plain extractors compare
+        for args in COMMAND_LINES.iter().filter(|args| !args.contains(&"-f")) {
+            let options = options(args);
+            let (narrow, wide) = (options.by.into_sorter::<u8>(), options.by.into_sorter::<u16>());
+
+            let mismatch = all_colors().find_any(|pixel| {
+                let wide_pixel = Rgb(pixel.0.map(|channel| channel as u16 * 257));
+                to_u8_key(narrow(pixel, &options)).abs_diff(to_u8_key(wide(&wide_pixel, &options))) > 1
+            });
+            assert_eq!(mismatch, None, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn pixels_near_the_target_get_low_color_distance_keys() {
+        let options = test_options(&["color-distance", "--target", "ff8000"]);
+        let key = |pixel: [u8; 3]| to_u8_key(color_distance(&Rgb(pixel), &options));
+
+        assert_eq!(key([255, 128, 0]), 0);
+        assert!(key([250, 130, 5]) <= 5);
+        assert!(key([250, 130, 5]) < key([200, 100, 50]));
+        assert!(key([200, 100, 50]) < key([0, 0, 255]));
+    }
+
+    #[test]
+    fn hue_origins_reorder_a_rainbow_span() {
+        let rainbow = [[255u8, 0, 0], [255, 255, 0], [0, 255, 0], [0, 255, 255], [0, 0, 255], [255, 0, 255]].map(Rgb);
+        let sorted = |origin: &str| {
+            let options = test_options(&["hue", "--hue-origin", origin]);
+            let mut span = rainbow.to_vec();
+            span.sort_by_key(|pixel| to_u8_key(hue(pixel, &options)));
+            span
+        };
+
+        // cyan first with the circle turned halfway, magenta first with it turned five sixths
+        assert_eq!(sorted("180"), [&rainbow[3..], &rainbow[..3]].concat());
+        assert_eq!(sorted("300"), [&rainbow[5..], &rainbow[..5]].concat());
+        assert_ne!(sorted("180"), sorted("90"));
     }
 }
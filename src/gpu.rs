@@ -0,0 +1,329 @@
+use std::{borrow::Cow, sync::mpsc::channel};
+
+use anyhow::{anyhow, bail, Context};
+use image::{GrayImage, RgbImage};
+use wgpu::util::DeviceExt;
+
+use crate::{
+    sort::{rgb8_key_map, SortOptions},
+    SortingAlgorithm
+};
+
+/// Images with fewer pixels than this get their keys on the CPU,
+/// since setting up the device would take longer than extracting them
+pub const GPU_PIXEL_LIMIT: u64 = 1024 * 1024;
+
+/// Number of invocations of one workgroup, has to match `@workgroup_size` in [`SHADER`]
+const WORKGROUP_SIZE: u32 = 64;
+
+/// Largest number of workgroups in one dimension of a dispatch that every device supports
+const MAX_WORKGROUPS: u32 = 65535;
+
+/// Compute shader that extracts the key of every pixel
+///
+/// Mirrors the `u8` extractors in [`crate::extractor`], including their truncation and wrapping
+const SHADER: &str = r#"
+struct Params {
+    kind: u32,
+    red: f32,
+    green: f32,
+    blue: f32,
+    hue_origin: f32,
+    target_color: u32,
+    count: u32,
+    padding: u32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> pixels: array<u32>;
+@group(0) @binding(2) var<storage, read_write> keys: array<u32>;
+
+fn unpack(pixel: u32) -> vec3<f32> {
+    return vec3<f32>(f32(pixel & 255u), f32((pixel >> 8u) & 255u), f32((pixel >> 16u) & 255u));
+}
+
+fn saturating_cast(value: f32) -> f32 {
+    return floor(clamp(value, 0.0, 255.0));
+}
+
+fn update_channel(channel: f32, coefficient: f32) -> f32 {
+    if coefficient != 0.0 {
+        return saturating_cast(channel * coefficient);
+    }
+    return channel;
+}
+
+fn update_pixel(pixel: vec3<f32>) -> vec3<f32> {
+    return vec3<f32>(
+        update_channel(pixel.x, params.red),
+        update_channel(pixel.y, params.green),
+        update_channel(pixel.z, params.blue)
+    );
+}
+
+fn hue(pixel: vec3<f32>) -> f32 {
+    let color = pixel / 255.0;
+    let high = max(max(color.x, color.y), color.z);
+    let low = min(min(color.x, color.y), color.z);
+
+    if high == low {
+        return 0.0;
+    }
+
+    // without a hue origin the key is the unrotated one of the CPU extractor,
+    // which divides by the difference of the channels on the 0 to 255 scale
+    if params.hue_origin == 0.0 {
+        var unrotated = (params.red + (color.y - color.z) / ((high - low) * 255.0)) * 60.0;
+        if unrotated < 0.0 {
+            unrotated += 360.0;
+        }
+        return saturating_cast(unrotated) / 255.0;
+    }
+
+    let diff = high - low;
+    var angle: f32;
+    if color.x == high {
+        angle = params.red + (color.y - color.z) / diff;
+    } else if color.y == high {
+        angle = params.green + (color.z - color.x) / diff;
+    } else {
+        angle = params.blue + (color.x - color.y) / diff;
+    }
+
+    let degrees = angle * 60.0 - params.hue_origin;
+    return (degrees - 360.0 * floor(degrees / 360.0)) / 360.0;
+}
+
+fn key(pixel: vec3<f32>) -> f32 {
+    let updated = update_pixel(pixel);
+    let high = max(max(updated.x, updated.y), updated.z);
+    let low = min(min(updated.x, updated.y), updated.z);
+
+    switch params.kind {
+        case 0u: {
+            return saturating_cast(params.red * pixel.x + params.green * pixel.y + params.blue * pixel.z) / 255.0;
+        }
+        case 1u: {
+            return (high - low) / 255.0;
+        }
+        case 2u: {
+            if high == 0.0 {
+                return 0.0;
+            }
+            return floor((high - low) / high) / 255.0;
+        }
+        case 3u: {
+            return hue(pixel);
+        }
+        case 4u: {
+            return floor(f32((u32(high) + u32(low)) & 255u) / 2.0) / 255.0;
+        }
+        default: {
+            let distance = length(pixel - unpack(params.target_color));
+            return saturating_cast(distance / 441.67294 * 255.0) / 255.0;
+        }
+    }
+}
+
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) id: vec3<u32>, @builtin(num_workgroups) groups: vec3<u32>) {
+    let index = id.x + id.y * groups.x * 64u;
+    if index >= params.count {
+        return;
+    }
+
+    keys[index] = u32(round(key(unpack(pixels[index])) * 255.0));
+}
+"#;
+
+/// Map every pixel of an `RGB8` image to its key like [`rgb8_key_map`], computed on the GPU
+///
+/// Falls back to the CPU with a warning if there is no usable device or the image doesn't fit on it
+pub fn rgb8_key_map_gpu(image: &RgbImage, options: &SortOptions) -> GrayImage {
+    match pollster::block_on(gpu_key_map(image, options)) {
+        Ok(keys) => keys,
+        Err(error) => {
+            log::warn!("{:#}, extracting the keys on the CPU", error);
+            rgb8_key_map(image, options)
+        }
+    }
+}
+
+/// Position of the extractor in the `switch` of [`SHADER`]
+fn shader_kind(by: SortingAlgorithm) -> u32 {
+    match by {
+        SortingAlgorithm::Luma => 0,
+        SortingAlgorithm::Chroma => 1,
+        SortingAlgorithm::Saturation => 2,
+        SortingAlgorithm::Hue => 3,
+        SortingAlgorithm::Brightness => 4,
+        SortingAlgorithm::ColorDistance => 5,
+        SortingAlgorithm::Dominant | SortingAlgorithm::Texture => {
+            unreachable!("dominant color and texture keys are always extracted on the CPU")
+        }
+    }
+}
+
+/// Pack an RGB color into the low three bytes of a `u32`, red first
+fn pack([red, green, blue]: [u8; 3]) -> u32 {
+    u32::from_le_bytes([red, green, blue, 0])
+}
+
+/// Extract the keys of `image` with [`SHADER`] on the first device that is found
+async fn gpu_key_map(image: &RgbImage, options: &SortOptions) -> anyhow::Result<GrayImage> {
+    let (width, height) = image.dimensions();
+    let count = width.checked_mul(height).ok_or_else(|| anyhow!("{}x{} pixels don't fit into one dispatch", width, height))?;
+
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+    let adapter = instance
+        .request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            compatible_surface: None,
+            force_fallback_adapter: false
+        })
+        .await
+        .ok_or_else(|| anyhow!("no GPU adapter was found"))?;
+    let (device, queue) = adapter
+        .request_device(
+            &wgpu::DeviceDescriptor { label: None, features: wgpu::Features::empty(), limits: adapter.limits() },
+            None
+        )
+        .await
+        .context("failed to open the GPU")?;
+
+    let size = count as u64 * 4;
+    let limits = device.limits();
+    let limit = (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size);
+    if size > limit {
+        bail!("the keys of {} pixels take {} bytes, more than the {} bytes the GPU can bind", count, size, limit);
+    }
+
+    let target = options.target.map_or(0, |target| pack(target.0));
+    let params = [
+        shader_kind(options.by),
+        options.coefficients.red.to_bits(),
+        options.coefficients.green.to_bits(),
+        options.coefficients.blue.to_bits(),
+        options.hue_origin.to_bits(),
+        target,
+        count,
+        0
+    ];
+
+    let to_bytes = |words: &[u32]| words.iter().flat_map(|word| word.to_le_bytes()).collect::<Vec<_>>();
+    let pixels = image.pixels().map(|pixel| pack(pixel.0)).collect::<Vec<_>>();
+
+    let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("params"),
+        contents: &to_bytes(&params),
+        usage: wgpu::BufferUsages::UNIFORM
+    });
+    let pixels_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("pixels"),
+        contents: &to_bytes(&pixels),
+        usage: wgpu::BufferUsages::STORAGE
+    });
+    let keys_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("keys"),
+        size,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        mapped_at_creation: false
+    });
+    let staging_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("staging"),
+        size,
+        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false
+    });
+
+    let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("keys"),
+        source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(SHADER))
+    });
+    let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: Some("keys"),
+        layout: None,
+        module: &shader,
+        entry_point: "main"
+    });
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: None,
+        layout: &pipeline.get_bind_group_layout(0),
+        entries: &[
+            wgpu::BindGroupEntry { binding: 0, resource: params_buffer.as_entire_binding() },
+            wgpu::BindGroupEntry { binding: 1, resource: pixels_buffer.as_entire_binding() },
+            wgpu::BindGroupEntry { binding: 2, resource: keys_buffer.as_entire_binding() }
+        ]
+    });
+
+    // large images don't fit into one row of workgroups, so they spill over into more rows
+    let workgroups = (count + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE;
+    let (groups_x, groups_y) = (workgroups.min(MAX_WORKGROUPS), (workgroups + MAX_WORKGROUPS - 1) / MAX_WORKGROUPS);
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+    {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+        pass.set_pipeline(&pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.dispatch_workgroups(groups_x, groups_y, 1);
+    }
+    encoder.copy_buffer_to_buffer(&keys_buffer, 0, &staging_buffer, 0, size);
+    queue.submit(Some(encoder.finish()));
+
+    let slice = staging_buffer.slice(..);
+    let (tx, rx) = channel();
+    slice.map_async(wgpu::MapMode::Read, move |result| {
+        let _ = tx.send(result);
+    });
+    device.poll(wgpu::Maintain::Wait);
+    rx.recv()?.context("failed to read the keys back from the GPU")?;
+
+    let keys = slice
+        .get_mapped_range()
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]) as u8)
+        .collect::<Vec<_>>();
+    staging_buffer.unmap();
+
+    GrayImage::from_raw(width, height, keys).ok_or_else(|| anyhow!("the GPU returned too few keys"))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::sort::test_options;
+
+    #[test]
+    fn gpu_and_cpu_key_maps_match_within_tolerance() {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        if pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default())).is_none() {
+            eprintln!("no GPU adapter was found, skipping");
+            return;
+        }
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let image = RgbImage::from_fn(67, 31, |_, _| Rgb(rng.gen()));
+        let command_lines: &[&[&str]] = &[
+            &["luma"],
+            &["chroma"],
+            &["saturation"],
+            &["hue"],
+            &["hue", "--hue-origin", "100"],
+            &["brightness"],
+            &["color-distance", "--target", "3080c0"]
+        ];
+
+        for args in command_lines {
+            let options = test_options(args);
+            let gpu = pollster::block_on(gpu_key_map(&image, &options)).unwrap();
+            let cpu = rgb8_key_map(&image, &options);
+
+            for (gpu, cpu) in gpu.pixels().zip(cpu.pixels()) {
+                assert!(gpu.0[0].abs_diff(cpu.0[0]) <= 1, "{:?}: {} on the GPU, {} on the CPU", args, gpu.0[0], cpu.0[0]);
+            }
+        }
+    }
+}
@@ -1,11 +1,1084 @@
-use std::path::Path;
+use std::{collections::HashSet, fs::File, io::{BufReader, BufWriter, Seek, Write}, path::Path};
 
-use anyhow::anyhow;
-use image::DynamicImage;
+use image::{
+    codecs::{gif::GifDecoder, png::PngDecoder},
+    imageops::{self, FilterType}, AnimationDecoder, ColorType, DynamicImage, Frame, Frames, GrayImage, ImageBuffer, ImageError,
+    ImageFormat, Luma, Pixel, Rgb, RgbImage
+};
 
-/// Loads an image into memory from `path`
-pub fn load_image<T: AsRef<Path>>(path: T) -> anyhow::Result<DynamicImage> {
-    image::io::Reader::open(path.as_ref())?
-        .decode()
-        .map_err(|error| anyhow!("error decoding image: {}", error))
+use crate::{Coefficients, MaskMode, Polygon, PxsortError};
+
+/// Largest number of pixels an image may have to be loaded, unless another limit is given
+///
+/// As large as a 16384x16384 image, which takes 1 GiB as 8-bit RGBA
+pub const DEFAULT_MAX_PIXELS: u64 = 1 << 28;
+
+/// Bytes the decoder may allocate for every allowed pixel, enough for 32-bit float RGBA
+const MAX_BYTES_PER_PIXEL: u64 = 16;
+
+/// Loads an image into memory from `path`, if it has at most [`DEFAULT_MAX_PIXELS`]
+pub fn load_image<T: AsRef<Path>>(path: T) -> Result<DynamicImage, PxsortError> {
+    load_image_limited(path, DEFAULT_MAX_PIXELS)
+}
+
+/// Loads an image like [`load_image`], failing before it is decoded if it has more than `max_pixels`
+pub fn load_image_limited<T: AsRef<Path>>(path: T, max_pixels: u64) -> Result<DynamicImage, PxsortError> {
+    let path = path.as_ref();
+    check_pixels(path, max_pixels)?;
+
+    // a decoder could still allocate more than the header claims, so the allocations are limited too
+    let mut limits = image::io::Limits::default();
+    limits.max_alloc = Some(max_pixels.saturating_mul(MAX_BYTES_PER_PIXEL));
+
+    let mut reader = open_image(path)?;
+    reader.limits(limits);
+    reader.decode().map_err(decode_error)
+}
+
+/// Tell images the decoders don't support apart from broken ones
+fn decode_error(error: ImageError) -> PxsortError {
+    match error {
+        ImageError::Unsupported(_) => PxsortError::UnsupportedFormat(error),
+        error => PxsortError::Decode(error)
+    }
+}
+
+/// Open the image at `path` to decode it as the format its extension names
+fn open_image(path: &Path) -> Result<image::io::Reader<BufReader<File>>, PxsortError> {
+    image::io::Reader::open(path).map_err(|source| PxsortError::Io { path: path.to_path_buf(), source })
+}
+
+/// Fail with [`PxsortError::TooLarge`] if the header of the image at `path` declares more than `max_pixels`
+///
+/// Only the header is read to find the size
+fn check_pixels(path: &Path, max_pixels: u64) -> Result<(), PxsortError> {
+    let (width, height) = open_image(path)?.into_dimensions().map_err(decode_error)?;
+    if width as u64 * height as u64 > max_pixels {
+        return Err(PxsortError::TooLarge { path: path.to_path_buf(), width, height, max_pixels });
+    }
+
+    Ok(())
+}
+
+/// The frames of the animated GIF or APNG at `path`, each composited onto the whole canvas
+///
+/// `None` for the other formats and for PNGs without an animation
+fn animation_frames(path: &Path) -> Result<Option<Frames<'static>>, PxsortError> {
+    let open = || {
+        let file = File::open(path).map_err(|source| PxsortError::Io { path: path.to_path_buf(), source })?;
+        Ok::<_, PxsortError>(BufReader::new(file))
+    };
+
+    match ImageFormat::from_path(path) {
+        Ok(ImageFormat::Gif) => Ok(Some(GifDecoder::new(open()?).map_err(decode_error)?.into_frames())),
+        Ok(ImageFormat::Png) => {
+            let decoder = PngDecoder::new(open()?).map_err(decode_error)?;
+            Ok(decoder.is_apng().then(|| decoder.apng().into_frames()))
+        }
+        _ => Ok(None)
+    }
+}
+
+/// Loads every frame of the animated GIF or APNG at `path` with its delay, in order
+///
+/// A still image is a single frame without a delay
+pub fn load_frames<T: AsRef<Path>>(path: T, max_pixels: u64) -> Result<Vec<Frame>, PxsortError> {
+    let path = path.as_ref();
+
+    match animation_frames(path)? {
+        Some(frames) => {
+            check_pixels(path, max_pixels)?;
+            frames.collect_frames().map_err(decode_error)
+        }
+        None => Ok(vec![Frame::new(load_image_limited(path, max_pixels)?.into_rgba8())])
+    }
+}
+
+/// Loads frame `index` of the animated GIF or APNG at `path`, counting from 0, as RGBA
+///
+/// Still images only have frame 0. Fails with [`PxsortError::FrameOutOfRange`] past the last frame
+pub fn load_frame<T: AsRef<Path>>(path: T, index: usize, max_pixels: u64) -> Result<DynamicImage, PxsortError> {
+    let path = path.as_ref();
+    let out_of_range = |frames| PxsortError::FrameOutOfRange { path: path.to_path_buf(), index, frames };
+
+    let frames = match animation_frames(path)? {
+        Some(frames) => frames,
+        None if index == 0 => return load_image_limited(path, max_pixels),
+        None => return Err(out_of_range(1))
+    };
+    check_pixels(path, max_pixels)?;
+
+    let mut count = 0;
+    for frame in frames {
+        let frame = frame.map_err(decode_error)?;
+        if count == index {
+            return Ok(DynamicImage::ImageRgba8(frame.into_buffer()));
+        }
+
+        count += 1;
+    }
+
+    Err(out_of_range(count))
+}
+
+/// Loads an image like [`load_image_limited`], but keeps the rows of a corrupt PNG that could be decoded
+///
+/// The rows after the first broken one are filled with black, so the image keeps the size its header declares.
+/// Other formats and interlaced PNGs fail like they would with [`load_image_limited`]
+pub fn load_image_lenient<T: AsRef<Path>>(path: T, max_pixels: u64) -> Result<DynamicImage, PxsortError> {
+    let path = path.as_ref();
+
+    match load_image_limited(path, max_pixels) {
+        Err(error @ PxsortError::Decode(_)) if matches!(ImageFormat::from_path(path), Ok(ImageFormat::Png)) => {
+            load_png_partial(path).ok_or(error)
+        }
+        result => result
+    }
+}
+
+/// Decode the rows of a non-interlaced PNG up to the first one that fails, filling the rest with black
+///
+/// `None` if not even the header can be read
+fn load_png_partial(path: &Path) -> Option<DynamicImage> {
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path).ok()?));
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().ok()?;
+
+    let (width, height) = (reader.info().width, reader.info().height);
+    if reader.info().interlaced {
+        return None;
+    }
+
+    let (color, _) = reader.output_color_type();
+    let row_bytes = width as usize * color.samples();
+    let mut bytes = vec![0; row_bytes * height as usize];
+    let mut rows = 0;
+
+    while let Ok(Some(row)) = reader.next_row() {
+        bytes[rows * row_bytes..(rows + 1) * row_bytes].copy_from_slice(row.data());
+        rows += 1;
+    }
+
+    if rows < height as usize {
+        log::warn!(
+            "'{}' is corrupt after row {} of {}, filling the rest with black",
+            path.display(),
+            rows,
+            height
+        );
+    }
+
+    match color {
+        png::ColorType::Grayscale => GrayImage::from_raw(width, height, bytes).map(DynamicImage::ImageLuma8),
+        png::ColorType::GrayscaleAlpha => ImageBuffer::from_raw(width, height, bytes).map(DynamicImage::ImageLumaA8),
+        png::ColorType::Rgb => RgbImage::from_raw(width, height, bytes).map(DynamicImage::ImageRgb8),
+        _ => ImageBuffer::from_raw(width, height, bytes).map(DynamicImage::ImageRgba8)
+    }
+}
+
+/// Loads an image like [`load_image_limited`], rotated and flipped upright by its EXIF orientation
+pub fn load_image_oriented<T: AsRef<Path>>(path: T, max_pixels: u64) -> Result<DynamicImage, PxsortError> {
+    let path = path.as_ref();
+    let image = load_image_limited(path, max_pixels)?;
+
+    Ok(orient_by_exif(image, path))
+}
+
+/// Rotate and flip `image` upright by the EXIF orientation of the file at `path` it was loaded from
+pub fn orient_by_exif<T: AsRef<Path>>(image: DynamicImage, path: T) -> DynamicImage {
+    match exif_orientation(path.as_ref()).unwrap_or(1) {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image
+    }
+}
+
+/// The EXIF orientation tag of the image at `path`, if it has one
+fn exif_orientation(path: &Path) -> Option<u32> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let exif = exif::Reader::new().read_from_container(&mut reader).ok()?;
+
+    exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?.value.get_uint(0)
+}
+
+/// The raw EXIF metadata of the image at `path`, if it has any
+pub fn read_exif<T: AsRef<Path>>(path: T) -> Option<Vec<u8>> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let exif = exif::Reader::new().read_from_container(&mut reader).ok()?;
+
+    Some(exif.buf().to_vec())
+}
+
+/// Save the image to `path`, in the format its extension names
+///
+/// With `embed_srgb` set PNGs are written with an sRGB chunk declaring their color space,
+/// and `exif` is written into an eXIf chunk. Other formats are saved without either.
+/// The image is written with [`write_atomically`], so a failed save leaves an existing file at `path` as it was
+pub fn save_image<T: AsRef<Path>>(
+    image: &DynamicImage,
+    path: T,
+    embed_srgb: bool,
+    exif: Option<&[u8]>
+) -> Result<(), PxsortError> {
+    let path = path.as_ref();
+    write_atomically(path, |writer| encode_image(image, writer, path, embed_srgb, exif))
+}
+
+/// Write the file at `path` with `write`, into a temporary file next to it first
+///
+/// The temporary file is only renamed over `path` once `write` succeeded and everything is flushed,
+/// otherwise it is removed and an existing file at `path` is left as it was
+pub(crate) fn write_atomically<R>(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<&mut File>) -> Result<R, PxsortError>
+) -> Result<R, PxsortError> {
+    let io_error = |source| PxsortError::Io { path: path.to_path_buf(), source };
+
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(".pxsort-");
+
+    // temporary files are only readable by their owner, the output gets the usual permissions instead
+    #[cfg(unix)]
+    builder.permissions(<std::fs::Permissions as std::os::unix::fs::PermissionsExt>::from_mode(0o666));
+
+    let mut temp = builder.tempfile_in(dir).map_err(io_error)?;
+
+    let result = {
+        let mut writer = BufWriter::new(temp.as_file_mut());
+        let result = write(&mut writer)?;
+        writer.flush().map_err(io_error)?;
+        result
+    };
+
+    temp.persist(path).map_err(|error| io_error(error.error))?;
+
+    Ok(result)
+}
+
+/// Encode the image into `writer` for [`save_image`], in the format the extension of `path` names
+fn encode_image<W: Write + Seek>(
+    image: &DynamicImage,
+    writer: &mut W,
+    path: &Path,
+    embed_srgb: bool,
+    exif: Option<&[u8]>
+) -> Result<(), PxsortError> {
+    let is_png = path
+        .extension()
+        .map_or(false, |extension| extension.eq_ignore_ascii_case("png"));
+
+    if !embed_srgb && exif.is_none() {
+        return Ok(image.write_to(writer, ImageFormat::from_path(path)?)?);
+    }
+
+    if !is_png {
+        if embed_srgb {
+            log::warn!("the sRGB color space can only be embedded into PNGs, saving without it");
+        }
+
+        if exif.is_some() {
+            log::warn!("metadata can only be kept in PNGs, saving without it");
+        }
+
+        return Ok(image.write_to(writer, ImageFormat::from_path(path)?)?);
+    }
+
+    let (color, bytes) = match image {
+        DynamicImage::ImageLuma8(image) => (png::ColorType::Grayscale, image.as_raw().clone()),
+        DynamicImage::ImageLumaA8(image) => (png::ColorType::GrayscaleAlpha, image.as_raw().clone()),
+        DynamicImage::ImageRgb8(image) => (png::ColorType::Rgb, image.as_raw().clone()),
+        other => (png::ColorType::Rgba, other.to_rgba8().into_raw())
+    };
+
+    let mut encoder = png::Encoder::new(writer, image.width(), image.height());
+    encoder.set_color(color);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    if embed_srgb {
+        encoder.set_srgb(png::SrgbRenderingIntent::Perceptual);
+    }
+
+    let mut writer = encoder.write_header()?;
+
+    // the eXIf chunk has to come before the image data
+    if let Some(exif) = exif {
+        writer.write_chunk(png::chunk::ChunkType(*b"eXIf"), exif)?;
+    }
+
+    writer.write_image_data(&bytes)?;
+
+    Ok(())
+}
+
+/// Loads the mask at `path` as grayscale
+///
+/// Without a `mode` it has to be as large as the image it masks, otherwise it is fitted onto it
+pub fn load_mask<T: AsRef<Path>>(
+    path: T,
+    dimensions: (u32, u32),
+    mode: Option<MaskMode>
+) -> Result<GrayImage, PxsortError> {
+    let path = path.as_ref();
+
+    let mask = load_image(path)
+        .map_err(|source| PxsortError::MaskLoadFailed { path: path.to_path_buf(), source: Box::new(source) })?
+        .into_luma8();
+
+    if mask.dimensions() == dimensions {
+        return Ok(mask);
+    }
+
+    let (width, height) = dimensions;
+
+    match mode {
+        None => Err(PxsortError::DimensionMismatch { expected: dimensions, found: mask.dimensions() }),
+        Some(MaskMode::Stretch) => Ok(imageops::resize(&mask, width, height, FilterType::Nearest)),
+        Some(MaskMode::Tile) => {
+            let (mask_width, mask_height) = mask.dimensions();
+            Ok(GrayImage::from_fn(width, height, |x, y| *mask.get_pixel(x % mask_width, y % mask_height)))
+        }
+        Some(MaskMode::Center) => {
+            // a black canvas, so nothing outside of the mask gets sorted
+            let mut canvas = GrayImage::new(width, height);
+            let x = (width as i64 - mask.width() as i64) / 2;
+            let y = (height as i64 - mask.height() as i64) / 2;
+            imageops::overlay(&mut canvas, &mask, x, y);
+
+            Ok(canvas)
+        }
+    }
+}
+
+/// Rasterize `polygon` into a mask of `dimensions`, white inside of it and black outside
+///
+/// A pixel is inside if its center is, by the even-odd rule. With `outside` the mask is inverted
+pub fn polygon_mask(polygon: &Polygon, (width, height): (u32, u32), outside: bool) -> GrayImage {
+    let Polygon(points) = polygon;
+    let edges = points.iter().zip(points.iter().cycle().skip(1)).collect::<Vec<_>>();
+
+    GrayImage::from_fn(width, height, |x, y| {
+        let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+        let crossings = edges
+            .iter()
+            .filter(|&&(&(x1, y1), &(x2, y2))| {
+                (y1 > py) != (y2 > py) && px < x1 + (py - y1) * (x2 - x1) / (y2 - y1)
+            })
+            .count();
+
+        let inside = crossings % 2 == 1;
+        Luma([if inside != outside { u8::MAX } else { 0 }])
+    })
+}
+
+/// Convert any [`DynamicImage`] into an `RgbImage`
+///
+/// Warns about what gets lost on the way, like the alpha channel or the precision
+/// of 16-bit and floating point channels
+pub fn to_rgb8_lossy(image: DynamicImage) -> RgbImage {
+    let loss = match &image {
+        DynamicImage::ImageRgb8(_) | DynamicImage::ImageLuma8(_) => None,
+        DynamicImage::ImageLumaA8(_) | DynamicImage::ImageRgba8(_) => Some("the alpha channel is dropped"),
+        DynamicImage::ImageLuma16(_) | DynamicImage::ImageRgb16(_) => Some("16-bit channels are reduced to 8 bits"),
+        DynamicImage::ImageLumaA16(_) | DynamicImage::ImageRgba16(_) => {
+            Some("16-bit channels are reduced to 8 bits and the alpha channel is dropped")
+        }
+        DynamicImage::ImageRgb32F(_) => Some("float channels are clamped to 0..=1 and reduced to 8 bits"),
+        DynamicImage::ImageRgba32F(_) => {
+            Some("float channels are clamped to 0..=1 and reduced to 8 bits and the alpha channel is dropped")
+        }
+        _ => Some("the pixels are converted to 8-bit RGB")
+    };
+
+    if let Some(loss) = loss {
+        log::warn!("converting a {:?} image, {}", image.color(), loss);
+    }
+
+    match image {
+        DynamicImage::ImageRgb8(image) => image,
+        other => other.into_rgb8()
+    }
+}
+
+/// Convert a [`DynamicImage`] into an `RgbImage`, returning the original [`ColorType`] with it
+pub fn from_dynamic(image: DynamicImage) -> (RgbImage, ColorType) {
+    let color = image.color();
+
+    (to_rgb8_lossy(image), color)
+}
+
+/// Convert a sorted image back into the [`ColorType`] it was loaded as
+///
+/// Only 8-bit color types are restored, anything else stays as it was sorted since
+/// the sorted pixels are 8-bit anyway
+pub fn into_dynamic<I: Into<DynamicImage>>(image: I, color: ColorType) -> DynamicImage {
+    let image = image.into();
+
+    match color {
+        ColorType::L8 => image.into_luma8().into(),
+        ColorType::La8 => image.into_luma_alpha8().into(),
+        ColorType::Rgb8 => image.into_rgb8().into(),
+        ColorType::Rgba8 => image.into_rgba8().into(),
+        _ => image
+    }
+}
+
+/// Clamp the luma of every pixel into `low..=high`, scaling its channels to match
+///
+/// Pixels already inside the range are left untouched, only the color channels
+/// of RGB(A) images are changed
+pub fn clamp_brightness<P: Pixel<Subpixel = u8>>(image: &mut ImageBuffer<P, Vec<u8>>, low: u8, high: u8) {
+    let Coefficients { red, green, blue } = Coefficients::luma();
+
+    image.pixels_mut().for_each(|pixel| {
+        let color = &mut pixel.channels_mut()[..3];
+        let luma = red * color[0] as f32 + green * color[1] as f32 + blue * color[2] as f32;
+        let clamped = luma.clamp(low as f32, high as f32);
+
+        if clamped == luma {
+            return;
+        }
+
+        if luma == 0.0 {
+            // black has no color to scale, so it becomes gray
+            color.fill(clamped as u8);
+        } else {
+            for channel in color.iter_mut() {
+                *channel = (*channel as f32 * clamped / luma).round().min(u8::MAX as f32) as u8;
+            }
+        }
+    });
+}
+
+/// Cumulative distribution of the values of one channel of `pixels`, normalized into `0.0..=1.0`
+fn channel_cdf<'a>(pixels: impl Iterator<Item = &'a [u8]>, channel: usize) -> [f64; 256] {
+    let mut histogram = [0u64; 256];
+    for pixel in pixels {
+        histogram[pixel[channel] as usize] += 1;
+    }
+
+    let total = histogram.iter().sum::<u64>().max(1) as f64;
+    let mut cdf = [0.0; 256];
+    let mut sum = 0;
+
+    for (value, count) in histogram.iter().enumerate() {
+        sum += count;
+        cdf[value] = sum as f64 / total;
+    }
+
+    cdf
+}
+
+/// Split `histogram` into a dark and a bright class with Otsu's method
+///
+/// Returns the first value of the bright class, where the variance between the classes is largest
+pub fn otsu_threshold(histogram: &[u64; 256]) -> u8 {
+    let total = histogram.iter().sum::<u64>() as f64;
+    let sum = histogram.iter().enumerate().map(|(value, &count)| value as f64 * count as f64).sum::<f64>();
+
+    let (mut dark, mut dark_sum) = (0.0, 0.0);
+    let (mut best_variance, mut best) = (0.0, 0);
+
+    // the last value can't start a bright class, nothing would be left in it
+    for (value, &count) in histogram.iter().enumerate().take(255) {
+        dark += count as f64;
+        dark_sum += value as f64 * count as f64;
+
+        let bright = total - dark;
+        if dark == 0.0 || bright == 0.0 {
+            continue;
+        }
+
+        let mean_difference = dark_sum / dark - (sum - dark_sum) / bright;
+        let variance = dark * bright * mean_difference * mean_difference;
+
+        if variance > best_variance {
+            best_variance = variance;
+            best = value + 1;
+        }
+    }
+
+    best as u8
+}
+
+/// Match the histogram of every color channel of `image` to the one of `reference`
+///
+/// Each value is mapped to the lowest reference value that is at least as far along the
+/// cumulative distribution, the alpha channel is left alone
+pub fn match_histogram<P: Pixel<Subpixel = u8>>(image: &mut ImageBuffer<P, Vec<u8>>, reference: &RgbImage) {
+    let channels = (P::CHANNEL_COUNT as usize).min(3);
+
+    for channel in 0..channels {
+        let source = channel_cdf(image.pixels().map(|pixel| pixel.channels()), channel);
+        let target = channel_cdf(reference.pixels().map(|pixel| pixel.channels()), channel);
+
+        let mut mapping = [0u8; 256];
+        for (value, mapped) in mapping.iter_mut().enumerate() {
+            *mapped = target.iter().position(|&level| level >= source[value]).unwrap_or(255) as u8;
+        }
+
+        for pixel in image.pixels_mut() {
+            let value = &mut pixel.channels_mut()[channel];
+            *value = mapping[*value as usize];
+        }
+    }
+}
+
+/// Hue, saturation and value of an RGB color, each scaled into `0..=255`
+fn rgb_to_hsv([red, green, blue]: [u8; 3]) -> [u8; 3] {
+    let [red, green, blue] = [red, green, blue].map(|channel| channel as f32 / 255.0);
+    let max = red.max(green).max(blue);
+    let min = red.min(green).min(blue);
+    let diff = max - min;
+
+    let hue = if diff == 0.0 {
+        0.0
+    } else if max == red {
+        ((green - blue) / diff).rem_euclid(6.0)
+    } else if max == green {
+        (blue - red) / diff + 2.0
+    } else {
+        (red - green) / diff + 4.0
+    };
+    let saturation = if max == 0.0 { 0.0 } else { diff / max };
+
+    [hue / 6.0, saturation, max].map(|channel| (channel * 255.0).round() as u8)
+}
+
+/// RGB color of a hue, saturation and value that are each scaled into `0..=255`
+fn hsv_to_rgb([hue, saturation, value]: [u8; 3]) -> [u8; 3] {
+    let [hue, saturation, value] = [hue, saturation, value].map(|channel| channel as f32 / 255.0);
+    let hue = (hue * 6.0).rem_euclid(6.0);
+    let chroma = value * saturation;
+    let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+
+    let (red, green, blue) = match hue as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x)
+    };
+    let min = value - chroma;
+
+    [red, green, blue].map(|channel| ((channel + min) * 255.0).round() as u8)
+}
+
+/// Relabel the color channels of every pixel between RGB and HSV
+///
+/// With `to_hsv` the color is converted to HSV, which is stored in the RGB channels.
+/// Otherwise the RGB channels are read as HSV and converted back
+pub fn relabel_hsv<P: Pixel<Subpixel = u8>>(image: &mut ImageBuffer<P, Vec<u8>>, to_hsv: bool) {
+    image.pixels_mut().for_each(|pixel| {
+        let color = &mut pixel.channels_mut()[..3];
+        let triple = [color[0], color[1], color[2]];
+
+        color.copy_from_slice(&if to_hsv { rgb_to_hsv(triple) } else { hsv_to_rgb(triple) });
+    });
+}
+
+/// Rotate the image clockwise by `degrees` around its center, with bilinear filtering
+///
+/// The canvas is `size` large, or just large enough to hold the whole rotated image
+/// if not given. Corners that the image doesn't cover are filled with `fill`, alpha is opaque there
+pub fn rotate<P: Pixel<Subpixel = u8>>(
+    image: &ImageBuffer<P, Vec<u8>>,
+    degrees: f32,
+    fill: Rgb<u8>,
+    size: Option<(u32, u32)>
+) -> ImageBuffer<P, Vec<u8>> {
+    let (width, height) = image.dimensions();
+    let (sin, cos) = (degrees as f64).to_radians().sin_cos();
+
+    let (canvas_width, canvas_height) = size.unwrap_or_else(|| {
+        let canvas_width = width as f64 * cos.abs() + height as f64 * sin.abs();
+        let canvas_height = width as f64 * sin.abs() + height as f64 * cos.abs();
+        ((canvas_width.round() as u32).max(1), (canvas_height.round() as u32).max(1))
+    });
+
+    let channels = P::CHANNEL_COUNT as usize;
+    let mut background = [u8::MAX; 4];
+    background[..3].copy_from_slice(&fill.0);
+
+    let texel = |x: i64, y: i64| -> &[u8] {
+        if x < 0 || y < 0 || x >= width as i64 || y >= height as i64 {
+            &background[..channels]
+        } else {
+            image.get_pixel(x as u32, y as u32).channels()
+        }
+    };
+
+    let (center_x, center_y) = (width as f64 / 2.0, height as f64 / 2.0);
+    let (canvas_center_x, canvas_center_y) = (canvas_width as f64 / 2.0, canvas_height as f64 / 2.0);
+
+    ImageBuffer::from_fn(canvas_width, canvas_height, |x, y| {
+        // rotate the center of the canvas pixel back onto the image
+        let dx = x as f64 + 0.5 - canvas_center_x;
+        let dy = y as f64 + 0.5 - canvas_center_y;
+        let source_x = dx * cos + dy * sin + center_x - 0.5;
+        let source_y = -dx * sin + dy * cos + center_y - 0.5;
+
+        let (left, top) = (source_x.floor(), source_y.floor());
+        let (fraction_x, fraction_y) = (source_x - left, source_y - top);
+        let (left, top) = (left as i64, top as i64);
+
+        let mut pixel = [0u8; 4];
+        for (channel, value) in pixel.iter_mut().enumerate().take(channels) {
+            let sample = |x: i64, y: i64| texel(x, y)[channel] as f64;
+            let upper = sample(left, top) * (1.0 - fraction_x) + sample(left + 1, top) * fraction_x;
+            let lower = sample(left, top + 1) * (1.0 - fraction_x) + sample(left + 1, top + 1) * fraction_x;
+
+            *value = (upper * (1.0 - fraction_y) + lower * fraction_y).round() as u8;
+        }
+
+        *P::from_slice(&pixel[..channels])
+    })
+}
+
+/// Upscale the image by an integer `factor` with nearest-neighbor filtering to keep hard edges
+///
+/// Fails if the upscaled image would be wider or taller than a `u32` can hold
+pub fn upscale<P: Pixel<Subpixel = u8> + 'static>(
+    image: &ImageBuffer<P, Vec<u8>>,
+    factor: u32
+) -> Result<ImageBuffer<P, Vec<u8>>, PxsortError> {
+    let (width, height) = image.dimensions();
+    let too_large = || PxsortError::ScaleOverflow { width, height, factor };
+
+    let scaled_width = width.checked_mul(factor).ok_or_else(too_large)?;
+    let scaled_height = height.checked_mul(factor).ok_or_else(too_large)?;
+
+    Ok(imageops::resize(image, scaled_width, scaled_height, FilterType::Nearest))
+}
+
+/// Downscale the image so that its larger side is at most `max` pixels, keeping the aspect ratio
+///
+/// Images that already fit are returned as they are
+pub fn downscale<P: Pixel<Subpixel = u8> + 'static>(
+    image: &ImageBuffer<P, Vec<u8>>,
+    max: u32,
+    filter: FilterType
+) -> ImageBuffer<P, Vec<u8>> {
+    let (width, height) = image.dimensions();
+
+    if width.max(height) <= max {
+        return image.clone();
+    }
+
+    let scale = max as f64 / width.max(height) as f64;
+    let width = ((width as f64 * scale).round() as u32).max(1);
+    let height = ((height as f64 * scale).round() as u32).max(1);
+
+    imageops::resize(image, width, height, filter)
+}
+
+/// Apply a median filter with a `2 * radius + 1` wide square window to every channel
+///
+/// Windows are clamped at the image edges, a radius of zero leaves the image as it was
+pub fn median_filter<P: Pixel<Subpixel = u8>>(image: &ImageBuffer<P, Vec<u8>>, radius: u32) -> ImageBuffer<P, Vec<u8>> {
+    if radius == 0 {
+        return image.clone();
+    }
+
+    let (width, height) = image.dimensions();
+    let mut window = Vec::with_capacity(((2 * radius + 1) * (2 * radius + 1)) as usize);
+
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let mut pixel = *image.get_pixel(x, y);
+
+        for channel in 0..P::CHANNEL_COUNT as usize {
+            window.clear();
+
+            for window_y in y.saturating_sub(radius)..=(y + radius).min(height - 1) {
+                for window_x in x.saturating_sub(radius)..=(x + radius).min(width - 1) {
+                    window.push(image.get_pixel(window_x, window_y).channels()[channel]);
+                }
+            }
+
+            let middle = window.len() / 2;
+            pixel.channels_mut()[channel] = *window.select_nth_unstable(middle).1;
+        }
+
+        pixel
+    })
+}
+
+/// The unique colors of an image, in a stable order
+pub fn palette_from_image(image: &RgbImage) -> Vec<Rgb<u8>> {
+    let mut colors = image
+        .pixels()
+        .map(|pixel| pixel.0)
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+
+    colors.sort_unstable();
+    colors.into_iter().map(Rgb).collect()
+}
+
+/// Replace the color of every pixel with the nearest color of the palette
+///
+/// Only the color channels of RGB(A) images are changed
+pub fn quantize_to_palette<P: Pixel<Subpixel = u8>>(image: &mut ImageBuffer<P, Vec<u8>>, palette: &[Rgb<u8>]) {
+    image.pixels_mut().for_each(|pixel| {
+        let color = &mut pixel.channels_mut()[..3];
+
+        let nearest = palette.iter().min_by_key(|Rgb(candidate)| {
+            candidate
+                .iter()
+                .zip(color.iter())
+                .map(|(&a, &b)| (a as i32 - b as i32).pow(2))
+                .sum::<i32>()
+        });
+
+        if let Some(Rgb(nearest)) = nearest {
+            color.copy_from_slice(nearest);
+        }
+    });
+}
+
+/// Linearly interpolate every channel of `sorted` towards `original` by `amount`
+///
+/// An `amount` of 0.0 gives back the original, 1.0 leaves the sorted image as it is
+pub fn blend<P: Pixel<Subpixel = u8>>(original: &ImageBuffer<P, Vec<u8>>, sorted: &mut ImageBuffer<P, Vec<u8>>, amount: f32) {
+    sorted.pixels_mut().zip(original.pixels()).for_each(|(sorted, original)| {
+        sorted.apply2(original, |sorted, original| {
+            (original as f32 + (sorted as f32 - original as f32) * amount).round() as u8
+        });
+    });
+}
+
+/// Paint the pixels at `points` in `color`, leaving the alpha channel as it is
+pub fn mark_pixels<P: Pixel<Subpixel = u8>>(image: &mut ImageBuffer<P, Vec<u8>>, points: &[(u32, u32)], color: Rgb<u8>) {
+    for &(x, y) in points {
+        image.get_pixel_mut(x, y).channels_mut()[..3].copy_from_slice(&color.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    /// An image with an uneven histogram in every channel
+    fn gradient() -> RgbImage {
+        RgbImage::from_fn(64, 48, |x, y| Rgb([(x * 4) as u8, (x * y % 251) as u8, ((x + y) * (x + y) / 30) as u8]))
+    }
+
+    /// Big endian EXIF metadata holding nothing but the `orientation` tag
+    fn exif_with_orientation(orientation: u8) -> Vec<u8> {
+        let mut exif = b"MM\0\x2a\0\0\0\x08".to_vec();
+        exif.extend([0, 1]);
+        exif.extend([0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, orientation, 0, 0]);
+        exif.extend([0, 0, 0, 0]);
+        exif
+    }
+
+    #[test]
+    fn matching_an_image_to_itself_keeps_it() {
+        let original = gradient();
+        let mut matched = original.clone();
+
+        match_histogram(&mut matched, &original);
+
+        assert_eq!(matched, original);
+    }
+
+    #[test]
+    fn matching_an_rgba_image_to_its_colors_keeps_it() {
+        let reference = gradient();
+        let original = RgbaImage::from_fn(64, 48, |x, y| {
+            let Rgb([red, green, blue]) = *reference.get_pixel(x, y);
+            Rgba([red, green, blue, (x + y) as u8])
+        });
+        let mut matched = original.clone();
+
+        match_histogram(&mut matched, &reference);
+
+        assert_eq!(matched, original);
+    }
+    #[test]
+    fn upscaling_by_1_keeps_the_image() {
+        let image = gradient();
+
+        assert_eq!(upscale(&image, 1).unwrap(), image);
+    }
+
+    #[test]
+    fn upscaling_by_2_doubles_both_dimensions() {
+        let image = gradient();
+        let scaled = upscale(&image, 2).unwrap();
+
+        assert_eq!(scaled.dimensions(), (128, 96));
+        assert_eq!(scaled.get_pixel(9, 7), image.get_pixel(4, 3));
+    }
+
+    #[test]
+    fn upscaling_past_the_largest_dimension_fails() {
+        // an empty image, so the test doesn't need gigabytes for its pixels
+        let image = RgbImage::new(1 << 31, 0);
+
+        assert!(matches!(upscale(&image, 2), Err(PxsortError::ScaleOverflow { width: 2147483648, height: 0, factor: 2 })));
+    }
+    #[test]
+    fn a_failed_write_keeps_the_original_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.png");
+        std::fs::write(&path, "original").unwrap();
+
+        let written = write_atomically(&path, |writer| {
+            writer.write_all(b"half of a new file").unwrap();
+            Err::<(), _>(PxsortError::Io { path: path.clone(), source: std::io::ErrorKind::WriteZero.into() })
+        });
+
+        assert!(matches!(written, Err(PxsortError::Io { .. })));
+        assert_eq!(std::fs::read(&path).unwrap(), b"original");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1, "the temporary file was left behind");
+    }
+    #[test]
+    fn images_over_the_pixel_limit_are_too_large() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photo.png");
+        gradient().save(&path).unwrap();
+
+        assert!(matches!(
+            load_image_limited(&path, 64 * 48 - 1),
+            Err(PxsortError::TooLarge { width: 64, height: 48, max_pixels: 3071, .. })
+        ));
+        assert!(load_image_limited(&path, 64 * 48).is_ok());
+    }
+
+    #[test]
+    fn truncated_pngs_load_leniently_at_their_declared_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("truncated.png");
+
+        // noise barely compresses, so the rows in the first half of the file decode
+        let mut state = 1u32;
+        let original = RgbImage::from_fn(32, 32, |_, _| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            Rgb([(state >> 24) as u8, (state >> 16) as u8, (state >> 8) as u8])
+        });
+        original.save(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+
+        assert!(matches!(load_image_limited(&path, DEFAULT_MAX_PIXELS), Err(PxsortError::Decode(_))));
+
+        let image = load_image_lenient(&path, DEFAULT_MAX_PIXELS).unwrap().into_rgb8();
+        assert_eq!(image.dimensions(), (32, 32));
+        assert_eq!(image.get_pixel(5, 0), original.get_pixel(5, 0));
+        assert_eq!(image.get_pixel(5, 31), &Rgb([0, 0, 0]));
+    }
+    #[test]
+    fn a_tiled_white_pixel_masks_the_whole_image_in() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mask.png");
+        GrayImage::from_pixel(1, 1, Luma([255])).save(&path).unwrap();
+
+        let image = gradient();
+        let mask = load_mask(&path, image.dimensions(), Some(MaskMode::Tile)).unwrap();
+        assert_eq!(mask, GrayImage::from_pixel(64, 48, Luma([255])));
+
+        let options = crate::sort::test_options(&["luma", "-i", "16", "-d", "16", "--seed", "1"]);
+        let sort = |mask: Option<GrayImage>| {
+            let mut options = options.clone();
+            options.mask = mask;
+
+            let mut sorted = image.clone();
+            crate::sort::rgb8_pixel_sort(&mut sorted, options, None);
+            sorted
+        };
+
+        let masked = sort(Some(mask));
+        assert_eq!(masked, sort(None));
+        assert_ne!(masked, image);
+    }
+    #[test]
+    fn only_the_inside_of_a_triangle_gets_sorted() {
+        let image = gradient();
+        let triangle = Polygon(vec![(0.0, 0.0), (64.0, 0.0), (0.0, 48.0)]);
+        let mask = polygon_mask(&triangle, image.dimensions(), false);
+
+        assert_eq!(mask.get_pixel(1, 1).0, [255]);
+        assert_eq!(mask.get_pixel(62, 46).0, [0]);
+
+        // spans that tile every row exactly, so no pixel is read into two of them
+        let mut options = crate::sort::test_options(&["luma", "-i", "16", "-d", "16", "-p", "1000", "--seed", "1"]);
+        options.mask = Some(mask.clone());
+        let mut sorted = image.clone();
+        crate::sort::rgb8_pixel_sort(&mut sorted, options, None);
+
+        let changed = |x, y| sorted.get_pixel(x, y) != image.get_pixel(x, y);
+        assert!(mask.enumerate_pixels().all(|(x, y, Luma([inside]))| *inside == 255 || !changed(x, y)));
+        assert!(mask.enumerate_pixels().any(|(x, y, Luma([inside]))| *inside == 255 && changed(x, y)));
+    }
+    #[test]
+    fn the_otsu_threshold_of_a_bimodal_image_falls_between_its_modes() {
+        // a dark half spread around 40 and a bright one around 200
+        let image = GrayImage::from_fn(40, 20, |x, y| Luma([if x < 20 { 30 + (x + y) as u8 % 21 } else { 190 + (x * y) as u8 % 21 }]));
+
+        let mut histogram = [0u64; 256];
+        image.pixels().for_each(|Luma([value])| histogram[*value as usize] += 1);
+        let threshold = otsu_threshold(&histogram);
+
+        assert!(image.enumerate_pixels().all(|(x, _, Luma([value]))| (*value >= threshold) == (x >= 20)), "{}", threshold);
+        assert!((51..=190).contains(&threshold));
+    }
+    #[test]
+    fn frames_of_a_gif_are_picked_by_their_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("two.gif");
+        let frames = [RgbImage::from_pixel(4, 3, Rgb([250, 10, 10])), RgbImage::from_pixel(4, 3, Rgb([10, 10, 250]))];
+        crate::animate::write_gif(&frames, &path).unwrap();
+
+        let second = load_frame(&path, 1, DEFAULT_MAX_PIXELS).unwrap().into_rgb8();
+        assert_eq!(second, frames[1]);
+        assert_eq!(load_frame(&path, 0, DEFAULT_MAX_PIXELS).unwrap().into_rgb8(), frames[0]);
+
+        assert!(matches!(
+            load_frame(&path, 2, DEFAULT_MAX_PIXELS),
+            Err(PxsortError::FrameOutOfRange { index: 2, frames: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn clamping_to_the_full_range_keeps_the_image() {
+        let original = gradient();
+        let mut clamped = original.clone();
+
+        clamp_brightness(&mut clamped, 0, 255);
+
+        assert_eq!(clamped, original);
+    }
+
+    #[test]
+    fn sorted_luma8_images_save_back_as_luma8() {
+        let gray = GrayImage::from_fn(16, 4, |x, y| Luma([(255 - x * 15 - y) as u8]));
+        let (mut image, color) = from_dynamic(DynamicImage::ImageLuma8(gray.clone()));
+        crate::sort::rgb8_pixel_sort(&mut image, crate::sort::test_options(&["luma", "-i", "16", "-d", "16", "-p", "1000"]), None);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sorted.png");
+        save_image(&into_dynamic(image, color), &path, false, None).unwrap();
+
+        let saved = load_image(&path).unwrap();
+        assert_eq!(saved.color(), ColorType::L8);
+        assert_ne!(saved.as_luma8(), Some(&gray));
+        for row in saved.as_luma8().unwrap().rows() {
+            let values = row.map(|pixel| pixel.0[0]).collect::<Vec<_>>();
+            assert!(values.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", values);
+        }
+    }
+
+    #[test]
+    fn denoising_with_radius_0_keeps_the_image() {
+        let image = gradient();
+
+        assert_eq!(median_filter(&image, 0), image);
+        assert_ne!(median_filter(&image, 1), image);
+    }
+
+    #[test]
+    fn every_image_variant_converts_to_rgb8() {
+        let color = DynamicImage::ImageRgb8(gradient());
+        let gray = DynamicImage::ImageLuma8(color.to_luma8());
+
+        let colors = [
+            color.clone(),
+            color.to_rgba8().into(),
+            color.to_rgb16().into(),
+            color.to_rgba16().into(),
+            color.to_rgb32f().into(),
+            color.to_rgba32f().into()
+        ];
+        for image in colors {
+            let variant = image.color();
+            assert_eq!(to_rgb8_lossy(image), gradient(), "{:?}", variant);
+        }
+
+        let grays = [gray.clone(), gray.to_luma_alpha8().into(), gray.to_luma16().into(), gray.to_luma_alpha16().into()];
+        for image in grays {
+            let variant = image.color();
+            assert_eq!(to_rgb8_lossy(image), gray.to_rgb8(), "{:?}", variant);
+        }
+    }
+
+    #[test]
+    fn blending_by_0_keeps_the_original_and_by_1_the_sort() {
+        let original = gradient();
+        let mut sorted = original.clone();
+        crate::sort::rgb8_pixel_sort(&mut sorted, crate::sort::test_options(&["hue", "--seed", "2"]), None);
+        assert_ne!(sorted, original);
+
+        let mut blended = sorted.clone();
+        blend(&original, &mut blended, 0.0);
+        assert_eq!(blended, original);
+
+        let mut blended = sorted.clone();
+        blend(&original, &mut blended, 1.0);
+        assert_eq!(blended, sorted);
+    }
+
+    #[test]
+    fn images_with_a_rotation_tag_get_rotated() {
+        let original = RgbImage::from_fn(3, 2, |x, y| Rgb([(x * 80) as u8, (y * 200) as u8, 30]));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photo.png");
+        save_image(&DynamicImage::ImageRgb8(original.clone()), &path, false, Some(&exif_with_orientation(6))).unwrap();
+
+        let oriented = orient_by_exif(image::open(&path).unwrap(), &path).into_rgb8();
+        assert_eq!(oriented, imageops::rotate90(&original));
+    }
+
+    #[test]
+    fn pngs_saved_with_embed_srgb_have_an_srgb_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let srgb = |embed_srgb: bool| {
+            let path = dir.path().join(format!("{}.png", embed_srgb));
+            save_image(&DynamicImage::ImageRgb8(gradient()), &path, embed_srgb, None).unwrap();
+
+            let decoder = png::Decoder::new(BufReader::new(File::open(&path).unwrap()));
+            decoder.read_info().unwrap().info().srgb
+        };
+
+        assert_eq!(srgb(true), Some(png::SrgbRenderingIntent::Perceptual));
+        assert_eq!(srgb(false), None);
+    }
+
+    #[test]
+    fn kept_metadata_survives_a_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let (input, output) = (dir.path().join("in.png"), dir.path().join("out.png"));
+        let image = DynamicImage::ImageRgb8(gradient());
+        save_image(&image, &input, false, Some(&exif_with_orientation(3))).unwrap();
+
+        let exif = read_exif(&input).unwrap();
+        save_image(&image, &output, false, Some(&exif)).unwrap();
+
+        assert_eq!(exif_orientation(&output), Some(3));
+        assert_eq!(read_exif(&output), Some(exif));
+    }
+
+    #[test]
+    fn rotating_by_0_or_360_degrees_keeps_the_image() {
+        let original = gradient();
+
+        for degrees in [0.0, 360.0] {
+            let rotated = rotate(&original, degrees, Rgb([255, 0, 0]), None);
+
+            assert_eq!(rotated.dimensions(), original.dimensions(), "{} degrees", degrees);
+            for (rotated, original) in rotated.pixels().zip(original.pixels()) {
+                assert!(rotated.0.iter().zip(original.0).all(|(a, b)| a.abs_diff(b) <= 1), "{} degrees", degrees);
+            }
+        }
+    }
 }
@@ -1,10 +1,16 @@
 mod cli;
-mod animate;
+pub mod animate;
+mod cluster;
 mod edge;
+mod error;
+#[cfg(feature = "gpu")]
+pub mod gpu;
 mod img;
 pub mod extractor;
 pub mod sort;
+pub mod stream;
 
 pub use cli::*;
-pub use img::load_image;
+pub use error::PxsortError;
+pub use img::{DEFAULT_MAX_PIXELS, load_image, load_image_limited, load_image_lenient, load_image_oriented, load_frame, load_frames, orient_by_exif, load_mask, polygon_mask, read_exif, save_image, to_rgb8_lossy, from_dynamic, into_dynamic, clamp_brightness, match_histogram, relabel_hsv, rotate, upscale, downscale, median_filter, quantize_to_palette, blend, mark_pixels};
 pub use sort::PixelSort;
@@ -1,18 +1,1379 @@
-use anyhow::anyhow;
-use clap::Parser;
-use pxsort::{Cli, load_image, PixelSort};
+use std::{
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant}
+};
 
-fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
+use anyhow::{bail, Context};
+use clap::ValueEnum;
+use image::{imageops::{self, FilterType}, ColorType, DynamicImage, Frame, GrayImage, ImageBuffer, ImageFormat, Pixel, RgbImage};
+use pxsort::{
+    animate::{animate_frames, is_video, pad_frames, write_frames, write_gif, write_gif_frames, write_video},
+    extractor::update_pixel,
+    sort::{rgb8_key_map, shuffle_lines, span_boundaries, SortOptions, SortReport},
+    stream::stream_sort,
+    AnimateParams, Cli, Flip, FlipAt, MaskMode, PreviewQuality, Reinterpret, SortingAlgorithm, WalkPath, load_image, load_image_limited, load_image_lenient, load_frame, load_frames, orient_by_exif, load_mask, polygon_mask, read_exif, save_image, from_dynamic, into_dynamic, clamp_brightness, match_histogram, relabel_hsv, rotate, upscale, downscale, median_filter, quantize_to_palette, blend, mark_pixels, PixelSort, PxsortError
+};
+
+/// Seed of --self-test if none is given
+const SELF_TEST_SEED: u64 = 0x5eed;
+
+/// Columns of --term-preview if the width of the terminal is unknown
+const TERM_PREVIEW_COLUMNS: u32 = 80;
+
+/// Flip the image in place along the axes of `flip`
+fn flip<P: Pixel<Subpixel = u8>>(image: &mut ImageBuffer<P, Vec<u8>>, flip: Flip) {
+    if matches!(flip, Flip::H | Flip::Both) {
+        imageops::flip_horizontal_in_place(image);
+    }
+
+    if matches!(flip, Flip::V | Flip::Both) {
+        imageops::flip_vertical_in_place(image);
+    }
+}
+
+/// Raise every color channel of the image, scaled to `0.0..=1.0`, to the power of `gamma`
+///
+/// The alpha channel is left as it is
+fn apply_gamma<P: Pixel<Subpixel = u8>>(image: &mut ImageBuffer<P, Vec<u8>>, gamma: f32) {
+    let table: [u8; 256] =
+        std::array::from_fn(|value| ((value as f32 / 255.0).powf(gamma) * 255.0).round() as u8);
+
+    for pixel in image.pixels_mut() {
+        for channel in pixel.channels_mut().iter_mut().take(3) {
+            *channel = table[*channel as usize];
+        }
+    }
+}
+
+/// Apply the transformations that run on the image before sorting, in order
+///
+/// `reference` is the decoded --match-histogram image
+fn preprocess<P: Pixel<Subpixel = u8>>(
+    image: &mut ImageBuffer<P, Vec<u8>>,
+    cli: &Cli,
+    reference: Option<&RgbImage>
+) -> anyhow::Result<()> {
+    if let (Some(axes), FlipAt::Pre) = (cli.flip, cli.flip_at) {
+        flip(image, axes);
+    }
+
+    if let Some(reference) = reference {
+        match_histogram(image, reference);
+    }
+
+    if let Some(degrees) = cli.rotate {
+        *image = rotate(image, degrees, cli.rotate_fill, None);
+    }
+
+    if cli.denoise > 0 {
+        *image = median_filter(image, cli.denoise);
+    }
+
+    if let Some(gamma) = cli.gamma {
+        apply_gamma(image, gamma);
+    }
+
+    if cli.invert_pre {
+        imageops::invert(image);
+    }
+
+    if let Some(clamp) = cli.clamp_brightness {
+        clamp_brightness(image, clamp.low, clamp.high);
+    }
+
+    match cli.reinterpret {
+        Reinterpret::None => {}
+        Reinterpret::RgbAsHsv => relabel_hsv(image, false),
+        Reinterpret::HsvAsRgb => relabel_hsv(image, true)
+    }
+
+    Ok(())
+}
+
+/// Rotate the sorted image back by --rotate onto a canvas of the `dimensions` it had before, if asked to
+fn rotate_back<P: Pixel<Subpixel = u8>>(
+    image: ImageBuffer<P, Vec<u8>>,
+    cli: &Cli,
+    dimensions: (u32, u32)
+) -> ImageBuffer<P, Vec<u8>> {
+    match cli.rotate {
+        Some(degrees) if cli.rotate_back => rotate(&image, -degrees, cli.rotate_fill, Some(dimensions)),
+        _ => image
+    }
+}
+
+/// Apply the transformations that run on the sorted image, in order
+fn postprocess<P: Pixel<Subpixel = u8> + 'static>(
+    mut image: ImageBuffer<P, Vec<u8>>,
+    cli: &Cli
+) -> anyhow::Result<ImageBuffer<P, Vec<u8>>> {
+    // undo the relabeling of preprocess
+    match cli.reinterpret {
+        Reinterpret::None => {}
+        Reinterpret::RgbAsHsv => relabel_hsv(&mut image, true),
+        Reinterpret::HsvAsRgb => relabel_hsv(&mut image, false)
+    }
+
+    if let (Some(gamma), true) = (cli.gamma, cli.gamma_restore) {
+        apply_gamma(&mut image, 1.0 / gamma);
+    }
+
+    if cli.invert_post {
+        imageops::invert(&mut image);
+    }
+
+    if let (Some(axes), FlipAt::Post) = (cli.flip, cli.flip_at) {
+        flip(&mut image, axes);
+    }
+
+    if let Some(palette) = &cli.palette {
+        quantize_to_palette(&mut image, &palette.colors);
+    }
+
+    let scale = cli.chunky.unwrap_or(cli.output_scale);
+    if scale > 1 {
+        image = upscale(&image, scale)?;
+    }
+
+    Ok(image)
+}
+
+/// Fail if `path` already exists under --no-clobber
+fn check_clobber(cli: &Cli, path: &Path) -> anyhow::Result<()> {
+    if cli.no_clobber && path.exists() {
+        return Err(PxsortError::AlreadyExists { path: path.to_path_buf() }.into());
+    }
+
+    Ok(())
+}
+
+/// Shrink the image to fit --limit, before sorting for fast previews and after it for good ones
+///
+/// --chunky shrinks it by its factor before sorting, postprocess scales it back up
+fn limit<P: Pixel<Subpixel = u8> + 'static>(
+    image: ImageBuffer<P, Vec<u8>>,
+    cli: &Cli,
+    sorted: bool
+) -> ImageBuffer<P, Vec<u8>> {
+    if let (Some(factor), false) = (cli.chunky, sorted) {
+        let (width, height) = image.dimensions();
+        return downscale(&image, (width.max(height) / factor).max(1), FilterType::Nearest);
+    }
+
+    match (cli.limit, cli.preview_quality, sorted) {
+        (Some(max), PreviewQuality::Fast, false) => downscale(&image, max, FilterType::Nearest),
+        (Some(max), PreviewQuality::Good, true) => downscale(&image, max, FilterType::Lanczos3),
+        _ => image
+    }
+}
+
+/// Build the sort options for an image of `dimensions`, loading the mask if one was given
+fn sort_options(cli: &Cli, dimensions: (u32, u32)) -> anyhow::Result<SortOptions> {
+    let mut options: SortOptions = cli.into();
+
+    // every pass and the span overlay have to build the same spans
+    options.seed.get_or_insert_with(rand::random);
+    log::debug!("sorting with {:?}", options);
+
+    if let Some(path) = &cli.image_mask {
+        options.mask = Some(load_mask(path, dimensions, cli.mask_mode)?);
+        log::info!("loaded the mask '{}'", path.display());
+    }
+
+    if let Some(polygon) = &cli.polygon {
+        options.mask = Some(polygon_mask(polygon, dimensions, cli.polygon_outside));
+    }
+
+    Ok(options)
+}
+
+/// Sort `image` once, or again and again until it stops changing if `until_stable` is given
+///
+/// Returns the report of the last sort
+fn sort_pass<P>(image: &mut ImageBuffer<P, Vec<u8>>, options: &SortOptions, until_stable: Option<u32>) -> SortReport
+where
+    P: Pixel<Subpixel = u8>,
+    ImageBuffer<P, Vec<u8>>: PixelSort
+{
+    match until_stable {
+        Some(max) => {
+            let mut stable = false;
+            let mut report = SortReport::default();
+
+            for _ in 0..max {
+                let previous = image.clone();
+                report = image.sort_rgb8_pixels_reported(options.clone());
+
+                if image.as_raw() == previous.as_raw() {
+                    stable = true;
+                    break;
+                }
+            }
+
+            if !stable {
+                log::warn!("the image was still changing after {} sorts", max);
+            }
+
+            report
+        }
+        None => image.sort_rgb8_pixels_reported(options.clone())
+    }
+}
+
+/// Print the statistics of a sort for --stats
+fn print_report(pass: &str, report: &SortReport) {
+    println!("{}:", pass);
+    println!("  spans:           {}", report.spans_total);
+    println!("  mean span len:   {:.2}", report.mean_span_len);
+    println!("  pixels moved:    {}", report.pixels_moved);
+    println!("  elapsed:         {:?}", report.elapsed);
+}
+
+/// Whether images saved to `path` can have an alpha channel, for --alpha-from-mask
+fn supports_alpha(path: &Path) -> bool {
+    matches!(
+        ImageFormat::from_path(path),
+        Ok(ImageFormat::Png | ImageFormat::WebP | ImageFormat::Tiff | ImageFormat::Tga)
+    )
+}
+
+/// Turn `image` into RGBA with the --image-mask as alpha, fitted onto the final size of the image
+fn alpha_from_mask(image: DynamicImage, cli: &Cli) -> anyhow::Result<DynamicImage> {
+    let path = cli.image_mask.as_ref().expect("clap requires --image-mask for --alpha-from-mask");
+    let mut image = image.into_rgba8();
+    let mask = load_mask(path, image.dimensions(), Some(cli.mask_mode.unwrap_or(MaskMode::Stretch)))?;
+
+    for (pixel, alpha) in image.pixels_mut().zip(mask.pixels()) {
+        pixel.0[3] = alpha.0[0];
+    }
+
+    Ok(image.into())
+}
+
+/// Render `image` at most `columns` characters wide for --term-preview
+///
+/// Every character is an upper half block colored with the pixel above as foreground and the one
+/// below as background, so every line shows two rows of pixels
+fn terminal_preview(image: &RgbImage, columns: u32) -> String {
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return String::new();
+    }
+
+    let columns = columns.clamp(1, width);
+    let rows = ((height as u64 * columns as u64 / width as u64) as u32).max(1);
+    let small = imageops::resize(image, columns, rows + rows % 2, FilterType::Triangle);
+
+    let mut preview = String::new();
+    for y in (0..small.height()).step_by(2) {
+        for x in 0..columns {
+            let [top_red, top_green, top_blue] = small.get_pixel(x, y).0;
+            let [red, green, blue] = small.get_pixel(x, y + 1).0;
+            preview.push_str(&format!(
+                "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m\u{2580}",
+                top_red, top_green, top_blue, red, green, blue
+            ));
+        }
+
+        preview.push_str("\x1b[0m\n");
+    }
+
+    preview
+}
+
+/// Sort a single image and save it as the color type it was loaded as
+fn sort_and_save<P>(
+    mut image: ImageBuffer<P, Vec<u8>>,
+    color: ColorType,
+    cli: &Cli,
+    reference: Option<&RgbImage>,
+    input: &Path,
+    output: &Path
+) -> anyhow::Result<()>
+where
+    P: Pixel<Subpixel = u8> + 'static,
+    ImageBuffer<P, Vec<u8>>: PixelSort + Into<DynamicImage>
+{
+    let started = Instant::now();
+    image = limit(image, cli, false);
+    let dimensions = image.dimensions();
+    preprocess(&mut image, cli, reference)?;
+    log::debug!("preprocessed in {:?}", started.elapsed());
+
+    let options = sort_options(cli, image.dimensions())?;
+    let original = cli.blend.map(|_| image.clone());
+
+    if cli.row_shuffle {
+        shuffle_lines(&mut image, cli.direction, options.seed);
+    }
+
+    if let Some(path) = &cli.write_key_map {
+        check_clobber(cli, path)?;
+        let dynamic: DynamicImage = image.clone().into();
+        save_image(&DynamicImage::ImageLuma8(rgb8_key_map(&dynamic.into_rgb8(), &options)), path, false, None)?;
+    }
+
+    let boundaries = cli.debug_spans.then(|| span_boundaries(&image, &options));
+
+    let report = sort_pass(&mut image, &options, cli.until_stable);
+    log::debug!("sorted in {:?}", report.elapsed);
+
+    if cli.stats {
+        print_report("sort", &report);
+    }
+
+    if cli.cross {
+        let mut cross_options = options.clone();
+        cross_options.mask = options.mask.as_ref().map(imageops::rotate90);
+
+        if let Some(interval) = &cli.cross_interval {
+            cross_options.interval = interval.clone();
+        }
+
+        let mut rotated = imageops::rotate90(&image);
+        let report = sort_pass(&mut rotated, &cross_options, cli.until_stable);
+        log::debug!("cross sorted in {:?}", report.elapsed);
+
+        if cli.stats {
+            print_report("cross sort", &report);
+        }
+
+        image = imageops::rotate270(&rotated);
+    }
+
+    if let (Some(original), Some(amount)) = (&original, cli.blend) {
+        blend(original, &mut image, amount);
+    }
+
+    if let Some(boundaries) = &boundaries {
+        mark_pixels(&mut image, boundaries, cli.debug_spans_color);
+    }
+
+    let started = Instant::now();
+    let image = rotate_back(image, cli, dimensions);
+    let image = postprocess(limit(image, cli, true), cli)?;
+
+    let mut image = into_dynamic(image, color);
+    if cli.alpha_from_mask {
+        image = alpha_from_mask(image, cli)?;
+    }
+    log::debug!("postprocessed in {:?}", started.elapsed());
+
+    if cli.term_preview {
+        let columns = terminal_size::terminal_size().map_or(TERM_PREVIEW_COLUMNS, |(width, _)| width.0 as u32);
+        print!("{}", terminal_preview(&image.to_rgb8(), columns));
+    }
+
+    let started = Instant::now();
+    let exif = if cli.keep_metadata { read_exif(input) } else { None };
+    save_image(&image, output, cli.embed_srgb, exif.as_deref())?;
+    log::debug!("saved '{}' in {:?}", output.display(), started.elapsed());
+
+    Ok(())
+}
+
+/// The keys of the preprocessed `image` for --show-key, with the options the sort would use
+fn key_image(image: DynamicImage, cli: &Cli, reference: Option<&RgbImage>) -> anyhow::Result<GrayImage> {
+    let (mut rgb8_image, _) = from_dynamic(image);
+    preprocess(&mut rgb8_image, cli, reference)?;
+
+    let options = sort_options(cli, rgb8_image.dimensions())?;
+    Ok(rgb8_key_map(&rgb8_image, &options))
+}
+
+/// Sort one frame for every animated value and save them as a GIF, or as a video for the video extensions
+///
+/// `image` is the pristine copy that every frame is cloned from,
+/// single images are sorted in place and never cloned
+fn animate_and_save(
+    image: RgbImage,
+    params: &[AnimateParams],
+    cli: &Cli,
+    reference: Option<&RgbImage>,
+    input: &Path,
+    output: &Path
+) -> anyhow::Result<()> {
+    let mut image = limit(image, cli, false);
+    let dimensions = image.dimensions();
+    preprocess(&mut image, cli, reference)?;
+
+    let options = sort_options(cli, image.dimensions())?;
+
+    if cli.row_shuffle {
+        shuffle_lines(&mut image, cli.direction, options.seed);
+    }
+
+    if let Some(path) = &cli.write_key_map {
+        check_clobber(cli, path)?;
+        save_image(&DynamicImage::ImageLuma8(rgb8_key_map(&image, &options)), path, false, None)?;
+    }
+
+    let started = Instant::now();
+    let frames = animate_frames(&image, &options, params)?
+        .into_iter()
+        .map(|mut frame| {
+            if let Some(amount) = cli.blend {
+                blend(&image, &mut frame, amount);
+            }
+
+            postprocess(limit(rotate_back(frame, cli, dimensions), cli, true), cli)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let frames = pad_frames(frames, cli.pad_color);
+    log::debug!("sorted {} frames in {:?}", frames.len(), started.elapsed());
+
+    if let Some(dir) = &cli.frames_dir {
+        write_frames(&frames, dir, &cli.output_template, input, params, cli.no_clobber)?;
+    }
+
+    if is_video(output) {
+        write_video(&frames, output)?;
+    } else {
+        write_gif(&frames, output)?;
+    }
+
+    Ok(())
+}
+
+/// Sort copies of `image` `runs` times for --benchmark and print the fastest, median and slowest sort
+///
+/// Only the sort itself is timed, the image is decoded and preprocessed once up front
+fn benchmark(mut image: RgbImage, cli: &Cli, reference: Option<&RgbImage>, runs: u32) -> anyhow::Result<()> {
+    preprocess(&mut image, cli, reference)?;
+    let options = sort_options(cli, image.dimensions())?;
+
+    for line in benchmark_report(image.dimensions(), &benchmark_timings(&image, &options, runs)) {
+        println!("{}", line);
+    }
+
+    Ok(())
+}
+
+/// How long each of `runs` sorts of a copy of `image` took, from the fastest to the slowest
+fn benchmark_timings(image: &RgbImage, options: &SortOptions, runs: u32) -> Vec<Duration> {
+    let mut timings = (0..runs)
+        .map(|_| {
+            let mut copy = image.clone();
+            let started = Instant::now();
+            copy.sort_rgb8_pixels(options.clone());
+            started.elapsed()
+        })
+        .collect::<Vec<_>>();
+    timings.sort();
+    timings
+}
+
+/// The lines --benchmark prints for the sorted `timings` of an image of `dimensions`,
+/// a single sort only has the one timing to report
+fn benchmark_report((width, height): (u32, u32), timings: &[Duration]) -> Vec<String> {
+    match timings {
+        [] => vec![format!("no sorts of {}x{}", width, height)],
+        [timing] => vec![format!("1 sort of {}x{}: {:?}", width, height, timing)],
+        timings => vec![
+            format!("{} sorts of {}x{}:", timings.len(), width, height),
+            format!("  min:     {:?}", timings[0]),
+            format!("  median:  {:?}", timings[timings.len() / 2]),
+            format!("  max:     {:?}", timings[timings.len() - 1])
+        ]
+    }
+}
+
+/// Sort every frame of the animated `input` with the same options and save them as a GIF, for --sort-all-frames
+///
+/// The frames keep their delays, and the same seed, so still parts of the animation stay still
+fn sort_all_frames(cli: &Cli, reference: Option<&RgbImage>, input: &Path, output: &Path) -> anyhow::Result<()> {
+    let frames = load_frames(input, cli.max_pixels)?;
+    log::info!("loaded {} frames of '{}'", frames.len(), input.display());
+
+    let mut options = None;
+    let started = Instant::now();
+    let frames = frames
+        .into_iter()
+        .map(|frame| {
+            let delay = frame.delay();
+            let mut image = limit(frame.into_buffer(), cli, false);
+            let dimensions = image.dimensions();
+            preprocess(&mut image, cli, reference)?;
+
+            if options.is_none() {
+                options = Some(sort_options(cli, image.dimensions())?);
+            }
+
+            let options = options.as_ref().expect("the options were just built");
+            image.sort_rgb8_pixels(options.clone());
+
+            let image = postprocess(limit(rotate_back(image, cli, dimensions), cli, true), cli)?;
+            Ok(Frame::from_parts(image, 0, 0, delay))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    log::debug!("sorted {} frames in {:?}", frames.len(), started.elapsed());
+
+    write_gif_frames(frames, output)?;
+
+    Ok(())
+}
+
+/// What a line of --interactive asks for
+enum Command {
+    Set,
+    Render(PathBuf),
+    Quit
+}
+
+/// Commands understood by --interactive, printed by `help`
+const INTERACTIVE_HELP: &str = "\
+interval N[,N...]   set the interval
+discretize N        set the discretize
+by NAME             set the extractor
+direction NAME      set the direction
+reverse             toggle reversing the sort
+seed N              set the seed
+render PATH         sort the image with the current options and save it
+quit                stop";
+
+/// Apply one line of --interactive to `options`
+fn interactive_command(options: &mut SortOptions, line: &str) -> anyhow::Result<Command> {
+    let (command, argument) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let argument = argument.trim();
+
+    let parse = |name: &str| -> anyhow::Result<u64> {
+        argument.parse().with_context(|| format!("{} needs a positive number, got '{}'", name, argument))
+    };
+
+    match command {
+        "interval" => {
+            let interval = argument
+                .split(',')
+                .map(|value| match value.trim().parse::<usize>() {
+                    Ok(value) if value > 0 => Ok(value),
+                    _ => bail!("interval needs positive numbers, got '{}'", value)
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            options.interval = interval;
+        }
+        "discretize" => options.discretize = parse("discretize")?.max(1),
+        "by" => options.by = SortingAlgorithm::try_from(argument)?,
+        "direction" => options.direction = <WalkPath as ValueEnum>::from_str(argument, true).map_err(anyhow::Error::msg)?,
+        "reverse" => options.reverse = !options.reverse,
+        "seed" => options.seed = Some(parse("seed")?),
+        "render" if argument.is_empty() => bail!("render needs an output path"),
+        "render" => return Ok(Command::Render(PathBuf::from(argument))),
+        "help" => println!("{}", INTERACTIVE_HELP),
+        "quit" | "exit" => return Ok(Command::Quit),
+        other => bail!("unknown command '{}', try 'help'", other)
+    }
+
+    Ok(Command::Set)
+}
+
+/// Read commands from stdin for --interactive, re-sorting a copy of `image` on every render
+///
+/// Bad commands and failed renders are reported without leaving the loop
+fn interactive(image: RgbImage, cli: &Cli, reference: Option<&RgbImage>) -> anyhow::Result<()> {
+    let mut image = limit(image, cli, false);
+    let dimensions = image.dimensions();
+    preprocess(&mut image, cli, reference)?;
+
+    let mut options = sort_options(cli, image.dimensions())?;
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+
+    loop {
+        print!("> ");
+        std::io::stdout().flush()?;
+
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break
+        };
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        match interactive_command(&mut options, line) {
+            Ok(Command::Set) => {}
+            Ok(Command::Render(path)) => {
+                let mut sorted = image.clone();
+                sorted.sort_rgb8_pixels(options.clone());
+                let saved = check_clobber(cli, &path)
+                    .and_then(|()| postprocess(limit(rotate_back(sorted, cli, dimensions), cli, true), cli))
+                    .and_then(|sorted| {
+                        save_image(&DynamicImage::ImageRgb8(sorted), &path, cli.embed_srgb, None)?;
+                        Ok(())
+                    });
+
+                match saved {
+                    Ok(()) => println!("saved '{}'", path.display()),
+                    Err(error) => eprintln!("error: {:#}", error)
+                }
+            }
+            Ok(Command::Quit) => break,
+            Err(error) => eprintln!("error: {:#}", error)
+        }
+    }
+
+    Ok(())
+}
+
+/// 32-bit FNV-1a of `bytes`, which unlike `DefaultHasher` hashes the same in every build
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, &byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193))
+}
+
+/// The sort options that shape the output, spelled out one by one for the hash of [`output_name`]
+///
+/// Loaded images and options that only change how fast the sort runs are left out
+fn options_key(options: &SortOptions) -> String {
+    [
+        format!("by={:?} then={:?} coefficients={:?}", options.by, options.then, options.coefficients),
+        format!("interval={:?} unit={:?} channels={:?}", options.interval, options.interval_unit, options.channel_intervals),
+        format!("jitter={} gap={} min={}", options.interval_jitter, options.span_gap, options.min_span),
+        format!("source={:?} period={} seed={:?}", options.interval_source, options.wave_period, options.seed),
+        format!("reverse={} center={} discretize={}", options.reverse, options.center_out, options.discretize),
+        format!("wrap={} progressive={:?} {:?}", options.wrap, options.progressive_amount, options.progressive_curve),
+        format!("direction={:?} curve={:?} diagonals={:?}", options.direction, options.curve, options.diagonals),
+        format!("splice={:?} edge={:?} {:?}", options.splice, options.edge_threshold, options.edge_detector),
+        format!(
+            "threshold={:?} auto={} adaptive={} metric={:?}",
+            options.image_threshold, options.auto_threshold, options.adaptive_threshold, options.threshold_metric
+        ),
+        format!(
+            "mask={:?} {} {:?} {:?}",
+            options.image_mask, options.mask_threshold, options.channel_threshold, options.mask_combine
+        ),
+        format!("key={:?} {} {}", options.key_color, options.key_tolerance, options.key_sort_only),
+        format!("channel={:?} shuffle={} target={:?} k={}", options.channel, options.shuffle, options.target, options.k),
+        format!("hue={} alpha={:?} premultiply={}", options.hue_origin, options.sort_alpha, options.premultiply),
+        format!(
+            "split={:?} position={} strength={} quantize={:?}",
+            options.split_order, options.position_weight, options.sort_strength, options.key_quantize
+        ),
+        format!(
+            "run={:?} partial={:?} {:?} reorder={:?}",
+            options.max_run, options.partial, options.partial_anchor, options.reorder_spans
+        )
+    ]
+    .join(";")
+}
+
+/// Generate an output file name from the input stem and the options, for --output-dir
+///
+/// The key options are spelled out, e.g. `photo_luma_i20`, and an [`fnv1a`] hash of the
+/// [`options_key`] tells apart runs that only differ in the others
+fn output_name(cli: &Cli, input: &Path) -> PathBuf {
+    let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+    let by = cli.by.to_string();
+    let interval = cli.interval.iter().map(ToString::to_string).collect::<Vec<_>>().join("-");
+
+    let mut name = format!("{}_{}_i{}", stem, by, interval);
+
+    if cli.discretize > 1 {
+        name.push_str(&format!("_d{}", cli.discretize));
+    }
+
+    if cli.reverse {
+        name.push_str("_r");
+    }
+
+    if let WalkPath::Vertical = cli.direction {
+        name.push_str("_v");
+    }
 
-    let mut image = load_image(&cli.input)?;
-    let rgb8_image = image
-        .as_mut_rgb8()
-        .ok_or_else(|| anyhow!("failed to convert image to RGB8"))?;
+    let options: SortOptions = cli.into();
+    name.push_str(&format!("_{:08x}", fnv1a(options_key(&options).as_bytes())));
 
-    rgb8_image.sort_rgb8_pixels((&cli).into());
+    let ext = if cli.animate.is_empty() && !cli.sort_all_frames {
+        input.extension().map_or("png".into(), |ext| ext.to_string_lossy().into_owned())
+    } else {
+        "gif".to_string()
+    };
 
-    rgb8_image.save(cli.output)?;
+    PathBuf::from(format!("{}.{}", name, ext))
+}
+
+/// The key the pixel at `point` is sorted by, for --sample
+///
+/// Read from the key map, which clusters the dominant colors and measures the texture around
+/// the pixel like the sort does, instead of from the extractor of the pixel alone
+fn sample_key(image: &RgbImage, (x, y): (u32, u32), options: &SortOptions) -> u8 {
+    rgb8_key_map(image, options).get_pixel(x, y).0[0]
+}
+
+/// `output` with the name of `by` appended to its file stem, for --multi-by
+fn multi_by_output(output: &Path, by: SortingAlgorithm) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+
+    match output.extension() {
+        Some(ext) => output.with_file_name(format!("{}_{}.{}", stem, by, ext.to_string_lossy())),
+        None => output.with_file_name(format!("{}_{}", stem, by))
+    }
+}
+
+/// Sort the loaded `image` and save it, as an animation if any parameter is animated
+fn sort_image(image: DynamicImage, cli: &Cli, reference: Option<&RgbImage>, input: &Path, output: &Path) -> anyhow::Result<()> {
+    match cli.animate.as_slice() {
+        [] if image.color().has_alpha() => {
+            let color = image.color();
+            sort_and_save(image.into_rgba8(), color, cli, reference, input, output)
+        }
+        [] => {
+            let (rgb8_image, color) = from_dynamic(image);
+            sort_and_save(rgb8_image, color, cli, reference, input, output)
+        }
+        params => {
+            let (rgb8_image, _) = from_dynamic(image);
+            animate_and_save(rgb8_image, params, cli, reference, input, output)
+        }
+    }
+}
+
+/// Sort a single input file with the options of `cli`, matching its histogram to `reference` if given
+fn process(cli: &Cli, input: &Path, reference: Option<&RgbImage>) -> anyhow::Result<()> {
+    let load = || -> anyhow::Result<DynamicImage> {
+        let image = match cli.input_frame {
+            Some(index) => load_frame(input, index, cli.max_pixels)?,
+            None if cli.lenient => load_image_lenient(input, cli.max_pixels)?,
+            None => load_image_limited(input, cli.max_pixels)?
+        };
+        let image = if cli.respect_exif { orient_by_exif(image, input) } else { image };
+        log::info!("loaded '{}', {}x{} {:?}", input.display(), image.width(), image.height(), image.color());
+        Ok(image)
+    };
+
+    if cli.interactive {
+        let (rgb8_image, _) = from_dynamic(load()?);
+        return interactive(rgb8_image, cli, reference);
+    }
+
+    if let Some(runs) = cli.benchmark {
+        let (rgb8_image, _) = from_dynamic(load()?);
+        return benchmark(rgb8_image, cli, reference, runs);
+    }
+
+    let output = match (&cli.output, &cli.output_dir) {
+        (Some(output), _) => output.clone(),
+        (None, Some(dir)) => dir.join(output_name(cli, input)),
+        (None, None) => unreachable!("clap requires an output file or --output-dir outside of --interactive and --benchmark")
+    };
+
+    if cli.multi_by.is_empty() {
+        check_clobber(cli, &output)?;
+    }
+
+    if cli.alpha_from_mask && !supports_alpha(&output) {
+        bail!("'{}' can't store the alpha of --alpha-from-mask, save it as PNG, WebP, TIFF or TGA", output.display())
+    }
+
+    if cli.sort_all_frames {
+        if !matches!(ImageFormat::from_path(&output), Ok(ImageFormat::Gif)) {
+            bail!("'{}' can't store the frames of --sort-all-frames, save them as a GIF", output.display())
+        }
+
+        return sort_all_frames(cli, reference, input, &output);
+    }
+
+    if cli.stream {
+        let options = sort_options(cli, image::image_dimensions(input)?)?;
+        let report = stream_sort(input, &output, options)?;
+
+        if cli.stats {
+            print_report("sort", &report);
+        }
+
+        return Ok(());
+    }
+
+    let image = load()?;
+
+    if cli.show_key {
+        let keys = key_image(image, cli, reference)?;
+        save_image(&DynamicImage::ImageLuma8(keys), &output, cli.embed_srgb, None)?;
+
+        return Ok(());
+    }
+
+    if cli.self_test {
+        let (mut rgb8_image, _) = from_dynamic(image);
+
+        preprocess(&mut rgb8_image, cli, reference)?;
+
+        // a fixed seed, so any difference comes from randomness the seed doesn't reach
+        let mut options = sort_options(cli, rgb8_image.dimensions())?;
+        options.seed = Some(cli.seed.unwrap_or(SELF_TEST_SEED));
+
+        let mut first = rgb8_image.clone();
+        first.sort_rgb8_pixels(options.clone());
+        rgb8_image.sort_rgb8_pixels(options);
+
+        if first.as_raw() != rgb8_image.as_raw() {
+            bail!("self test failed: two sorts with the same seed came out different")
+        }
+
+        println!("self test passed");
+
+        return Ok(());
+    }
+
+    if let Some(point) = cli.sample {
+        let (mut rgb8_image, _) = from_dynamic(image);
+
+        preprocess(&mut rgb8_image, cli, reference)?;
+
+        let (width, height) = rgb8_image.dimensions();
+        if point.x >= width || point.y >= height {
+            bail!("({}, {}) is outside of the {}x{} image", point.x, point.y, width, height)
+        }
+
+        let options = sort_options(cli, (width, height))?;
+        let pixel = rgb8_image.get_pixel(point.x, point.y);
+
+        println!("pixel ({}, {})", point.x, point.y);
+        println!("  rgb:     {:?}", pixel.0);
+        println!("  updated: {:?}", update_pixel(&pixel.0, &options));
+        println!("  key:     {} ({})", sample_key(&rgb8_image, (point.x, point.y), &options), options.by);
+
+        return Ok(());
+    }
+
+    if cli.multi_by.is_empty() {
+        return sort_image(image, cli, reference, input, &output);
+    }
+
+    // every extractor gets its own copy of the one decoded image
+    for &by in &cli.multi_by {
+        let mut cli = cli.clone();
+        cli.by = by;
+        cli.validate()?;
+
+        let output = match (&cli.output, &cli.output_dir) {
+            (None, Some(dir)) => dir.join(output_name(&cli, input)),
+            _ => multi_by_output(&output, by)
+        };
+
+        check_clobber(&cli, &output)?;
+
+        sort_image(image.clone(), &cli, reference, input, &output)?;
+    }
+
+    Ok(())
+}
+
+/// Read the input paths of --input-list, one per line
+///
+/// Blank lines and files that don't exist are skipped with a warning instead of failing the batch
+fn read_input_list(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let list = std::fs::read_to_string(path).with_context(|| format!("failed to read '{}'", path.display()))?;
+    let mut inputs = Vec::new();
+
+    for (number, line) in list.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() {
+            log::warn!("skipping blank line {} of '{}'", number + 1, path.display());
+            continue;
+        }
+
+        let input = PathBuf::from(line);
+        if !input.exists() {
+            log::warn!("skipping '{}', it doesn't exist", input.display());
+            continue;
+        }
+
+        inputs.push(input);
+    }
+
+    Ok(inputs)
+}
+
+/// Sort every input of --input-list, carrying on past the ones that fail
+///
+/// Fails once all of them were tried if any of them did, so the exit code still tells
+fn process_list(cli: &Cli, inputs: &[PathBuf], reference: Option<&RgbImage>) -> anyhow::Result<()> {
+    let failed = inputs
+        .iter()
+        .filter(|input| match process(cli, input, reference) {
+            Ok(()) => false,
+            Err(error) => {
+                log::error!("failed to sort '{}': {:#}", input.display(), error);
+                true
+            }
+        })
+        .count();
+
+    if failed > 0 {
+        bail!("{} of the {} inputs failed", failed, inputs.len())
+    }
 
     Ok(())
 }
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::try_parse_styled_from(std::env::args_os()).unwrap_or_else(|error| error.exit());
+
+    // RUST_LOG still overrides the level of --verbose
+    env_logger::Builder::new().filter_level(cli.log_level()).parse_default_env().init();
+    cli.validate()?;
+
+    // decoded once for every input, validate only looked at its header
+    let reference = match &cli.match_histogram {
+        Some(path) => Some(load_image(path)?.into_rgb8()),
+        None => None
+    };
+
+    match (&cli.input, &cli.input_list) {
+        (Some(input), _) => process(&cli, input, reference.as_ref()),
+        (None, Some(list)) => process_list(&cli, &read_input_list(list)?, reference.as_ref()),
+        (None, None) => unreachable!("clap requires either an input file or --input-list")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use image::Rgb;
+    use pxsort::{extractor, sort::texture_key_map};
+
+    use super::*;
+
+    /// The command line `pxsort ARGS`
+    fn cli(args: &[&str]) -> Cli {
+        Cli::parse_from(["pxsort"].into_iter().chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn inverting_twice_gives_back_the_image() {
+        let original = RgbImage::from_fn(9, 7, |x, y| Rgb([(x * 28) as u8, (y * 36) as u8, (x * y) as u8]));
+
+        let pre = cli(&["luma", "--invert-pre", "in.png", "out.png"]);
+        let mut image = original.clone();
+        preprocess(&mut image, &pre, None).unwrap();
+        assert_eq!(image.get_pixel(1, 0).0, [227, 255, 255]);
+        preprocess(&mut image, &pre, None).unwrap();
+        assert_eq!(image, original);
+
+        let post = cli(&["luma", "--invert-post", "in.png", "out.png"]);
+        let image = postprocess(original.clone(), &post).unwrap();
+        assert_eq!(image.get_pixel(1, 0).0, [227, 255, 255]);
+        assert_eq!(postprocess(image, &post).unwrap(), original);
+    }
+
+    #[test]
+    fn luma_key_images_hold_the_luma_of_every_pixel() {
+        let image = RgbImage::from_fn(16, 12, |x, y| Rgb([(x * 16) as u8, (y * 20) as u8, ((x ^ y) * 15) as u8]));
+        let luma = cli(&["luma", "--show-key", "in.png", "out.png"]);
+
+        let keys = key_image(DynamicImage::ImageRgb8(image.clone()), &luma, None).unwrap();
+        let options = sort_options(&luma, image.dimensions()).unwrap();
+
+        for (pixel, key) in image.pixels().zip(keys.pixels()) {
+            assert_eq!(key.0[0], extractor::to_u8_key(extractor::luma(pixel, &options)));
+        }
+    }
+
+    #[test]
+    fn existing_files_are_only_kept_under_no_clobber() {
+        let dir = tempfile::tempdir().unwrap();
+        let existing = dir.path().join("out.png");
+        std::fs::write(&existing, "keep me").unwrap();
+
+        let error = check_clobber(&cli(&["luma", "--no-clobber", "in.png", "out.png"]), &existing).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(PxsortError::AlreadyExists { .. })));
+
+        assert!(check_clobber(&cli(&["luma", "--no-clobber", "in.png", "out.png"]), &dir.path().join("new.png")).is_ok());
+        assert!(check_clobber(&cli(&["luma", "--no-clobber", "--force", "in.png", "out.png"]), &existing).is_ok());
+        assert!(check_clobber(&cli(&["luma", "in.png", "out.png"]), &existing).is_ok());
+    }
+
+    #[test]
+    fn every_frame_of_an_animated_input_gets_sorted() {
+        let dir = tempfile::tempdir().unwrap();
+        let (input, output) = (dir.path().join("in.gif"), dir.path().join("out.gif"));
+
+        // gray rows descending from the left, darker in the second frame
+        let frame = |top: u32| RgbImage::from_fn(8, 2, |x, y| Rgb([(top - x * 20 - y * 5) as u8; 3]));
+        write_gif(&[frame(240), frame(200)], &input).unwrap();
+
+        let cli = cli(&["luma", "-i", "8", "-d", "8", "-p", "1000", "--sort-all-frames", "in.gif", "out.gif"]);
+        sort_all_frames(&cli, None, &input, &output).unwrap();
+
+        let frames = load_frames(&output, u64::MAX).unwrap();
+        assert_eq!(frames.len(), 2);
+        for (frame, top) in frames.iter().zip([240, 200]) {
+            let image = frame.buffer();
+            assert_eq!(image.get_pixel(7, 0).0[0], top as u8);
+
+            for y in 0..2 {
+                assert!((1..8).all(|x| image.get_pixel(x - 1, y).0[0] <= image.get_pixel(x, y).0[0]), "{:?}", image);
+            }
+        }
+    }
+
+    #[test]
+    fn sampled_keys_match_the_extractors() {
+        let image = RgbImage::from_fn(16, 12, |x, y| Rgb([(x * 16) as u8, (y * 20) as u8, ((x ^ y) * 15) as u8]));
+
+        let luma = cli(&["luma", "in.png", "out.png"]);
+        let options = sort_options(&luma, image.dimensions()).unwrap();
+        let extractor = options.by.into_rgb_sorter();
+        for (x, y, pixel) in image.enumerate_pixels() {
+            assert_eq!(sample_key(&image, (x, y), &options), extractor(pixel, &options));
+        }
+
+        // the dominant colors are clustered from the image, so not every pixel is nearest to the first one
+        let dominant = cli(&["dominant", "--seed", "3", "in.png", "out.png"]);
+        let options = sort_options(&dominant, image.dimensions()).unwrap();
+        let keys = rgb8_key_map(&image, &options);
+        assert!(keys.pixels().any(|key| key.0[0] > 0));
+        for (x, y, key) in keys.enumerate_pixels() {
+            assert_eq!(sample_key(&image, (x, y), &options), key.0[0]);
+        }
+
+        let texture = cli(&["texture", "in.png", "out.png"]);
+        let options = sort_options(&texture, image.dimensions()).unwrap();
+        let keys = texture_key_map(&image);
+        assert!(keys.pixels().any(|key| key.0[0] > 0));
+        for (x, y, key) in keys.enumerate_pixels() {
+            assert_eq!(sample_key(&image, (x, y), &options), key.0[0]);
+        }
+    }
+
+    #[test]
+    fn input_lists_skip_blank_lines_and_missing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("photo.png");
+        RgbImage::new(4, 4).save(&input).unwrap();
+
+        let list = dir.path().join("inputs.txt");
+        let missing = dir.path().join("missing.png");
+        std::fs::write(&list, format!("\n{}\n   \n{}\n", input.display(), missing.display())).unwrap();
+
+        assert_eq!(read_input_list(&list).unwrap(), vec![input]);
+    }
+
+    #[test]
+    fn input_lists_sort_the_rest_after_a_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let (broken, photo) = (dir.path().join("broken.png"), dir.path().join("photo.png"));
+        std::fs::write(&broken, "not a png").unwrap();
+        RgbImage::from_fn(8, 8, |x, y| Rgb([(x * 30) as u8, (y * 30) as u8, 0])).save(&photo).unwrap();
+
+        let output_dir = dir.path().join("sorted");
+        std::fs::create_dir(&output_dir).unwrap();
+        let list = dir.path().join("inputs.txt");
+        let cli = cli(&["luma", "--input-list", list.to_str().unwrap(), "--output-dir", output_dir.to_str().unwrap()]);
+
+        let error = process_list(&cli, &[broken, photo], None).unwrap_err();
+
+        assert_eq!(error.to_string(), "1 of the 2 inputs failed");
+        assert_eq!(std::fs::read_dir(&output_dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn benchmarking_once_reports_one_timing() {
+        let image = RgbImage::from_fn(12, 10, |x, y| Rgb([(x * 20) as u8, (y * 25) as u8, 7]));
+        let options = sort_options(&cli(&["luma", "--benchmark", "1", "in.png"]), image.dimensions()).unwrap();
+
+        let timings = benchmark_timings(&image, &options, 1);
+        assert_eq!(timings.len(), 1);
+
+        let report = benchmark_report(image.dimensions(), &timings);
+        assert_eq!(report, vec![format!("1 sort of 12x10: {:?}", timings[0])]);
+
+        let timings = benchmark_timings(&image, &options, 3);
+        assert_eq!(benchmark_report(image.dimensions(), &timings).len(), 4);
+    }
+
+    #[test]
+    fn fnv1a_matches_the_reference_values() {
+        assert_eq!(fnv1a(b""), 0x811c_9dc5);
+        assert_eq!(fnv1a(b"a"), 0xe40c_292c);
+        assert_eq!(fnv1a(b"foobar"), 0xbf9c_f968);
+    }
+
+    #[test]
+    fn output_names_spell_out_the_key_options() {
+        let args = ["luma", "-i", "20,5", "-d", "4", "-r", "-t", "v", "--seed", "7", "--output-dir", "out", "photo.png"];
+        let name = output_name(&cli(&args), Path::new("shots/photo.png")).to_string_lossy().into_owned();
+
+        let (options, hash) = name.strip_suffix(".png").unwrap().rsplit_once('_').unwrap();
+        assert_eq!(options, "photo_luma_i20-5_d4_r_v");
+        assert_eq!(hash.len(), 8);
+        assert!(hash.chars().all(|digit| digit.is_ascii_hexdigit()));
+
+        assert_eq!(output_name(&cli(&args), Path::new("shots/photo.png")).to_string_lossy(), name);
+    }
+
+    #[test]
+    fn output_names_tell_apart_the_other_options() {
+        let name = |args: &[&str]| output_name(&cli(args), Path::new("photo.jpg"));
+        let plain = name(&["hue", "--seed", "1", "--output-dir", "out", "photo.jpg"]);
+
+        assert_ne!(plain, name(&["hue", "--seed", "2", "--output-dir", "out", "photo.jpg"]));
+        assert_ne!(plain, name(&["hue", "--seed", "1", "--then", "luma", "--output-dir", "out", "photo.jpg"]));
+        assert_ne!(plain, name(&["hue", "--seed", "1", "--hue-origin", "90", "--output-dir", "out", "photo.jpg"]));
+        assert_eq!(plain.extension().unwrap(), "jpg");
+    }
+
+    #[test]
+    fn single_images_are_sorted_in_their_own_buffer() {
+        let mut image = RgbImage::from_fn(8, 2, |x, _| Rgb([(240 - x * 20) as u8; 3]));
+        let buffer = image.as_raw().as_ptr();
+
+        let single = cli(&["luma", "-i", "8", "-d", "8", "-p", "1000", "in.png", "out.png"]);
+        let options = sort_options(&single, image.dimensions()).unwrap();
+        sort_pass(&mut image, &options, None);
+
+        assert_eq!(image.get_pixel(0, 0).0, [100; 3]);
+        assert_eq!(image.as_raw().as_ptr(), buffer);
+    }
+
+    #[test]
+    fn sorting_until_stable_stops_within_the_cap() {
+        // the overlapping spans keep moving pixels for three sorts
+        let image = RgbImage::from_fn(12, 1, |x, _| Rgb([(250 - x * 20) as u8; 3]));
+        let options = sort_options(&cli(&["luma", "-i", "3", "-d", "4", "-p", "1000", "in.png", "out.png"]), (12, 1)).unwrap();
+
+        let mut stable = image.clone();
+        sort_pass(&mut stable, &options, Some(10));
+
+        let mut again = stable.clone();
+        sort_pass(&mut again, &options, None);
+        assert_eq!(again, stable);
+
+        // a cap of one sorts once although the image is still changing
+        let (mut capped, mut once) = (image.clone(), image);
+        sort_pass(&mut capped, &options, Some(1));
+        sort_pass(&mut once, &options, None);
+        assert_eq!(capped, once);
+        assert_ne!(capped, stable);
+    }
+
+    #[test]
+    fn crossing_a_symmetric_image_keeps_it_symmetric() {
+        // mirrored along the diagonal from the bottom left to the top right corner
+        let offsets = [40, 0, 96, 16, 64, 80];
+        let image = RgbImage::from_fn(6, 6, |x, y| Rgb([(offsets[x as usize] + offsets[5 - y as usize]) as u8; 3]));
+        let mirrored = |image: &RgbImage| RgbImage::from_fn(6, 6, |x, y| *image.get_pixel(5 - y, 5 - x));
+        assert_eq!(mirrored(&image), image);
+
+        let dir = tempfile::tempdir().unwrap();
+        let (input, output) = (dir.path().join("in.png"), dir.path().join("out.png"));
+        let cross = cli(&["luma", "-i", "6", "-d", "6", "-p", "1000", "--cross", "in.png", "out.png"]);
+        sort_and_save(image.clone(), ColorType::Rgb8, &cross, None, &input, &output).unwrap();
+
+        let sorted = image::open(&output).unwrap().into_rgb8();
+        assert_ne!(sorted, image);
+        assert_eq!(mirrored(&sorted), sorted);
+    }
+
+    #[test]
+    fn good_previews_filter_the_pixels_fast_ones_pick() {
+        // one pixel wide stripes, which picking every fourth pixel can't tell apart from a flat image
+        let image = RgbImage::from_fn(32, 32, |x, _| Rgb([if x % 2 == 0 { 0 } else { 200 }; 3]));
+        let preview = |quality: &str| {
+            let cli = cli(&["luma", "--limit", "8", "--preview-quality", quality, "in.png", "out.png"]);
+            limit(limit(image.clone(), &cli, false), &cli, true)
+        };
+
+        let (fast, good) = (preview("fast"), preview("good"));
+        assert_eq!(fast.dimensions(), (8, 8));
+        assert_eq!(good.dimensions(), (8, 8));
+        assert_ne!(fast, good);
+    }
+
+    #[test]
+    fn key_maps_are_written_next_to_the_sorted_image() {
+        let image = RgbImage::from_fn(10, 6, |x, y| Rgb([(x * 25) as u8, (y * 40) as u8, ((x + y) * 15) as u8]));
+
+        let dir = tempfile::tempdir().unwrap();
+        let (input, output, keys) = (dir.path().join("in.png"), dir.path().join("out.png"), dir.path().join("keys.png"));
+        let cli = cli(&["luma", "--write-key-map", keys.to_str().unwrap(), "in.png", "out.png"]);
+        sort_and_save(image, ColorType::Rgb8, &cli, None, &input, &output).unwrap();
+
+        assert_eq!(image::open(&output).unwrap().into_rgb8().dimensions(), (10, 6));
+        let keys = image::open(&keys).unwrap();
+        assert_eq!(keys.color(), ColorType::L8);
+        assert_eq!(keys.into_luma8().dimensions(), (10, 6));
+    }
+
+    #[test]
+    fn the_span_overlay_only_paints_the_boundaries() {
+        let image = RgbImage::from_fn(16, 8, |x, y| Rgb([(x * 16) as u8, (y * 30) as u8, 90]));
+        let dir = tempfile::tempdir().unwrap();
+        let (input, plain, marked) = (dir.path().join("in.png"), dir.path().join("plain.png"), dir.path().join("marked.png"));
+
+        let args = ["hue", "-i", "5", "--seed", "9", "in.png", "out.png"];
+        sort_and_save(image.clone(), ColorType::Rgb8, &cli(&args), None, &input, &plain).unwrap();
+        let debug = cli(&[&args[..], &["--debug-spans"]].concat());
+        sort_and_save(image.clone(), ColorType::Rgb8, &debug, None, &input, &marked).unwrap();
+
+        let (plain, marked) = (image::open(&plain).unwrap().into_rgb8(), image::open(&marked).unwrap().into_rgb8());
+        let options = sort_options(&debug, image.dimensions()).unwrap();
+        let boundaries = span_boundaries(&image, &options);
+        assert!(!boundaries.is_empty());
+
+        for (x, y, pixel) in marked.enumerate_pixels() {
+            if boundaries.contains(&(x, y)) {
+                assert_eq!(pixel.0, [0xff, 0, 0xff]);
+            } else {
+                assert_eq!(pixel, plain.get_pixel(x, y), "({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn flipping_twice_gives_back_the_image() {
+        let original = RgbImage::from_fn(5, 4, |x, y| Rgb([(x * 50) as u8, (y * 60) as u8, 7]));
+
+        for axes in [Flip::H, Flip::V, Flip::Both] {
+            let mut image = original.clone();
+            flip(&mut image, axes);
+            assert_ne!(image, original, "{:?}", axes);
+            flip(&mut image, axes);
+            assert_eq!(image, original, "{:?}", axes);
+        }
+    }
+
+    #[test]
+    fn reinterpreting_and_back_keeps_the_colors() {
+        let original = RgbImage::from_fn(16, 16, |x, y| Rgb([(x * 16) as u8, (y * 16) as u8, ((x * y) % 256) as u8]));
+        let round_trip = |mode: &str| {
+            let cli = cli(&["luma", "--reinterpret", mode, "in.png", "out.png"]);
+            let mut image = original.clone();
+            preprocess(&mut image, &cli, None).unwrap();
+            (image.clone(), postprocess(image, &cli).unwrap())
+        };
+
+        let (relabeled, restored) = round_trip("none");
+        assert_eq!(relabeled, original);
+        assert_eq!(restored, original);
+
+        // HSV in 8 bits can't hold every color exactly, so they come back a few levels off
+        let (relabeled, restored) = round_trip("hsv-as-rgb");
+        assert_ne!(relabeled, original);
+        for (restored, original) in restored.pixels().zip(original.pixels()) {
+            assert!(restored.0.iter().zip(original.0).all(|(a, b)| a.abs_diff(b) <= 4), "{:?} {:?}", restored, original);
+        }
+    }
+
+    #[test]
+    fn chunky_by_1_sorts_like_a_plain_sort() {
+        let image = RgbImage::from_fn(20, 12, |x, y| Rgb([(x * 12) as u8, (y * 20) as u8, ((x ^ y) * 9) as u8]));
+        let dir = tempfile::tempdir().unwrap();
+        let (input, plain, chunky) = (dir.path().join("in.png"), dir.path().join("plain.png"), dir.path().join("chunky.png"));
+
+        let args = ["hue", "-i", "6", "--seed", "8", "in.png", "out.png"];
+        sort_and_save(image.clone(), ColorType::Rgb8, &cli(&args), None, &input, &plain).unwrap();
+        let chunky_cli = cli(&[&args[..], &["--chunky", "1"]].concat());
+        sort_and_save(image, ColorType::Rgb8, &chunky_cli, None, &input, &chunky).unwrap();
+
+        assert_eq!(image::open(&chunky).unwrap().into_rgb8(), image::open(&plain).unwrap().into_rgb8());
+    }
+
+    #[test]
+    fn the_self_test_passes_for_a_seeded_sort() {
+        let dir = tempfile::tempdir().unwrap();
+        let (input, output) = (dir.path().join("in.png"), dir.path().join("out.png"));
+        // large enough to be sorted in parallel
+        RgbImage::from_fn(260, 260, |x, y| Rgb([(x * 7 % 256) as u8, (y * 11 % 256) as u8, ((x ^ y) % 256) as u8]))
+            .save(&input)
+            .unwrap();
+
+        let args = ["hue", "-i", "9", "--shuffle", "--self-test", input.to_str().unwrap(), output.to_str().unwrap()];
+        process(&cli(&args), &input, None).unwrap();
+
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn interactive_commands_change_the_options_until_a_render() {
+        let mut options = sort_options(&cli(&["luma", "in.png", "out.png"]), (8, 8)).unwrap();
+        let script = ["interval 30,5", "by hue", "interval 0", "direction vertical", "reverse", "seed 7", "jump 3"];
+
+        let failed = script
+            .into_iter()
+            .filter(|line| !matches!(interactive_command(&mut options, line), Ok(Command::Set)))
+            .collect::<Vec<_>>();
+        assert_eq!(failed, ["interval 0", "jump 3"]);
+
+        assert_eq!(options.interval, [30, 5]);
+        assert!(matches!(options.by, SortingAlgorithm::Hue));
+        assert!(matches!(options.direction, WalkPath::Vertical));
+        assert!(options.reverse);
+        assert_eq!(options.seed, Some(7));
+
+        assert!(matches!(interactive_command(&mut options, "render out.png"), Ok(Command::Render(path)) if path == Path::new("out.png")));
+        assert!(interactive_command(&mut options, "render").is_err());
+        assert!(matches!(interactive_command(&mut options, "quit"), Ok(Command::Quit)));
+    }
+
+    #[test]
+    fn masked_out_pixels_become_transparent() {
+        let dir = tempfile::tempdir().unwrap();
+        let mask = dir.path().join("mask.png");
+        GrayImage::from_fn(8, 4, |x, _| image::Luma([if x < 4 { 0 } else { 255 }])).save(&mask).unwrap();
+
+        let image = RgbImage::from_fn(8, 4, |x, y| Rgb([(x * 30) as u8, (y * 60) as u8, 120]));
+        let cli = cli(&["luma", "--image-mask", mask.to_str().unwrap(), "--alpha-from-mask", "in.png", "out.png"]);
+        let masked = alpha_from_mask(DynamicImage::ImageRgb8(image.clone()), &cli).unwrap().into_rgba8();
+
+        for (x, y, pixel) in masked.enumerate_pixels() {
+            assert_eq!(pixel.0[..3], image.get_pixel(x, y).0);
+            assert_eq!(pixel.0[3], if x < 4 { 0 } else { 255 }, "({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn terminal_previews_show_two_rows_of_pixels_per_line() {
+        let image = RgbImage::from_fn(40, 20, |x, y| Rgb([(x * 6) as u8, (y * 12) as u8, 50]));
+
+        // 10 columns leave 5 rows, rounded up to 6 and shown on 3 lines
+        let preview = terminal_preview(&image, 10);
+        assert_eq!(preview.lines().count(), 3);
+        assert!(preview.lines().all(|line| line.matches('\u{2580}').count() == 10));
+
+        // never wider than the image
+        let narrow = RgbImage::from_pixel(4, 9, Rgb([1, 2, 3]));
+        assert_eq!(terminal_preview(&narrow, 80).lines().count(), 5);
+    }
+
+    #[test]
+    fn gamma_and_its_inverse_give_back_the_image() {
+        let original = RgbImage::from_fn(256, 1, |x, _| Rgb([x as u8, 255 - x as u8, (x / 2) as u8]));
+        let round_trip = |gamma: &str| {
+            let cli = cli(&["luma", "--gamma", gamma, "--gamma-restore", "in.png", "out.png"]);
+            let mut image = original.clone();
+            preprocess(&mut image, &cli, None).unwrap();
+            (image.clone(), postprocess(image, &cli).unwrap())
+        };
+
+        assert_eq!(round_trip("1"), (original.clone(), original.clone()));
+
+        // the darkest values merge into each other, but never by more than a level
+        let (darkened, restored) = round_trip("1.25");
+        assert_ne!(darkened, original);
+        for (restored, original) in restored.pixels().zip(original.pixels()) {
+            assert!(restored.0.iter().zip(original.0).all(|(a, b)| a.abs_diff(b) <= 1), "{:?} {:?}", restored, original);
+        }
+    }
+
+    #[test]
+    fn multi_by_saves_one_image_per_extractor() {
+        let dir = tempfile::tempdir().unwrap();
+        let (input, output) = (dir.path().join("in.png"), dir.path().join("out.png"));
+        RgbImage::from_fn(10, 6, |x, y| Rgb([(x * 25) as u8, (y * 40) as u8, ((x + y) * 15) as u8])).save(&input).unwrap();
+
+        let args = ["luma", "--multi-by", "luma,hue,saturation", input.to_str().unwrap(), output.to_str().unwrap()];
+        process(&cli(&args), &input, None).unwrap();
+
+        let mut names = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["in.png", "out_hue.png", "out_luma.png", "out_saturation.png"]);
+    }
+}
@@ -1,11 +1,25 @@
-use std::{path::PathBuf, sync::mpsc::channel};
+use std::{
+    ops::Range,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::channel
+    },
+    time::{Duration, Instant}
+};
 
-use image::{Rgb, RgbImage, ImageBuffer};
+use image::{GrayImage, Luma, Pixel, Rgb, Rgba, RgbImage, RgbaImage, ImageBuffer};
 use indicatif::{ParallelProgressIterator, ProgressIterator, ProgressStyle};
 use itertools::Itertools;
-use rand::{seq::SliceRandom, thread_rng};
+use rand::{rngs::StdRng, seq::SliceRandom, thread_rng, Rng, SeedableRng};
 use rayon::prelude::*;
-use crate::{SortingAlgorithm, WalkPath, ColorChannel, AnimateParams, Cli, Coefficients};
+use crate::{
+    cluster::dominant_colors,
+    edge::{gradient_magnitude, local_deviation, EdgeMap},
+    img::otsu_threshold,
+    SortingAlgorithm, WalkPath, ChannelThreshold, ColorChannel, AnimateParams, Cli, Coefficients, Curve, Diagonals, AlphaOrder, EdgeDetector,
+    IntervalSource, IntervalUnit, MaskCombine, PartialAnchor, ProgressiveCurve, SpanOrder
+};
 
 /// Sort the pixels of an `RGB8` image
 ///
@@ -13,109 +27,1008 @@ use crate::{SortingAlgorithm, WalkPath, ColorChannel, AnimateParams, Cli, Coeffi
 /// Sort the pixels of an `RGB8` image
 ///
 /// Configurable with [`SortOptions`]
-fn rgb8_pixel_sort(image: &mut RgbImage, options: SortOptions) {
+///
+/// Looks the keys up in `keys` instead of extracting them if given,
+/// which has to be the [`rgb8_key_map`] of the image for the same options
+pub(crate) fn rgb8_pixel_sort(image: &mut RgbImage, options: SortOptions, keys: Option<&GrayImage>) -> SortReport {
+    match keys {
+        Some(keys) => pixel_sort(image, options, None, |pixel, (x, y), options| {
+            then_key(quantize_key(keys.get_pixel(x, y).0[0], options.key_quantize), pixel, options)
+        }),
+        None => {
+            let sorter = options.by.into_rgb_sorter();
+            pixel_sort(image, options, None, move |pixel, _, options| {
+                then_key(quantize_key(sorter(pixel, options), options.key_quantize), pixel, options)
+            })
+        }
+    }
+}
+
+/// Sort the pixels of an `RGBA8` image, carrying the alpha channel along
+///
+/// Configurable with [`SortOptions`]
+fn rgba8_pixel_sort(image: &mut RgbaImage, options: SortOptions) -> SortReport {
+    if let SortingAlgorithm::Texture = options.by {
+        let keys = texture_key_map(image);
+        return pixel_sort(image, options, None, move |pixel, (x, y), options| {
+            let color = then_key(quantize_key(keys.get_pixel(x, y).0[0], options.key_quantize), &pixel.to_rgb(), options);
+            rgba8_key(color, pixel.0[3], options.sort_alpha)
+        });
+    }
+
+    let sorter = rgba8_sorter(options.by);
+    pixel_sort(image, options, None, move |pixel, _, options| sorter(pixel, options))
+}
+
+/// Sort `rows` horizontally as the rows from `first_row` on of an image `height` rows tall
+///
+/// Sorting an image a few rows at a time this way gives the same image as sorting it whole,
+/// as long as the sort doesn't look across rows, like the edge detection does
+pub fn sort_rgb8_rows(rows: &mut RgbImage, first_row: u32, height: u32, options: SortOptions) -> SortReport {
     let sorter = options.by.into_rgb_sorter();
+    pixel_sort(rows, options, Some((first_row, height)), move |pixel, _, options| {
+        then_key(quantize_key(sorter(pixel, options), options.key_quantize), pixel, options)
+    })
+}
+
+/// Sort `rows` of an `RGBA8` image like [`sort_rgb8_rows`], carrying the alpha channel along
+pub fn sort_rgba8_rows(rows: &mut RgbaImage, first_row: u32, height: u32, options: SortOptions) -> SortReport {
+    let sorter = rgba8_sorter(options.by);
+    pixel_sort(rows, options, Some((first_row, height)), move |pixel, _, options| sorter(pixel, options))
+}
+
+/// Key extractor for `RGBA8` pixels
+///
+/// Sorts by the color, and by the alpha channel too if `sort_alpha` is set
+fn rgba8_sorter(by: SortingAlgorithm) -> impl Fn(&Rgba<u8>, &SortOptions) -> u32 + Copy {
+    let sorter = by.into_rgb_sorter();
+
+    move |&Rgba([red, green, blue, alpha]): &Rgba<u8>, options: &SortOptions| {
+        let color = Rgb([red, green, blue]);
+        let key = then_key(quantize_key(sorter(&color, options), options.key_quantize), &color, options);
+        rgba8_key(key, alpha, options.sort_alpha)
+    }
+}
+
+/// Round `key` down into one of `levels` evenly sized buckets, spread back out over 0-255
+///
+/// 256 levels leave the key as it is and a single level makes every key 0
+fn quantize_key(key: u8, levels: Option<u16>) -> u8 {
+    match levels {
+        Some(levels) if levels < 256 => {
+            let bucket = key as u32 * levels as u32 / 256;
+            (bucket * u8::MAX as u32 / (levels as u32 - 1).max(1)) as u8
+        }
+        _ => key
+    }
+}
+
+/// Break ties between equal `key`s by the key `then` gives `pixel`, if it is set
+fn then_key(key: u8, pixel: &Rgb<u8>, options: &SortOptions) -> u16 {
+    match options.then {
+        Some(then) => u16::from_be_bytes([key, then.into_rgb_sorter()(pixel, options)]),
+        None => key as u16
+    }
+}
+
+/// Combine the key of the color of an `RGBA8` pixel with its alpha as `sort_alpha` asks for
+fn rgba8_key(color: u16, alpha: u8, sort_alpha: Option<AlphaOrder>) -> u32 {
+    match sort_alpha {
+        None => color as u32,
+        Some(AlphaOrder::Primary) => (alpha as u32) << 16 | color as u32,
+        Some(AlphaOrder::Tiebreaker) => (color as u32) << 8 | alpha as u32
+    }
+}
+
+/// Build the spans of a line, where every span is a block of `discretize` pixels
+///
+/// A span starts at the beginning of every interval, cycling through `intervals`
+/// span by span, moved by a random offset of up to `jitter` pixels either way.
+/// Every span is followed by `gap` pixels that no span starts in.
+/// Spans may reach past the end of the line
+fn spans(
+    line_length: u32,
+    intervals: &[usize],
+    discretize: u32,
+    jitter: u32,
+    gap: u32,
+    rng: &mut impl Rng
+) -> Vec<Range<u32>> {
+    intervals
+        .iter()
+        .cycle()
+        .scan(0, |start, &interval| {
+            let base = *start;
+            *start += interval as u32 + gap;
+            Some(base)
+        })
+        .take_while(|&base| base < line_length)
+        .map(|base| {
+            let offset = match jitter {
+                0 => 0,
+                jitter => rng.gen_range(-(jitter as i64)..=jitter as i64)
+            };
+            let start = (base as i64 + offset).clamp(0, line_length as i64 - 1) as u32;
+
+            start..start + discretize
+        })
+        .collect()
+}
+
+/// Split spans at every edge, so that no span gets sorted across one
+///
+/// An edge pixel starts a new span
+fn split_at_edges(spans: Vec<Range<u32>>, is_edge: impl Fn(u32) -> bool) -> Vec<Range<u32>> {
+    spans
+        .into_iter()
+        .flat_map(|span| {
+            let mut pieces = Vec::new();
+            let mut start = span.start;
+
+            for i in span.start + 1..span.end {
+                if is_edge(i) {
+                    pieces.push(start..i);
+                    start = i;
+                }
+            }
+
+            pieces.push(start..span.end);
+            pieces
+        })
+        .collect()
+}
+
+/// Sort only the included pixels of a block with `sort`, the others keep their place
+///
+/// The sorted pixels fill the places of the included ones in order
+fn sort_included<K, P>(block: Vec<(bool, K, P)>, sort: impl FnOnce(&mut Vec<(K, P)>)) -> Vec<P> {
+    let pattern = block.iter().map(|&(included, _, _)| included).collect::<Vec<_>>();
+    let (selected, rest): (Vec<_>, Vec<_>) = block.into_iter().partition(|&(included, _, _)| included);
+
+    let mut selected = selected.into_iter().map(|(_, key, pixel)| (key, pixel)).collect::<Vec<_>>();
+    sort(&mut selected);
+
+    let mut selected = selected.into_iter().map(|(_, pixel)| pixel);
+    let mut rest = rest.into_iter().map(|(_, _, pixel)| pixel);
+
+    pattern
+        .into_iter()
+        .filter_map(|included| if included { selected.next() } else { rest.next() })
+        .collect()
+}
+
+/// Stable sort of keyed pixels by their key, in parallel for large images
+fn sort_keyed<K: Ord + Send, P: Send>(pixels: &mut [(K, P)], descending: bool, parallel: bool) {
+    let order = |(a, _): &(K, P), (b, _): &(K, P)| if descending { b.cmp(a) } else { a.cmp(b) };
+
+    if parallel {
+        pixels.par_sort_by(order);
+    } else {
+        pixels.sort_by(order);
+    }
+}
+
+/// Merge sort of keyed pixels that only swaps every pair that is out of order with a probability of `strength`
+///
+/// A `strength` of 1.0 sorts like [`sort_keyed`] and 0.0 leaves the pixels as they are
+fn sort_keyed_weakly<K: Ord, P, R: Rng>(pixels: &mut Vec<(K, P)>, descending: bool, strength: f32, rng: &mut R) {
+    if pixels.len() < 2 {
+        return;
+    }
+
+    let mut right = pixels.split_off(pixels.len() / 2);
+    sort_keyed_weakly(pixels, descending, strength, rng);
+    sort_keyed_weakly(&mut right, descending, strength, rng);
+
+    let mut left = std::mem::take(pixels).into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+
+    loop {
+        let out_of_order = match (left.peek(), right.peek()) {
+            (Some((a, _)), Some((b, _))) => if descending { a < b } else { b < a },
+            _ => break
+        };
+
+        let next = if out_of_order && rng.gen::<f32>() < strength { right.next() } else { left.next() };
+        pixels.extend(next);
+    }
+
+    pixels.extend(left);
+    pixels.extend(right);
+}
+
+/// Stable sort of keyed pixels by their key blended with their position in the block
+///
+/// Keys are normalized to the range of the block first, a `weight` of 1.0 keeps the pixels in place
+fn sort_position_weighted<K: Copy + Into<f64>, P>(pixels: &mut Vec<(K, P)>, weight: f32) {
+    let weight = weight as f64;
+    let (min, max) = pixels
+        .iter()
+        .map(|&(key, _)| Into::<f64>::into(key))
+        .fold((f64::MAX, f64::MIN), |(min, max), key| (min.min(key), max.max(key)));
+    let range = (max - min).max(f64::EPSILON);
+    let last = (pixels.len().max(2) - 1) as f64;
+
+    let mut weighted = pixels
+        .drain(..)
+        .enumerate()
+        .map(|(position, (key, pixel))| {
+            let blended = (1.0 - weight) * (Into::<f64>::into(key) - min) / range + weight * position as f64 / last;
+            (blended, (key, pixel))
+        })
+        .collect::<Vec<_>>();
+
+    weighted.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+    pixels.extend(weighted.into_iter().map(|(_, pixel)| pixel));
+}
+
+/// Sort `block` with `sort`, except for every pixel after `max_run` others, which keeps its place
+///
+/// The kept pixels break the block into sorted runs of at most `max_run` pixels
+fn sort_runs<T>(block: &mut Vec<T>, max_run: usize, sort: impl FnOnce(&mut Vec<T>)) {
+    let is_kept = |index: usize| index % (max_run + 1) == max_run;
+    let len = block.len();
+
+    let (kept, rest): (Vec<_>, Vec<_>) = block.drain(..).enumerate().partition(|&(index, _)| is_kept(index));
+    let mut rest = rest.into_iter().map(|(_, pixel)| pixel).collect::<Vec<_>>();
+    sort(&mut rest);
+
+    let mut kept = kept.into_iter().map(|(_, pixel)| pixel);
+    let mut rest = rest.into_iter();
+    block.extend((0..len).filter_map(|index| if is_kept(index) { kept.next() } else { rest.next() }));
+}
+
+/// Sort only the pixels of `block` in `range` with `sort`, the others keep their place
+fn sort_partial<T>(block: &mut Vec<T>, range: Range<usize>, sort: impl FnOnce(&mut Vec<T>)) {
+    let rest = block.split_off(range.end);
+    let mut sorted = block.split_off(range.start);
+    sort(&mut sorted);
+
+    block.extend(sorted);
+    block.extend(rest);
+}
+
+/// Move the first pixel of a sorted `block` to its middle and deal the others out to both sides,
+/// alternating right and left, so the order runs outward from the middle
+fn center_out<T>(block: &mut Vec<T>) {
+    let (mut left, mut right) = (Vec::with_capacity(block.len() / 2), Vec::with_capacity(block.len() / 2 + 1));
+    for (index, pixel) in block.drain(..).enumerate() {
+        if index % 2 == 0 {
+            right.push(pixel);
+        } else {
+            left.push(pixel);
+        }
+    }
+
+    block.extend(left.into_iter().rev());
+    block.extend(right);
+}
+
+/// Reorder the sorted blocks of a line themselves, shortest or darkest first
+///
+/// Equal blocks keep their order
+fn reorder_spans<P: Pixel<Subpixel = u8>>(blocks: &mut [Vec<P>], order: SpanOrder) {
+    match order {
+        SpanOrder::None => {}
+        SpanOrder::Length => blocks.sort_by_key(|block| block.len()),
+        SpanOrder::Brightness => blocks.sort_by_cached_key(|block| {
+            let sum = block.iter().map(|pixel| pixel.to_luma().0[0] as u64).sum::<u64>();
+            sum / block.len().max(1) as u64
+        })
+    }
+}
+
+/// The value of `pixel` the image and edge thresholds measure, its luma unless `threshold_metric` is set
+fn threshold_key<P: Pixel<Subpixel = u8>>(pixel: &P, options: &SortOptions) -> u8 {
+    match options.threshold_metric {
+        Some(metric) => metric.into_rgb_sorter()(&pixel.to_rgb(), options),
+        None => pixel.to_luma().0[0]
+    }
+}
+
+/// Whether `pixel` is at most `tolerance` away from the `key` color in RGB space
+fn matches_key(pixel: Rgb<u8>, key: Rgb<u8>, tolerance: u32) -> bool {
+    let distance = pixel
+        .0
+        .iter()
+        .zip(key.0)
+        .map(|(&channel, key)| (channel as i32 - key as i32).pow(2) as u32)
+        .sum::<u32>();
+
+    distance <= tolerance.saturating_mul(tolerance)
+}
+
+/// Coordinates of the pixel at `inner` on the line `outer`
+fn line_point(direction: WalkPath, outer: u32, inner: u32) -> (u32, u32) {
+    match direction {
+        WalkPath::Horizontal => (inner, outer),
+        WalkPath::Vertical => (outer, inner)
+    }
+}
+
+/// Map an index past the end of a line back onto it
+///
+/// Wraps around to the start of the line if `wrap` is set, clamps to the last pixel otherwise
+fn line_index(inner: u32, inner_limit: u32, wrap: bool) -> u32 {
+    if wrap {
+        inner % inner_limit
+    } else {
+        inner.min(inner_limit - 1)
+    }
+}
+
+/// Write the sorted blocks of a line back into the image, one pixel after the other
+fn write_line<P: Pixel>(
+    image: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+    direction: WalkPath,
+    outer: u32,
+    inner_limit: u32,
+    wrap: bool,
+    blocks: Vec<Vec<P>>
+) {
+    for (inner, pixel) in blocks.concat().into_iter().enumerate() {
+        let (x, y) = line_point(direction, outer, line_index(inner as u32, inner_limit, wrap));
+        image.put_pixel(x, y, pixel);
+    }
+}
+
+/// Progressive amount of the line `outer` out of `lines`, growing from `start` along `curve`
+///
+/// `Linear` grows by one every line, `Ease` and `Exp` end up at the same amount on the last line
+fn progressive_amount(curve: ProgressiveCurve, start: u64, outer: u32, lines: u32) -> u64 {
+    let steps = outer as u64 + 1;
+    let t = steps as f64 / lines.max(1) as f64;
+
+    start
+        + match curve {
+            ProgressiveCurve::Linear => steps,
+            ProgressiveCurve::Ease => (t * t * (3.0 - 2.0 * t) * lines as f64).round() as u64,
+            ProgressiveCurve::Exp => ((2f64.powf(10.0 * t) - 1.0) / 1023.0 * lines as f64).round() as u64
+        }
+}
+
+/// Number of lines one swell of [`IntervalSource::Wave`] takes if not given
+const DEFAULT_WAVE_PERIOD: u32 = 64;
+
+/// Builds the spans of every line of one image, the same way every time for the same seed
+struct SpanBuilder {
+    direction: WalkPath,
+    outer_limit: u32,
+    inner_limit: u32,
+    intervals: Vec<usize>,
+    source: IntervalSource,
+    wave_period: u32,
+    discretize: u32,
+    jitter: u32,
+    gap: u32,
+    min_span: u32,
+    wrap: bool,
+    progressive: Option<(u64, ProgressiveCurve)>,
+    seed: u64,
+    edges: Option<(EdgeMap, u64)>,
+    /// Image threshold of every line for `adaptive_threshold`
+    thresholds: Option<Vec<u8>>,
+    /// Lines the image starts at and has in total, if it is only a part of a larger one
+    first_line: u32,
+    lines: u32
+}
+
+impl SpanBuilder {
+    fn new<P: Pixel<Subpixel = u8>>(image: &ImageBuffer<P, Vec<u8>>, options: &SortOptions) -> Self {
+        let (width, height) = image.dimensions();
+        let (outer_limit, inner_limit) = match options.direction {
+            WalkPath::Horizontal => (height, width),
+            WalkPath::Vertical => (width, height)
+        };
+
+        if options.discretize > inner_limit as u64 {
+            log::warn!(
+                "discretize ({}) exceeds the line length ({}), clamping it",
+                options.discretize, inner_limit
+            );
+        }
+
+        let intervals = options
+            .interval
+            .iter()
+            .map(|&interval| match options.interval_unit {
+                IntervalUnit::Pixels => interval,
+                IntervalUnit::Percent => (interval * inner_limit as usize / 100).max(1)
+            })
+            .collect();
+
+        let edges = options.edge_threshold.map(|threshold| {
+            let gray = GrayImage::from_fn(width, height, |x, y| Luma([threshold_key(image.get_pixel(x, y), options)]));
+            (gradient_magnitude(&gray, options.edge_detector), threshold)
+        });
+
+        let thresholds = options.adaptive_threshold.then(|| {
+            (0..outer_limit)
+                .map(|outer| {
+                    let mut histogram = [0u64; 256];
+                    for inner in 0..inner_limit {
+                        let (x, y) = line_point(options.direction, outer, inner);
+                        if options.region.as_ref().map_or(true, |region| region.get_pixel(x, y).0[0] > 0) {
+                            histogram[threshold_key(image.get_pixel(x, y), options) as usize] += 1;
+                        }
+                    }
+
+                    otsu_threshold(&histogram)
+                })
+                .collect()
+        });
+
+        Self {
+            direction: options.direction,
+            outer_limit,
+            inner_limit,
+            intervals,
+            source: options.interval_source,
+            wave_period: options.wave_period.max(1),
+            discretize: options.discretize.min(inner_limit as u64) as u32,
+            jitter: options.interval_jitter,
+            gap: options.span_gap,
+            min_span: options.min_span,
+            wrap: options.wrap,
+            progressive: options
+                .progressive_amount
+                .filter(|&start| start != 1)
+                .map(|start| (start, options.progressive_curve)),
+            seed: options.seed.unwrap_or_else(|| thread_rng().gen()),
+            edges,
+            thresholds,
+            first_line: 0,
+            lines: outer_limit
+        }
+    }
+
+    /// The spans of the line `outer`, with the generator they were drawn from
+    ///
+    /// Every line gets its own generator seeded from the line number,
+    /// so a seeded sort comes out the same no matter which thread sorts which line
+    fn line(&self, outer: u32) -> (Vec<Range<u32>>, StdRng) {
+        let line = self.first_line + outer;
+        let mut rng = StdRng::seed_from_u64(self.seed ^ line as u64);
+
+        let prog_amount = match self.progressive {
+            Some((start, curve)) => progressive_amount(curve, start, line, self.lines),
+            None => 1
+        };
+
+        let intervals = match self.source {
+            IntervalSource::Value | IntervalSource::Edge => self
+                .intervals
+                .iter()
+                .map(|&interval| (rng.gen_range(1..=interval) + prog_amount as usize).min(interval))
+                .collect::<Vec<_>>(),
+            IntervalSource::Random => {
+                // draw lengths until they cover the line, the spans cycle through them otherwise
+                let mut covered = 0;
+                let mut lengths = Vec::new();
+
+                for &interval in self.intervals.iter().cycle() {
+                    if covered >= self.inner_limit as usize {
+                        break;
+                    }
+
+                    let length = rng.gen_range(1..=interval);
+                    covered += length;
+                    lengths.push(length);
+                }
+
+                lengths
+            }
+            IntervalSource::Wave => {
+                let phase = std::f64::consts::TAU * line as f64 / self.wave_period as f64;
+                let scale = 0.5 + 0.5 * phase.sin();
+
+                self.intervals
+                    .iter()
+                    .map(|&interval| ((interval as f64 * scale).round() as usize).max(1))
+                    .collect()
+            }
+        };
+
+        let mut line_spans = spans(self.inner_limit, &intervals, self.discretize, self.jitter, self.gap, &mut rng);
+
+        if let Some((magnitudes, threshold)) = &self.edges {
+            line_spans = split_at_edges(line_spans, |i| {
+                let (x, y) = line_point(self.direction, outer, line_index(i, self.inner_limit, self.wrap));
+                magnitudes.get_pixel(x, y).0[0] as u64 > *threshold
+            });
+        }
+
+        (line_spans, rng)
+    }
+
+    /// The image threshold of the line `outer`, its own one with `adaptive_threshold` or the global one otherwise
+    fn line_threshold(&self, outer: u32, image_threshold: Option<u8>) -> Option<u8> {
+        match &self.thresholds {
+            Some(thresholds) => Some(thresholds[outer as usize]),
+            None => image_threshold
+        }
+    }
+
+    /// Whether `span` is long enough to be sorted, shorter ones keep their pixels in place
+    fn sorts(&self, span: &Range<u32>) -> bool {
+        span.end - span.start >= self.min_span
+    }
+}
+
+/// Coordinates of the first pixel of every span the sort would build for `image`
+///
+/// Only matches the spans of the sort itself if `options` has a seed
+pub fn span_boundaries<P: Pixel<Subpixel = u8>>(image: &ImageBuffer<P, Vec<u8>>, options: &SortOptions) -> Vec<(u32, u32)> {
+    let builder = SpanBuilder::new(image, options);
+
+    (0..builder.outer_limit)
+        .flat_map(|outer| {
+            let (line_spans, _) = builder.line(outer);
+            line_spans
+                .into_iter()
+                .map(|span| {
+                    let inner = line_index(span.start, builder.inner_limit, builder.wrap);
+                    line_point(builder.direction, outer, inner)
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Images with fewer pixels than this are sorted serially,
+/// since setting up the threads would take longer than the sort itself
+const SERIAL_PIXEL_LIMIT: u64 = 64 * 1024;
+
+#[cfg(test)]
+thread_local! {
+    /// Replaces [`SERIAL_PIXEL_LIMIT`] for the sorts started on this thread
+    static SERIAL_PIXEL_LIMIT_OVERRIDE: std::cell::Cell<Option<u64>> = std::cell::Cell::new(None);
+}
 
+/// The number of pixels from which images are sorted in parallel
+#[cfg(not(test))]
+fn serial_pixel_limit() -> u64 {
+    SERIAL_PIXEL_LIMIT
+}
+
+/// The number of pixels from which images are sorted in parallel, unless a test lowered or raised it
+#[cfg(test)]
+fn serial_pixel_limit() -> u64 {
+    SERIAL_PIXEL_LIMIT_OVERRIDE.with(|limit| limit.get()).unwrap_or(SERIAL_PIXEL_LIMIT)
+}
+
+/// Run `sort` with `limit` in place of [`SERIAL_PIXEL_LIMIT`], to send any image down either path
+#[cfg(test)]
+fn with_serial_pixel_limit<R>(limit: u64, sort: impl FnOnce() -> R) -> R {
+    SERIAL_PIXEL_LIMIT_OVERRIDE.with(|cell| cell.set(Some(limit)));
+    let result = sort();
+    SERIAL_PIXEL_LIMIT_OVERRIDE.with(|cell| cell.set(None));
+    result
+}
+
+/// Sort the pixels of an image line by line by the key `sorter` extracts
+///
+/// `sorter` is called once for every pixel with its coordinates, before any pixel is moved.
+/// If `rows` is given the image is the rows starting at the first one out of the second
+/// of a larger image, sorted horizontally, and the spans and mask match those of the larger image
+fn pixel_sort<P, K>(
+    image: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+    mut options: SortOptions,
+    rows: Option<(u32, u32)>,
+    sorter: impl Fn(&P, (u32, u32), &SortOptions) -> K + Sync
+) -> SortReport
+where
+    P: Pixel<Subpixel = u8> + Send + Sync,
+    K: Ord + Copy + Into<f64> + Send + Sync
+{
     let progress_style = ProgressStyle::with_template(
         "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}"
     )
     .unwrap();
 
+    let started = Instant::now();
     let (width, height) = image.dimensions();
-    let (outer_limit, inner_limit) = match options.direction {
-        WalkPath::Horizontal => (height, width),
-        WalkPath::Vertical => (width, height)
+
+    if options.auto_threshold {
+        let mut histogram = [0u64; 256];
+        for (x, y, pixel) in image.enumerate_pixels() {
+            if options.region.as_ref().map_or(true, |region| region.get_pixel(x, y).0[0] > 0) {
+                histogram[threshold_key(pixel, &options) as usize] += 1;
+            }
+        }
+
+        let threshold = otsu_threshold(&histogram);
+        log::info!("--auto-threshold picked an image threshold of {}", threshold);
+        options.image_threshold = Some(threshold);
+    }
+
+    let mut builder = SpanBuilder::new(image, &options);
+    if let Some((first_row, height)) = rows {
+        builder.first_line = first_row;
+        builder.lines = height;
+    }
+    let (outer_limit, inner_limit, first_line) = (builder.outer_limit, builder.inner_limit, builder.first_line);
+
+    // a pixel gets sorted if it is inside of the region, passes the thresholds and the mask,
+    // combined by `mask_combine`, and the color key
+    let included = |(x, y): (u32, u32), pixel: &P, image_threshold: Option<u8>| {
+        if options.region.as_ref().map_or(false, |region| region.get_pixel(x, y).0[0] == 0) {
+            return false;
+        }
+
+        let threshold = image_threshold.map(|threshold| threshold_key(pixel, &options) >= threshold);
+        let channel = options
+            .channel_threshold
+            .map(|threshold| pixel.to_rgb().0[threshold.channel.index()] >= threshold.value);
+        let mask = options.mask.as_ref().map(|mask| mask.get_pixel(x, y).0[0] >= options.mask_threshold);
+
+        if let Some(key) = options.key_color {
+            if matches_key(pixel.to_rgb(), key, options.key_tolerance) != options.key_sort_only {
+                return false;
+            }
+        }
+
+        let mut gates = [threshold, channel, mask].into_iter().flatten().peekable();
+        if gates.peek().is_none() {
+            return true;
+        }
+
+        match options.mask_combine {
+            MaskCombine::And => gates.all(|pass| pass),
+            MaskCombine::Or => gates.any(|pass| pass)
+        }
     };
 
-    let interval = (1..=options.interval).collect::<Vec<_>>();
+    let parallel = width as u64 * height as u64 >= serial_pixel_limit();
 
-    let progressive_amount = options.progressive_amount.unwrap_or(1);
+    // lines are sorted in parallel, so the report is gathered in atomics
+    let spans_total = AtomicU64::new(0);
+    let span_pixels = AtomicU64::new(0);
+    let pixels_moved = AtomicU64::new(0);
 
-    let (tx, rx) = channel();
+    // blocks are sorted with a stable sort, so the serial and parallel paths produce the same image
+    let sort_line = |outer: u32| {
+        let (line_spans, mut rng) = builder.line(outer);
+        for span in &line_spans {
+            log::trace!("line {}: span {:?}", outer + first_line, span);
+        }
 
-    (0..outer_limit)
-        .into_par_iter()
-        .progress_with_style(progress_style.clone())
-        .for_each_with((tx, progressive_amount), |(tx, prog_amount), outer| {
-            if progressive_amount != 1 {
-                *prog_amount += 1;
-            }
-
-            let interval = (interval.choose(&mut thread_rng()).unwrap() + *prog_amount as usize)
-                .min(*interval.last().unwrap());
-
-            let mut pixels = (0..inner_limit)
-                .step_by(interval)
-                .map(|inner| {
-                    (inner..inner + options.discretize as u32)
-                        .into_par_iter()
-                        .map(|i| match options.direction {
-                            WalkPath::Horizontal => *image.get_pixel(i.min(inner_limit - 1), outer),
-                            WalkPath::Vertical => *image.get_pixel(outer, i.min(inner_limit - 1))
-                        })
-                        .collect::<Vec<_>>()
-                })
-                .collect::<Vec<_>>();
+        let image_threshold = builder.line_threshold(outer, options.image_threshold);
+        let read = |i: u32| {
+            let (x, y) = line_point(options.direction, outer, line_index(i, inner_limit, options.wrap));
+            let pixel = *image.get_pixel(x, y);
+            (included((x, y + first_line), &pixel, image_threshold), sorter(&pixel, (x, y), &options), pixel)
+        };
+
+        // the gap after a span is carried along with it unsorted, so it keeps its place.
+        // Spans split at an edge run into the next piece and have no gap of their own
+        let gaps = line_spans
+            .iter()
+            .enumerate()
+            .map(|(index, span)| {
+                let next = line_spans.get(index + 1).map_or(inner_limit, |next| next.start);
+                builder.gap.min(next.saturating_sub(span.end))
+            })
+            .collect::<Vec<_>>();
+
+        // spans are usually only a few pixels long, so gathering them in parallel
+        // inside the parallel line loop costs more than it saves unless asked for
+        let pixels = line_spans
+            .into_iter()
+            .zip(gaps)
+            .map(|(span, gap)| {
+                let sorted = if builder.sorts(&span) { span.end } else { span.start };
+                let read_gapped = |i: u32| {
+                    let (included, key, pixel) = read(i);
+                    (included && i < sorted, key, pixel)
+                };
+
+                if parallel && options.parallel_gather {
+                    (span.start..span.end + gap).into_par_iter().map(read_gapped).collect::<Vec<_>>()
+                } else {
+                    (span.start..span.end + gap).map(read_gapped).collect::<Vec<_>>()
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let block_seeds = pixels.iter().map(|_| rng.gen()).collect::<Vec<u64>>();
+        let unsorted = pixels.iter().flatten().map(|&(_, _, pixel)| pixel).collect::<Vec<_>>();
+
+        let sort_run = |block: &mut Vec<(K, P)>, block_seed: u64| {
+            let mut rng = StdRng::seed_from_u64(block_seed);
 
             if options.shuffle {
-                pixels.par_iter_mut().for_each(|block| {
-                    block.shuffle(&mut thread_rng());
-                });
+                block.shuffle(&mut rng);
             }
 
             if options.reverse {
-                pixels.par_iter_mut().for_each(|block| {
-                    block.reverse();
-                    block.par_sort_unstable_by_key(|pixel| sorter(pixel, &options));
-                    block.reverse();
-                });
+                block.reverse();
+            }
+
+            let mut sort = |pixels: &mut Vec<(K, P)>, descending: bool| {
+                if options.sort_strength < 1.0 {
+                    sort_keyed_weakly(pixels, descending, options.sort_strength, &mut rng);
+                } else {
+                    sort_keyed(pixels, descending, parallel);
+                }
+            };
+
+            if options.position_weight > 0.0 {
+                sort_position_weighted(block, options.position_weight);
             } else {
-                pixels.par_iter_mut().for_each(|block| {
-                    block.par_sort_unstable_by_key(|pixel| sorter(pixel, &options));
+                match options.split_order {
+                    Some(threshold) => {
+                        // darker pixels ascend and lighter ones descend after them, meeting at the threshold
+                        let (mut dark, mut light): (Vec<_>, Vec<_>) =
+                            block.drain(..).partition(|(_, pixel)| pixel.to_luma().0[0] <= threshold);
+
+                        sort(&mut dark, false);
+                        sort(&mut light, true);
+
+                        block.extend(dark);
+                        block.extend(light);
+                    }
+                    None => sort(block, false)
+                }
+            }
+
+            if options.reverse {
+                block.reverse();
+            }
+
+            if options.center_out {
+                center_out(block);
+            }
+        };
+
+        let sort_runs_of = |block: &mut Vec<(K, P)>, block_seed: u64| match options.max_run {
+            Some(max_run) => sort_runs(block, max_run, |rest| sort_run(rest, block_seed)),
+            None => sort_run(block, block_seed)
+        };
+
+        let sort_block = |block: &mut Vec<(K, P)>, block_seed: u64| match options.partial {
+            Some(fraction) => {
+                let length = ((block.len() as f32 * fraction).round() as usize).min(block.len());
+                let start = match options.partial_anchor {
+                    PartialAnchor::Start => 0,
+                    PartialAnchor::Random => StdRng::seed_from_u64(block_seed).gen_range(0..=block.len() - length)
+                };
+
+                sort_partial(block, start..start + length, |part| sort_runs_of(part, block_seed));
+            }
+            None => sort_runs_of(block, block_seed)
+        };
+
+        let mut blocks = if parallel {
+            pixels
+                .into_par_iter()
+                .zip(block_seeds)
+                .map(|(block, block_seed)| sort_included(block, |block| sort_block(block, block_seed)))
+                .collect::<Vec<_>>()
+        } else {
+            pixels
+                .into_iter()
+                .zip(block_seeds)
+                .map(|(block, block_seed)| sort_included(block, |block| sort_block(block, block_seed)))
+                .collect::<Vec<_>>()
+        };
+
+        reorder_spans(&mut blocks, options.reorder_spans);
+
+        let moved = blocks
+            .iter()
+            .flatten()
+            .zip(&unsorted)
+            .filter(|(sorted, unsorted)| sorted.channels() != unsorted.channels())
+            .count();
+
+        spans_total.fetch_add(blocks.len() as u64, Ordering::Relaxed);
+        span_pixels.fetch_add(unsorted.len() as u64, Ordering::Relaxed);
+        pixels_moved.fetch_add(moved as u64, Ordering::Relaxed);
+
+        blocks
+    };
+
+    let report = |elapsed: Duration| {
+        let spans_total = spans_total.load(Ordering::Relaxed);
+
+        SortReport {
+            spans_total,
+            pixels_moved: pixels_moved.load(Ordering::Relaxed),
+            mean_span_len: span_pixels.load(Ordering::Relaxed) as f64 / spans_total.max(1) as f64,
+            elapsed
+        }
+    };
+
+    if !parallel {
+        let lines = (0..outer_limit)
+            .map(|outer| (outer, sort_line(outer)))
+            .collect::<Vec<_>>();
+
+        for (outer, blocks) in lines {
+            write_line(image, options.direction, outer, inner_limit, options.wrap, blocks);
+        }
+
+        return report(started.elapsed());
+    }
+
+    let (tx, rx) = channel();
+
+    (0..outer_limit)
+        .into_par_iter()
+        .progress_with_style(progress_style.clone())
+        .for_each_with(tx, |tx, outer| {
+            tx.send((outer, sort_line(outer))).unwrap();
+        });
+
+    std::thread::scope(|s| {
+        s.spawn(move || {
+            rx.iter()
+                .collect_vec()
+                .into_iter()
+                .progress_with_style(progress_style.clone())
+                .for_each(|(outer, sorted_blocks)| {
+                    write_line(image, options.direction, outer, inner_limit, options.wrap, sorted_blocks);
                 });
+        });
+    });
+
+    report(started.elapsed())
+}
+
+/// Randomly reorder the whole lines of an image, rows or columns depending on `direction`
+///
+/// The pixels inside of the lines keep their order
+pub fn shuffle_lines<P: Pixel>(image: &mut ImageBuffer<P, Vec<P::Subpixel>>, direction: WalkPath, seed: Option<u64>) {
+    let (width, height) = image.dimensions();
+    let (outer_limit, inner_limit) = match direction {
+        WalkPath::Horizontal => (height, width),
+        WalkPath::Vertical => (width, height)
+    };
+
+    let mut order = (0..outer_limit).collect::<Vec<_>>();
+    order.shuffle(&mut StdRng::seed_from_u64(seed.unwrap_or_else(|| thread_rng().gen())));
+
+    let source = image.clone();
+
+    for (outer, &from) in order.iter().enumerate() {
+        for inner in 0..inner_limit {
+            let (x, y) = line_point(direction, from, inner);
+            let (to_x, to_y) = line_point(direction, outer as u32, inner);
+            image.put_pixel(to_x, to_y, *source.get_pixel(x, y));
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Convert an index along a Hilbert curve filling an `order` x `order` square into coordinates
+///
+/// `order` has to be a power of two
+fn hilbert_point(order: u64, index: u64) -> (u64, u64) {
+    let (mut x, mut y) = (0, 0);
+    let mut t = index;
+    let mut s = 1;
+
+    while s < order {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
             }
+            std::mem::swap(&mut x, &mut y);
+        }
+
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+
+    (x, y)
+}
+
+/// Coordinates of every pixel of a `width` x `height` image in the order the curve visits them
+///
+/// The Hilbert curve is laid over the smallest power of two square covering the image,
+/// points outside of the image are skipped
+fn curve_points(curve: Curve, width: u32, height: u32) -> Vec<(u32, u32)> {
+    match curve {
+        Curve::Snake => (0..height)
+            .flat_map(|y| {
+                (0..width).map(move |x| match y % 2 {
+                    0 => (x, y),
+                    _ => (width - 1 - x, y)
+                })
+            })
+            .collect(),
+        Curve::Hilbert => {
+            let order = (width.max(height) as u64).next_power_of_two();
+            (0..order * order)
+                .map(|index| hilbert_point(order, index))
+                .filter(|&(x, y)| x < width as u64 && y < height as u64)
+                .map(|(x, y)| (x as u32, y as u32))
+                .collect()
+        }
+    }
+}
+
+/// Sort the pixels of an image as one sequence along a space-filling curve
+///
+/// The sorted pixels are written back along the same curve
+fn curve_sort<P, K>(
+    image: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+    curve: Curve,
+    options: SortOptions,
+    sorter: impl Fn(&P, &SortOptions) -> K + Sync
+) -> SortReport
+where
+    P: Pixel + Send + Sync,
+    K: Ord + Send
+{
+    let started = Instant::now();
+    let (width, height) = image.dimensions();
+    let points = curve_points(curve, width, height);
+
+    let mut pixels = points
+        .iter()
+        .map(|&(x, y)| *image.get_pixel(x, y))
+        .collect::<Vec<_>>();
+
+    pixels.par_sort_unstable_by_key(|pixel| sorter(pixel, &options));
+
+    if options.reverse {
+        pixels.reverse();
+    }
+
+    let mut pixels_moved = 0;
+
+    for (&(x, y), pixel) in points.iter().zip(pixels) {
+        if image.get_pixel(x, y).channels() != pixel.channels() {
+            pixels_moved += 1;
+        }
+
+        image.put_pixel(x, y, pixel);
+    }
+
+    // the whole curve is a single span
+    SortReport {
+        spans_total: 1,
+        pixels_moved,
+        mean_span_len: points.len() as f64,
+        elapsed: started.elapsed()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Map every pixel of an `RGB8` image to the key it would be sorted by
+///
+/// Visualizes what the sort "sees"
+pub fn rgb8_key_map(image: &RgbImage, options: &SortOptions) -> GrayImage {
+    if let SortingAlgorithm::Texture = options.by {
+        return texture_key_map(image);
+    }
+
+    let mut options = options.clone();
+    resolve_dominant_colors(image.pixels().copied(), &mut options);
+    let options = &options;
+
+    let sorter = options.by.into_rgb_sorter();
+    let (width, height) = image.dimensions();
 
-            tx.send((outer, pixels)).unwrap();
-        });
+    GrayImage::from_fn(width, height, |x, y| Luma([sorter(image.get_pixel(x, y), options)]))
+}
 
-    match options.direction {
-        WalkPath::Horizontal => {
-            std::thread::scope(|s| {
-                s.spawn(move || {
-                    rx.iter()
-                        .collect_vec()
-                        .into_iter()
-                        .progress_with_style(progress_style.clone())
-                        .for_each(|(y, sorted_blocks)| {
-                            let sorted = sorted_blocks.concat();
-                            for (x, pixel) in sorted.into_iter().enumerate() {
-                                let pixel_x = (x as u32).min(inner_limit - 1);
-                                let pixel_y = y;
-                                image.put_pixel(pixel_x, pixel_y, pixel);
-                            }
-                        });
-                });
-            });
-        }
-        WalkPath::Vertical => {
-            std::thread::scope(|s| {
-                s.spawn(move || {
-                    rx.iter()
-                        .collect_vec()
-                        .into_iter()
-                        .progress_with_style(progress_style.clone())
-                        .for_each(|(y, sorted_blocks)| {
-                            let sorted = sorted_blocks.concat();
-                            for (x, pixel) in sorted.into_iter().enumerate() {
-                                let pixel_x = y;
-                                let pixel_y = (x as u32).min(inner_limit - 1);
-                                image.put_pixel(pixel_x, pixel_y, pixel);
-                            }
-                        });
-                });
-            });
-        }
-    }
+/// Map every pixel to its key for `--by texture`, the deviation of the luma around it
+pub fn texture_key_map<P: Pixel<Subpixel = u8>>(image: &ImageBuffer<P, Vec<u8>>) -> GrayImage {
+    let (width, height) = image.dimensions();
+    let luma = GrayImage::from_fn(width, height, |x, y| image.get_pixel(x, y).to_luma());
+
+    local_deviation(&luma)
 }
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////////
@@ -123,38 +1036,122 @@ fn rgb8_pixel_sort(image: &mut RgbImage, options: SortOptions) {
 #[derive(Debug, Clone)]
 pub struct SortOptions {
     pub by: SortingAlgorithm,
-    pub interval: usize,
+    pub then: Option<SortingAlgorithm>,
+    pub interval: Vec<usize>,
+    pub interval_unit: IntervalUnit,
+    pub channel_intervals: Option<[usize; 3]>,
+    pub interval_jitter: u32,
+    pub span_gap: u32,
+    pub min_span: u32,
+    pub interval_source: IntervalSource,
+    pub wave_period: u32,
+    pub seed: Option<u64>,
     pub reverse: bool,
+    pub center_out: bool,
     pub coefficients: Coefficients,
     pub discretize: u64,
+    pub wrap: bool,
     pub progressive_amount: Option<u64>,
+    pub progressive_curve: ProgressiveCurve,
     pub direction: WalkPath,
+    pub curve: Option<Curve>,
+    pub diagonals: Option<Diagonals>,
     pub splice: Option<f64>,
     pub edge_threshold: Option<u64>,
-    pub image_threshold: Option<u64>,
+    pub edge_detector: EdgeDetector,
+    pub image_threshold: Option<u8>,
+    pub auto_threshold: bool,
+    pub adaptive_threshold: bool,
+    pub threshold_metric: Option<SortingAlgorithm>,
     pub image_mask: Option<PathBuf>,
+    pub mask: Option<GrayImage>,
+    pub region: Option<GrayImage>,
+    pub mask_threshold: u8,
+    pub channel_threshold: Option<ChannelThreshold>,
+    pub mask_combine: MaskCombine,
+    pub key_color: Option<Rgb<u8>>,
+    pub key_tolerance: u32,
+    pub key_sort_only: bool,
     pub channel: Option<ColorChannel>,
-    pub animate: Option<AnimateParams>,
-    pub shuffle: bool
+    pub animate: Vec<AnimateParams>,
+    pub shuffle: bool,
+    pub parallel_gather: bool,
+    pub target: Option<Rgb<u8>>,
+    pub k: usize,
+    pub dominant_colors: Option<Vec<Rgb<u8>>>,
+    pub hue_origin: f32,
+    pub sort_alpha: Option<AlphaOrder>,
+    pub premultiply: bool,
+    pub split_order: Option<u8>,
+    pub position_weight: f32,
+    pub sort_strength: f32,
+    pub key_quantize: Option<u16>,
+    pub max_run: Option<usize>,
+    pub partial: Option<f32>,
+    pub partial_anchor: PartialAnchor,
+    pub reorder_spans: SpanOrder,
+    pub sort_key_cache: bool
 }
 
 impl From<Cli> for SortOptions {
     fn from(value: Cli) -> Self {
         Self {
+            coefficients: (&value).into(),
             interval: value.interval,
+            interval_unit: value.interval_unit,
+            channel_intervals: value.channel_intervals,
+            interval_jitter: value.interval_jitter,
+            span_gap: value.span_gap,
+            min_span: value.min_span,
+            interval_source: value.interval_source.unwrap_or_default(),
+            wave_period: value.wave_period.unwrap_or(DEFAULT_WAVE_PERIOD),
+            seed: value.seed,
             by: value.by,
+            then: value.then,
             reverse: value.reverse,
-            coefficients: (&value).into(),
+            center_out: value.center_out,
             discretize: value.discretize,
+            wrap: value.wrap,
             progressive_amount: value.progressive_amount,
+            progressive_curve: value.progressive_curve,
             direction: value.direction,
+            curve: value.curve,
+            diagonals: value.diagonals,
             splice: value.splice,
             edge_threshold: value.edge_threshold,
+            edge_detector: value.edge_detector,
             image_threshold: value.image_threshold,
+            auto_threshold: value.auto_threshold,
+            adaptive_threshold: value.adaptive_threshold,
+            threshold_metric: value.threshold_metric,
             image_mask: value.image_mask,
+            mask: None,
+            region: None,
+            mask_threshold: value.mask_threshold,
+            channel_threshold: value.channel_threshold,
+            mask_combine: value.mask_combine,
+            key_color: value.key_color,
+            key_tolerance: value.key_tolerance,
+            key_sort_only: value.key_sort_only,
             channel: value.channel,
             animate: value.animate,
-            shuffle: value.shuffle
+            shuffle: value.shuffle,
+            parallel_gather: value.parallel_gather,
+            target: value.target,
+            k: value.k,
+            dominant_colors: None,
+            hue_origin: value.hue_origin,
+            sort_alpha: value.sort_alpha,
+            premultiply: value.premultiply,
+            split_order: value.split_order,
+            position_weight: value.position_weight,
+            sort_strength: value.sort_strength,
+            key_quantize: value.key_quantize,
+            max_run: value.max_run,
+            partial: value.partial,
+            partial_anchor: value.partial_anchor,
+            reorder_spans: value.reorder_spans,
+            sort_key_cache: value.sort_key_cache
         }
     }
 }
@@ -162,35 +1159,889 @@ impl From<Cli> for SortOptions {
 impl From<&Cli> for SortOptions {
     fn from(value: &Cli) -> Self {
         Self {
-            interval: value.interval,
+            interval: value.interval.clone(),
+            interval_unit: value.interval_unit,
+            channel_intervals: value.channel_intervals,
+            interval_jitter: value.interval_jitter,
+            span_gap: value.span_gap,
+            min_span: value.min_span,
+            interval_source: value.interval_source.unwrap_or_default(),
+            wave_period: value.wave_period.unwrap_or(DEFAULT_WAVE_PERIOD),
+            seed: value.seed,
             by: value.by,
+            then: value.then,
             reverse: value.reverse,
+            center_out: value.center_out,
             discretize: value.discretize,
+            wrap: value.wrap,
             progressive_amount: value.progressive_amount,
+            progressive_curve: value.progressive_curve,
             coefficients: value.into(),
             direction: value.direction,
+            curve: value.curve,
+            diagonals: value.diagonals,
             splice: value.splice,
             edge_threshold: value.edge_threshold,
+            edge_detector: value.edge_detector,
             image_threshold: value.image_threshold,
+            auto_threshold: value.auto_threshold,
+            adaptive_threshold: value.adaptive_threshold,
+            threshold_metric: value.threshold_metric,
             image_mask: value.image_mask.clone(),
+            mask: None,
+            region: None,
+            mask_threshold: value.mask_threshold,
+            channel_threshold: value.channel_threshold,
+            mask_combine: value.mask_combine,
+            key_color: value.key_color,
+            key_tolerance: value.key_tolerance,
+            key_sort_only: value.key_sort_only,
             channel: value.channel,
             animate: value.animate.clone(),
-            shuffle: value.shuffle
+            shuffle: value.shuffle,
+            parallel_gather: value.parallel_gather,
+            target: value.target,
+            k: value.k,
+            dominant_colors: None,
+            hue_origin: value.hue_origin,
+            sort_alpha: value.sort_alpha,
+            premultiply: value.premultiply,
+            split_order: value.split_order,
+            position_weight: value.position_weight,
+            sort_strength: value.sort_strength,
+            key_quantize: value.key_quantize,
+            max_run: value.max_run,
+            partial: value.partial,
+            partial_anchor: value.partial_anchor,
+            reorder_spans: value.reorder_spans,
+            sort_key_cache: value.sort_key_cache
         }
     }
 }
 
+/// The options of the command line `pxsort ARGS in.png out.png`, where ARGS starts with the extractor
+#[cfg(test)]
+pub(crate) fn test_options(args: &[&str]) -> SortOptions {
+    use clap::Parser;
+
+    let cli = Cli::parse_from(["pxsort"].into_iter().chain(args.iter().copied()).chain(["in.png", "out.png"]));
+    (&cli).into()
+}
+
 ////////////////////////////////////////////////////////////////////////////////////////////////////////
 
+/// Sort the red, green and blue channels of `image` independently, each with its own interval
+///
+/// Every channel is sorted by its own value, so the channels of a pixel drift apart.
+/// All of them share the seed, so equal intervals build equal spans. Alpha stays in place
+fn sort_channels<P: Pixel<Subpixel = u8>>(
+    image: &mut ImageBuffer<P, Vec<u8>>,
+    options: SortOptions,
+    intervals: [usize; 3]
+) -> SortReport {
+    let (width, height) = image.dimensions();
+    let mut report = SortReport::default();
+    let mut span_pixels = 0.0;
+
+    for (channel, interval) in intervals.into_iter().enumerate() {
+        let values = GrayImage::from_fn(width, height, |x, y| Luma([image.get_pixel(x, y).channels()[channel]]));
+        let mut gray = RgbImage::from_fn(width, height, |x, y| Rgb([values.get_pixel(x, y).0[0]; 3]));
+
+        let mut options = options.clone();
+        options.interval = vec![interval];
+
+        let channel_report = rgb8_pixel_sort(&mut gray, options, Some(&values));
+        report.spans_total += channel_report.spans_total;
+        report.pixels_moved += channel_report.pixels_moved;
+        report.elapsed += channel_report.elapsed;
+        span_pixels += channel_report.mean_span_len * channel_report.spans_total as f64;
+
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            pixel.channels_mut()[channel] = gray.get_pixel(x, y).0[0];
+        }
+    }
+
+    report.mean_span_len = span_pixels / report.spans_total.max(1) as f64;
+    report
+}
+
+/// Statistics about a single sort
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SortReport {
+    /// Number of spans that were sorted
+    pub spans_total: u64,
+    /// Number of pixels that ended up somewhere else than they were
+    pub pixels_moved: u64,
+    /// Average number of pixels in a span
+    pub mean_span_len: f64,
+    /// Time the sort took
+    pub elapsed: Duration
+}
+
+/// Key map of `image` extracted on the GPU, if it is large enough to be worth it
+#[cfg(feature = "gpu")]
+fn gpu_keys(image: &RgbImage, options: &SortOptions) -> Option<GrayImage> {
+    // the shader has no dominant color extractor
+    let supported = !matches!(options.by, SortingAlgorithm::Dominant);
+
+    (supported && image.width() as u64 * image.height() as u64 >= crate::gpu::GPU_PIXEL_LIMIT)
+        .then(|| crate::gpu::rgb8_key_map_gpu(image, options))
+}
+
+/// Without the `gpu` feature the keys are always extracted on the CPU
+#[cfg(not(feature = "gpu"))]
+fn gpu_keys(_image: &RgbImage, _options: &SortOptions) -> Option<GrayImage> {
+    None
+}
+
+/// Cluster the image into its dominant colors if they are needed for the keys and haven't been found yet
+fn resolve_dominant_colors(pixels: impl ExactSizeIterator<Item = Rgb<u8>>, options: &mut SortOptions) {
+    let needed = [Some(options.by), options.then, options.threshold_metric]
+        .iter()
+        .any(|by| matches!(by, Some(SortingAlgorithm::Dominant)));
+
+    if needed && options.dominant_colors.is_none() {
+        options.dominant_colors = Some(dominant_colors(pixels, options.k, options.seed.unwrap_or(0)));
+    }
+}
+
+/// Sort along the diagonals of `image` instead of its lines, one family after the other for [`Diagonals::Both`]
+///
+/// Every diagonal is sheared into a row of a taller image, which is sorted horizontally.
+/// The corners of that image belong to no diagonal and are kept out of the sort by `region`
+fn sort_diagonals<P>(image: &mut ImageBuffer<P, Vec<u8>>, mut options: SortOptions, diagonals: Diagonals) -> SortReport
+where
+    P: Pixel<Subpixel = u8>,
+    ImageBuffer<P, Vec<u8>>: PixelSort
+{
+    let (width, height) = image.dimensions();
+    let families: &[bool] = match diagonals {
+        Diagonals::Down => &[true],
+        Diagonals::Up => &[false],
+        Diagonals::Both => &[true, false]
+    };
+
+    options.direction = WalkPath::Horizontal;
+    let mut report = SortReport::default();
+    let mut span_pixels = 0.0;
+
+    for &down in families {
+        let row = |x: u32, y: u32| if down { y + width - 1 - x } else { x + y };
+        let sheared_height = (width + height).saturating_sub(1);
+
+        let mut sheared = ImageBuffer::<P, Vec<u8>>::new(width, sheared_height);
+        let mut region = GrayImage::new(width, sheared_height);
+        let mut mask = options.mask.as_ref().map(|_| GrayImage::new(width, sheared_height));
+
+        for (x, y, pixel) in image.enumerate_pixels() {
+            sheared.put_pixel(x, row(x, y), *pixel);
+            region.put_pixel(x, row(x, y), Luma([u8::MAX]));
+
+            if let (Some(sheared_mask), Some(mask)) = (&mut mask, &options.mask) {
+                sheared_mask.put_pixel(x, row(x, y), *mask.get_pixel(x, y));
+            }
+        }
+
+        let mut pass = options.clone();
+        pass.mask = mask;
+        pass.region = Some(region);
+
+        let pass_report = sheared.sort_rgb8_pixels_reported(pass);
+        report.spans_total += pass_report.spans_total;
+        report.pixels_moved += pass_report.pixels_moved;
+        report.elapsed += pass_report.elapsed;
+        span_pixels += pass_report.mean_span_len * pass_report.spans_total as f64;
+
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            *pixel = *sheared.get_pixel(x, row(x, y));
+        }
+    }
+
+    report.mean_span_len = span_pixels / report.spans_total.max(1) as f64;
+    report
+}
+
 /// Extension trait for an `RgbImage` to provide pixel sorting functionality
 pub trait PixelSort {
     /// Sort the pixels by a key extraction function with options
-    fn sort_rgb8_pixels(&mut self, options: SortOptions);
+    fn sort_rgb8_pixels(&mut self, options: SortOptions) {
+        self.sort_rgb8_pixels_reported(options);
+    }
+
+    /// Sort the pixels like [`PixelSort::sort_rgb8_pixels`], reporting statistics about the sort
+    fn sort_rgb8_pixels_reported(&mut self, options: SortOptions) -> SortReport;
 }
 
 impl PixelSort for ImageBuffer<Rgb<u8>, Vec<u8>> {
     /// Sort the pixels by a key extraction function with options
-    fn sort_rgb8_pixels(&mut self, options: SortOptions) {
-        rgb8_pixel_sort(self, options);
+    fn sort_rgb8_pixels_reported(&mut self, mut options: SortOptions) -> SortReport {
+        resolve_dominant_colors(self.pixels().copied(), &mut options);
+
+        if let Some(diagonals) = options.diagonals.take() {
+            return sort_diagonals(self, options, diagonals);
+        }
+
+        if let Some(intervals) = options.channel_intervals {
+            return sort_channels(self, options, intervals);
+        }
+
+        match options.curve {
+            Some(curve) => {
+                let sorter = options.by.into_rgb_sorter();
+                curve_sort(self, curve, options, move |pixel, options| {
+                    then_key(quantize_key(sorter(pixel, options), options.key_quantize), pixel, options)
+                })
+            }
+            None => {
+                let keys = match options.by {
+                    SortingAlgorithm::Texture => Some(texture_key_map(self)),
+                    _ => gpu_keys(self, &options)
+                };
+                rgb8_pixel_sort(self, options, keys.as_ref())
+            }
+        }
+    }
+}
+
+/// Scale the color of every pixel by its alpha
+fn premultiply(image: &mut RgbaImage) {
+    for Rgba([red, green, blue, alpha]) in image.pixels_mut() {
+        for channel in [red, green, blue] {
+            *channel = ((*channel as u32 * *alpha as u32 + 127) / 255) as u8;
+        }
+    }
+}
+
+/// Undo [`premultiply`], fully transparent pixels stay black
+fn unpremultiply(image: &mut RgbaImage) {
+    for Rgba([red, green, blue, alpha]) in image.pixels_mut() {
+        if *alpha == 0 {
+            continue;
+        }
+
+        for channel in [red, green, blue] {
+            *channel = ((*channel as u32 * 255 + *alpha as u32 / 2) / *alpha as u32).min(255) as u8;
+        }
+    }
+}
+
+impl PixelSort for ImageBuffer<Rgba<u8>, Vec<u8>> {
+    /// Sort the pixels by a key extraction function with options, carrying the alpha channel along
+    fn sort_rgb8_pixels_reported(&mut self, mut options: SortOptions) -> SortReport {
+        resolve_dominant_colors(self.pixels().map(Pixel::to_rgb), &mut options);
+
+        if let Some(diagonals) = options.diagonals.take() {
+            return sort_diagonals(self, options, diagonals);
+        }
+
+        let premultiplied = options.premultiply;
+        if premultiplied {
+            premultiply(self);
+        }
+
+        let report = if let Some(intervals) = options.channel_intervals {
+            sort_channels(self, options, intervals)
+        } else {
+            match options.curve {
+                Some(curve) => {
+                    let sorter = rgba8_sorter(options.by);
+                    curve_sort(self, curve, options, sorter)
+                }
+                None => rgba8_pixel_sort(self, options)
+            }
+        };
+
+        if premultiplied {
+            unpremultiply(self);
+        }
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    /// An image with a different color in every pixel, `width` by `height` pixels
+    fn noise(width: u32, height: u32) -> RgbImage {
+        let mut rng = StdRng::seed_from_u64(1);
+        RgbImage::from_fn(width, height, |_, _| Rgb(rng.gen()))
+    }
+
+    /// A one row tall image of gray pixels with the `values`
+    fn gray_line(values: &[u8]) -> RgbImage {
+        RgbImage::from_fn(values.len() as u32, 1, |x, _| Rgb([values[x as usize]; 3]))
+    }
+
+    /// The gray values of the first row of `image`
+    fn gray_values(image: &RgbImage) -> Vec<u8> {
+        (0..image.width()).map(|x| image.get_pixel(x, 0).0[0]).collect()
+    }
+
+    /// Sort `image` by the options of `args` once on the serial path and once on the parallel one
+    fn sort_both_ways(image: &RgbImage, args: &[&str]) -> (RgbImage, RgbImage) {
+        let options = test_options(args);
+
+        let mut serial = image.clone();
+        with_serial_pixel_limit(u64::MAX, || rgb8_pixel_sort(&mut serial, options.clone(), None));
+
+        let mut parallel = image.clone();
+        with_serial_pixel_limit(0, || rgb8_pixel_sort(&mut parallel, options, None));
+
+        (serial, parallel)
+    }
+
+    #[test]
+    fn serial_and_parallel_sorts_give_the_same_image() {
+        let image = noise(64, 48);
+        let command_lines: &[&[&str]] = &[
+            &["luma", "-i", "8", "-d", "8", "--seed", "1"],
+            &["hue", "-i", "7,3", "-d", "4", "--seed", "2"],
+            &["saturation", "-i", "9", "-d", "5", "-t", "v", "--seed", "3", "--parallel-gather"],
+            &["brightness", "-i", "11", "-d", "11", "--interval-jitter", "3", "--shuffle", "--seed", "4"],
+            &["chroma", "-i", "6", "-d", "6", "--sort-strength", "0.5", "--reorder-spans", "length", "--seed", "5"]
+        ];
+
+        for args in command_lines {
+            let (serial, parallel) = sort_both_ways(&image, args);
+            assert_ne!(serial, image, "{:?} didn't sort", args);
+            assert_eq!(serial, parallel, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn discretize_of_the_line_length_sorts_every_line_as_one_block() {
+        let image = noise(24, 5);
+
+        // larger blocks are clamped to the line length, and a large progressive amount
+        // makes every line take the whole interval instead of a random part of it
+        for discretize in ["24", "100"] {
+            let options = test_options(&["luma", "-i", "24", "-d", discretize, "-p", "1000", "--seed", "1"]);
+            let mut sorted = image.clone();
+            let report = rgb8_pixel_sort(&mut sorted, options.clone(), None);
+
+            assert_eq!(report.spans_total, 5);
+            let key = options.by.into_rgb_sorter();
+
+            for y in 0..5 {
+                let row = |image: &RgbImage| (0..24).map(|x| *image.get_pixel(x, y)).collect::<Vec<_>>();
+                let (before, after) = (row(&image), row(&sorted));
+
+                assert!(after.windows(2).all(|pair| key(&pair[0], &options) <= key(&pair[1], &options)));
+                assert_eq!(before.iter().map(|pixel| pixel.0).sorted().collect_vec(), after.iter().map(|pixel| pixel.0).sorted().collect_vec());
+            }
+        }
+    }
+
+    #[test]
+    fn snake_curves_sort_a_square_along_its_rows_back_and_forth() {
+        assert_eq!(curve_points(Curve::Snake, 2, 2), vec![(0, 0), (1, 0), (1, 1), (0, 1)]);
+
+        let gray = |value| Rgb([value; 3]);
+        let mut image = RgbImage::from_vec(2, 2, [40, 10, 30, 20].into_iter().flat_map(|value| [value; 3]).collect()).unwrap();
+        image.sort_rgb8_pixels(test_options(&["luma", "--curve", "snake"]));
+
+        // the second row runs right to left, so its larger value ends up on the left
+        assert_eq!(image.pixels().copied().collect_vec(), vec![gray(10), gray(20), gray(40), gray(30)]);
+    }
+
+    #[test]
+    fn mask_thresholds_of_0_and_255_sort_everything_and_only_white() {
+        let image = noise(20, 6);
+        let options = test_options(&["luma", "-i", "20", "-d", "20", "-p", "1000", "--seed", "1"]);
+        // white on the left, gray on the right
+        let mask = GrayImage::from_fn(20, 6, |x, _| Luma([if x < 10 { 255 } else { 100 }]));
+
+        let sort = |threshold: Option<u8>| {
+            let mut options = options.clone();
+            if let Some(threshold) = threshold {
+                options.mask = Some(mask.clone());
+                options.mask_threshold = threshold;
+            }
+
+            let mut sorted = image.clone();
+            rgb8_pixel_sort(&mut sorted, options, None);
+            sorted
+        };
+
+        assert_eq!(sort(Some(0)), sort(None));
+
+        let white = sort(Some(255));
+        let key = options.by.into_rgb_sorter();
+        for y in 0..6 {
+            let row = |image: &RgbImage, columns: Range<u32>| columns.map(|x| *image.get_pixel(x, y)).collect_vec();
+
+            assert_eq!(row(&white, 10..20), row(&image, 10..20));
+            assert!(row(&white, 0..10).windows(2).all(|pair| key(&pair[0], &options) <= key(&pair[1], &options)));
+        }
+        assert_ne!(white, image);
+    }
+
+    #[test]
+    fn premultiplying_leaves_opaque_images_alone() {
+        let colors = noise(24, 8);
+        let image = RgbaImage::from_fn(24, 8, |x, y| colors.get_pixel(x, y).to_rgba());
+
+        let sort = |args: &[&str]| {
+            let mut sorted = image.clone();
+            sorted.sort_rgb8_pixels(test_options(args));
+            sorted
+        };
+
+        let premultiplied = sort(&["hue", "-i", "6", "-d", "6", "--seed", "1", "--premultiply"]);
+        assert_eq!(premultiplied, sort(&["hue", "-i", "6", "-d", "6", "--seed", "1"]));
+        assert_ne!(premultiplied, image);
+    }
+
+    #[test]
+    fn premultiplying_keeps_the_color_of_translucent_pixels() {
+        for alpha in [128, 200, 254] {
+            for value in 0..=255 {
+                let mut image = RgbaImage::from_pixel(1, 1, Rgba([value, 255 - value, value / 2, alpha]));
+                let original = image.clone();
+
+                premultiply(&mut image);
+                unpremultiply(&mut image);
+
+                let (Rgba(before), Rgba(after)) = (original.get_pixel(0, 0), image.get_pixel(0, 0));
+                assert!(before.iter().zip(after).all(|(before, after)| before.abs_diff(*after) <= 1));
+                assert_eq!(after[3], alpha);
+            }
+        }
+    }
+
+    #[test]
+    fn both_diagonals_leave_a_solid_image_alone() {
+        for (width, height) in [(1, 1), (7, 3), (4, 9)] {
+            let solid = RgbImage::from_pixel(width, height, Rgb([200, 40, 90]));
+            let mut image = solid.clone();
+
+            let report = image.sort_rgb8_pixels_reported(test_options(&["luma", "-i", "3", "-d", "5", "--diagonals", "both"]));
+
+            assert_eq!(image, solid);
+            assert_eq!(report.pixels_moved, 0);
+        }
+    }
+
+    #[test]
+    fn wrapping_merges_the_tail_span_of_a_short_line_with_its_head() {
+        let gray = |value| Rgb([value; 3]);
+        let image = RgbImage::from_vec(6, 1, [50, 10, 40, 30, 60, 20].into_iter().flat_map(|value| [value; 3]).collect()).unwrap();
+        let sort = |args: &[&str]| {
+            let mut sorted = image.clone();
+            sorted.sort_rgb8_pixels(test_options(args));
+            sorted.pixels().copied().collect_vec()
+        };
+
+        // the span at 4 reads 60, 20 and wraps around to 50, 10, then writes them back across the edge
+        let wrapped = sort(&["luma", "-i", "4", "-d", "4", "-p", "1000", "--wrap"]);
+        assert_eq!([wrapped[4], wrapped[5], wrapped[0], wrapped[1]], [gray(10), gray(20), gray(50), gray(60)]);
+        assert_eq!(wrapped, [50, 60, 40, 50, 10, 20].map(gray));
+
+        assert_ne!(sort(&["luma", "-i", "4", "-d", "4", "-p", "1000"]), wrapped);
+    }
+
+    #[test]
+    fn mask_combine_joins_the_threshold_and_the_mask() {
+        // every pair descends, the first two pairs pass the mask and the first of every two pairs the threshold
+        let image = gray_line(&[250, 220, 100, 80, 240, 210, 90, 60]);
+        let mask = GrayImage::from_fn(8, 1, |x, _| Luma([if x < 4 { 255 } else { 0 }]));
+
+        let sort = |combine: &str| {
+            let mut options = test_options(&["luma", "-i", "8", "-d", "8", "-p", "1000", "--image-threshold", "128", "--mask-combine", combine]);
+            options.mask = Some(mask.clone());
+            options.mask_threshold = 128;
+
+            let mut sorted = image.clone();
+            rgb8_pixel_sort(&mut sorted, options, None);
+            gray_values(&sorted)
+        };
+
+        // only the pair passing both is sorted with and, every pair but the one passing neither with or
+        assert_eq!(sort("and"), [220, 250, 100, 80, 240, 210, 90, 60]);
+        assert_eq!(sort("or"), [80, 100, 210, 220, 240, 250, 90, 60]);
+    }
+
+    #[test]
+    fn equal_channel_intervals_sort_every_channel_like_a_uniform_interval() {
+        let image = noise(30, 8);
+        let mut independent = image.clone();
+        independent.sort_rgb8_pixels(test_options(&["luma", "-f", "red=1", "--channel-intervals", "6,6,6", "-d", "6", "--seed", "3"]));
+
+        // a key of exactly the red value, so every channel sorts by its own value
+        let uniform = test_options(&["luma", "-f", "red=1", "-i", "6", "-d", "6", "--seed", "3"]);
+        for channel in 0..3 {
+            let mut gray = RgbImage::from_fn(30, 8, |x, y| Rgb([image.get_pixel(x, y).0[channel]; 3]));
+            gray.sort_rgb8_pixels(uniform.clone());
+
+            assert!(independent.enumerate_pixels().all(|(x, y, pixel)| pixel.0[channel] == gray.get_pixel(x, y).0[0]));
+        }
+
+        // equal spans in every channel keep gray pixels gray
+        let mut gray = RgbImage::from_fn(30, 8, |x, y| Rgb([image.get_pixel(x, y).0[0]; 3]));
+        gray.sort_rgb8_pixels(test_options(&["luma", "--channel-intervals", "6,6,6", "-d", "6", "--seed", "3"]));
+        assert!(gray.pixels().all(|Rgb([red, green, blue])| red == green && green == blue));
+        assert_ne!(independent, image);
+    }
+
+    #[test]
+    fn row_shuffle_is_a_permutation_of_the_original_rows() {
+        let image = noise(6, 32);
+        let rows = |image: &RgbImage| image.rows().map(|row| row.map(|pixel| pixel.0).collect_vec()).collect_vec();
+
+        let mut shuffled = image.clone();
+        shuffle_lines(&mut shuffled, WalkPath::Horizontal, Some(7));
+
+        let mut again = image.clone();
+        shuffle_lines(&mut again, WalkPath::Horizontal, Some(7));
+
+        assert_ne!(shuffled, image);
+        assert_eq!(shuffled, again);
+        assert_eq!(rows(&shuffled).into_iter().sorted().collect_vec(), rows(&image).into_iter().sorted().collect_vec());
+    }
+
+    #[test]
+    fn sorting_by_alpha_orders_pixels_of_one_color() {
+        let image = RgbaImage::from_fn(6, 1, |x, _| Rgba([120, 60, 200, 250 - x as u8 * 40]));
+        let sort = |args: &[&str]| {
+            let mut sorted = image.clone();
+            sorted.sort_rgb8_pixels(test_options(args));
+            sorted.pixels().map(|pixel| pixel.0[3]).collect_vec()
+        };
+
+        assert_eq!(sort(&["luma", "-i", "6", "-d", "6", "-p", "1000"]), [250, 210, 170, 130, 90, 50]);
+        assert_eq!(sort(&["luma", "-i", "6", "-d", "6", "-p", "1000", "--sort-alpha", "primary"]), [50, 90, 130, 170, 210, 250]);
+        assert_eq!(sort(&["luma", "-i", "6", "-d", "6", "-p", "1000", "--sort-alpha", "tiebreaker"]), [50, 90, 130, 170, 210, 250]);
+    }
+
+    #[test]
+    fn a_single_interval_in_a_list_builds_the_spans_of_that_interval() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let every_seventh = (0..50u32).step_by(7).map(|start| start..start + 7).collect_vec();
+
+        assert_eq!(test_options(&["luma", "-i", "7"]).interval, vec![7]);
+        assert_eq!(spans(50, &[7], 7, 0, 0, &mut rng), every_seventh);
+        assert_eq!(spans(50, &[7, 7, 7], 7, 0, 0, &mut rng), every_seventh);
+    }
+
+    #[test]
+    fn a_full_percent_interval_sorts_the_whole_line() {
+        let options = test_options(&["luma", "-i", "100", "--interval-unit", "percent", "-d", "1000", "-p", "1000"]);
+
+        for width in [13, 57] {
+            let values = (0..width).map(|x| (250 - x * 4) as u8).collect::<Vec<_>>();
+            let mut image = gray_line(&values);
+            rgb8_pixel_sort(&mut image, options.clone(), None);
+
+            let mut sorted = values;
+            sorted.sort();
+            assert_eq!(gray_values(&image), sorted, "{} pixels wide", width);
+        }
+    }
+
+    #[test]
+    fn the_linear_curve_grows_by_one_every_line() {
+        // the amount the progressive interval had on every line before there were curves
+        let mut amount = 5;
+        for outer in 0..40 {
+            amount += 1;
+            assert_eq!(progressive_amount(ProgressiveCurve::Linear, 5, outer, 40), amount);
+        }
+    }
+
+    #[test]
+    fn no_jitter_keeps_the_regular_boundaries() {
+        let image = noise(20, 2);
+        let options = test_options(&["luma", "-i", "5", "-p", "1000", "--interval-jitter", "0", "--seed", "3"]);
+
+        let regular = (0..2).flat_map(|y| (0..20).step_by(5).map(move |x| (x, y))).collect::<Vec<_>>();
+        assert_eq!(span_boundaries(&image, &options), regular);
+    }
+
+    #[test]
+    fn split_order_sorts_the_dark_pixels_up_and_the_light_ones_down() {
+        let mut image = gray_line(&[200, 10, 150, 90, 250, 40, 120, 60]);
+        rgb8_pixel_sort(&mut image, test_options(&["luma", "-i", "8", "-d", "8", "-p", "1000", "--split-order", "100"]), None);
+
+        assert_eq!(gray_values(&image), [10, 40, 60, 90, 250, 200, 150, 120]);
+    }
+
+    #[test]
+    fn a_full_position_weight_keeps_every_span() {
+        let original = noise(12, 5);
+        let mut image = original.clone();
+        rgb8_pixel_sort(&mut image, test_options(&["hue", "-i", "4", "-d", "4", "-p", "1000", "--position-weight", "1"]), None);
+
+        assert_eq!(image, original);
+    }
+
+    #[test]
+    fn reordering_by_length_puts_the_longest_span_last() {
+        // the end of the ramp is the only edge Laplacian finds, splitting the line into six and three pixels
+        let mut image = gray_line(&[0, 0, 10, 50, 90, 130, 170, 170, 170]);
+        let options = test_options(&[
+            "luma", "-i", "9", "-d", "9", "-p", "1000", "--interval-source", "edge", "-e", "30", "--edge-detector", "laplacian",
+            "--reorder-spans", "length"
+        ]);
+        rgb8_pixel_sort(&mut image, options, None);
+
+        assert_eq!(gray_values(&image), [170, 170, 170, 0, 0, 10, 50, 90, 130]);
+    }
+
+    #[test]
+    fn sorting_a_sorted_gradient_moves_no_pixels() {
+        let gradient = RgbImage::from_fn(16, 4, |x, _| Rgb([(x * 15) as u8; 3]));
+        let mut image = gradient.clone();
+
+        let report = image.sort_rgb8_pixels_reported(test_options(&["luma", "-i", "5", "-d", "5", "-p", "1000"]));
+
+        assert_eq!(image, gradient);
+        assert_eq!(report.spans_total, 16);
+        assert_eq!(report.pixels_moved, 0);
+    }
+
+    #[test]
+    fn pixels_of_the_key_color_keep_their_place() {
+        let green = Rgb([0, 255, 0]);
+        let mut image = RgbImage::from_vec(4, 1, [[90; 3], green.0, [30; 3], [60; 3]].concat()).unwrap();
+        rgb8_pixel_sort(&mut image, test_options(&["luma", "-i", "4", "-d", "4", "-p", "1000", "--key-color", "00ff00"]), None);
+
+        assert_eq!(image.pixels().copied().collect_vec(), [Rgb([30; 3]), green, Rgb([60; 3]), Rgb([90; 3])]);
+    }
+
+    #[test]
+    fn every_interval_source_builds_its_own_spans() {
+        // dark on the left and light on the right, with the only edge between them
+        let image = RgbImage::from_fn(24, 4, |x, _| Rgb([if x < 13 { 20 } else { 220 }; 3]));
+        let starts = |args: &[&str]| {
+            let options = test_options(&[&["luma", "-i", "6", "-p", "1000", "--seed", "3"][..], args].concat());
+            let mut lines = vec![Vec::new(); 4];
+            for (x, y) in span_boundaries(&image, &options) {
+                lines[y as usize].push(x);
+            }
+            lines
+        };
+
+        let value = starts(&["--interval-source", "value"]);
+        assert!(value.iter().all(|line| line == &[0, 6, 12, 18]));
+
+        // broken at the edge as well
+        let edge = starts(&["--interval-source", "edge", "-e", "30"]);
+        for line in &edge {
+            assert!(line.len() > 4 && [0, 6, 12, 18].iter().all(|start| line.contains(start)), "{:?}", line);
+        }
+
+        let random = starts(&["--interval-source", "random"]);
+        assert_ne!(random, value);
+        assert!(random.iter().all(|line| line.windows(2).all(|pair| (1..=6).contains(&(pair[1] - pair[0])))));
+
+        // half the interval, all of it, half again and a single pixel at the trough
+        let wave = starts(&["--interval-source", "wave", "--wave-period", "4"]);
+        let every = |step: usize| (0..24).step_by(step).collect::<Vec<u32>>();
+        assert_eq!(wave, [every(3), every(6), every(3), every(1)]);
+    }
+
+    #[test]
+    fn span_gaps_leave_the_pixels_after_every_span_alone() {
+        let original = noise(40, 3);
+        let sort = |args: &[&str]| {
+            let mut image = original.clone();
+            rgb8_pixel_sort(&mut image, test_options(&[&["hue", "-i", "4", "-d", "4", "--seed", "2"][..], args].concat()), None);
+            image
+        };
+
+        assert_eq!(sort(&["--span-gap", "0"]), sort(&[]));
+
+        // only the first span fits into a line, the gap after it runs to the end
+        let gapped = sort(&["-p", "1000", "--span-gap", "36"]);
+        assert_ne!(gapped, original);
+        for (x, y, pixel) in gapped.enumerate_pixels().filter(|&(x, _, _)| x >= 4) {
+            assert_eq!(pixel, original.get_pixel(x, y));
+        }
+    }
+
+    #[test]
+    fn the_threshold_metric_moves_the_spans_but_not_the_keys() {
+        // a step in luma without one in saturation, every gray is unsaturated
+        let image = RgbImage::from_fn(24, 2, |x, _| Rgb([if x < 13 { 20 } else { 220 }; 3]));
+        let options = |metric: &str| {
+            test_options(&[
+                "hue", "-i", "24", "-d", "24", "-p", "1000", "--interval-source", "edge", "-e", "30", "--threshold-metric", metric
+            ])
+        };
+        let (luma, saturation) = (options("luma"), options("saturation"));
+
+        assert_eq!(span_boundaries(&image, &saturation), [(0, 0), (0, 1)]);
+        assert!(span_boundaries(&image, &luma).len() > 2);
+        assert_eq!(rgb8_key_map(&image, &luma), rgb8_key_map(&image, &saturation));
+    }
+
+    #[test]
+    fn a_max_run_longer_than_the_span_sorts_all_of_it() {
+        let mut image = gray_line(&[200, 30, 170, 90, 250, 60, 140, 10, 110, 220, 40, 180]);
+        rgb8_pixel_sort(&mut image, test_options(&["luma", "-i", "6", "-d", "6", "-p", "1000", "--max-run", "10"]), None);
+
+        assert_eq!(gray_values(&image), [30, 60, 90, 170, 200, 250, 10, 40, 110, 140, 180, 220]);
+    }
+
+    #[test]
+    fn sorting_all_of_a_span_is_a_full_sort_and_none_of_it_a_no_op() {
+        let values = [200, 30, 170, 90, 250, 60, 140, 10, 110, 220, 40, 180];
+        let sort = |fraction: &str| {
+            let mut image = gray_line(&values);
+            rgb8_pixel_sort(&mut image, test_options(&["luma", "-i", "6", "-d", "6", "-p", "1000", "--partial", fraction]), None);
+            gray_values(&image)
+        };
+
+        assert_eq!(sort("1"), [30, 60, 90, 170, 200, 250, 10, 40, 110, 140, 180, 220]);
+        assert_eq!(sort("0"), values);
+    }
+
+    #[test]
+    fn a_red_channel_threshold_leaves_pixels_with_little_red_alone() {
+        // the pixels with less than 100 red keep their place however dark or bright they are
+        let pixels = [[250, 250, 250], [180, 0, 0], [20, 255, 255], [120, 10, 10], [5, 5, 5], [100, 0, 0]];
+        let mut image = RgbImage::from_vec(6, 1, pixels.concat()).unwrap();
+        let options = test_options(&["luma", "-i", "6", "-d", "6", "-p", "1000", "--channel-threshold", "red:100"]);
+        rgb8_pixel_sort(&mut image, options, None);
+
+        let sorted = image.pixels().map(|pixel| pixel.0).collect_vec();
+        assert_eq!(sorted, [[100, 0, 0], [120, 10, 10], [20, 255, 255], [180, 0, 0], [5, 5, 5], [250, 250, 250]]);
+    }
+
+    #[test]
+    fn weak_sorts_at_full_strength_sort_like_the_plain_one() {
+        let mut rng = StdRng::seed_from_u64(3);
+        // few distinct keys, so the order of equal keys shows as well
+        let keyed = (0..200).map(|index| (rng.gen_range(0..20u8), index)).collect::<Vec<_>>();
+
+        for descending in [false, true] {
+            let mut sorted = keyed.clone();
+            sort_keyed(&mut sorted, descending, false);
+
+            let mut weakly = keyed.clone();
+            sort_keyed_weakly(&mut weakly, descending, 1.0, &mut rng);
+            assert_eq!(weakly, sorted, "descending: {}", descending);
+        }
+    }
+
+    #[test]
+    fn ignored_black_pixels_stay_where_they_are() {
+        let mut image = gray_line(&[200, 0, 90, 2, 150, 40]);
+        let options = test_options(&[
+            "luma", "-i", "6", "-d", "6", "-p", "1000", "--ignore-color", "000000", "--ignore-tolerance", "5"
+        ]);
+        rgb8_pixel_sort(&mut image, options, None);
+
+        assert_eq!(gray_values(&image), [40, 0, 90, 2, 150, 200]);
+    }
+
+    #[test]
+    fn quantizing_to_256_keys_changes_nothing_and_to_1_keeps_the_spans() {
+        let original = noise(24, 6);
+        let sort = |args: &[&str]| {
+            let mut image = original.clone();
+            rgb8_pixel_sort(&mut image, test_options(&[&["hue", "-i", "6", "-d", "6", "-p", "1000"][..], args].concat()), None);
+            image
+        };
+
+        assert_ne!(sort(&[]), original);
+        assert_eq!(sort(&["--key-quantize", "256"]), sort(&[]));
+        assert_eq!(sort(&["--key-quantize", "1"]), original);
+    }
+
+    #[test]
+    fn center_out_puts_the_extreme_key_in_the_middle() {
+        let sort = |args: &[&str]| {
+            let mut image = gray_line(&[200, 30, 170, 90, 250, 60, 140]);
+            let options = test_options(&[&["luma", "-i", "7", "-d", "7", "-p", "1000", "--center-out"][..], args].concat());
+            rgb8_pixel_sort(&mut image, options, None);
+            gray_values(&image)
+        };
+
+        assert_eq!(sort(&[]), [200, 140, 60, 30, 90, 170, 250]);
+        assert_eq!(sort(&["--reverse"])[3], 250);
+    }
+
+    #[test]
+    fn only_spans_of_at_least_the_min_span_get_sorted() {
+        let original = noise(24, 4);
+        let sort = |args: &[&str]| {
+            let mut image = original.clone();
+            rgb8_pixel_sort(&mut image, test_options(&[&["hue", "-i", "6", "-d", "6", "-p", "1000"][..], args].concat()), None);
+            image
+        };
+
+        assert_ne!(sort(&[]), original);
+        assert_eq!(sort(&["--min-span", "1"]), sort(&[]));
+        assert_eq!(sort(&["--min-span", "7"]), original);
+    }
+
+    #[test]
+    fn adaptive_thresholds_find_spans_in_the_dark_and_the_bright_half() {
+        // every row is brighter than the one above it, with the same ripple of four values along it
+        let ripple = [36, 0, 24, 12];
+        let original = RgbImage::from_fn(16, 8, |x, y| Rgb([(y * 28 + ripple[x as usize % 4]) as u8; 3]));
+        let changed_rows = |args: &[&str]| {
+            let mut image = original.clone();
+            rgb8_pixel_sort(&mut image, test_options(&[&["luma", "-i", "16", "-d", "16", "-p", "1000"][..], args].concat()), None);
+            (0..8).filter(|&y| (0..16).any(|x| image.get_pixel(x, y) != original.get_pixel(x, y))).collect_vec()
+        };
+
+        // a global threshold between the halves leaves the dark one alone
+        assert_eq!(changed_rows(&["--image-threshold", "128"]), [4, 5, 6, 7]);
+        assert_eq!(changed_rows(&["--adaptive-threshold"]), [0, 1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    /// The fastest of `runs` sorts of copies of `image` by `options`
+    fn fastest_sort(image: &RgbImage, options: &SortOptions, runs: u32) -> Duration {
+        (0..runs)
+            .map(|_| {
+                let mut copy = image.clone();
+                let started = Instant::now();
+                rgb8_pixel_sort(&mut copy, options.clone(), None);
+                started.elapsed()
+            })
+            .min()
+            .unwrap_or_default()
+    }
+
+    /// Benchmark of the serial path against the parallel one on images below [`SERIAL_PIXEL_LIMIT`],
+    /// run with `just bench`
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_small_image_latency() {
+        let options = test_options(&["luma", "-i", "20", "-d", "20", "--seed", "1"]);
+
+        for size in [16, 64, 128, 255] {
+            let image = noise(size, size);
+            let serial = with_serial_pixel_limit(u64::MAX, || fastest_sort(&image, &options, 50));
+            let parallel = with_serial_pixel_limit(0, || fastest_sort(&image, &options, 50));
+
+            println!("{0}x{0}: serial {1:?}, parallel {2:?}", size, serial, parallel);
+        }
+    }
+
+    /// Benchmark of gathering the spans of every line in parallel against gathering them serially,
+    /// on a typical photo sized image with short and long spans, run with `just bench`
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_nested_gather() {
+        let image = noise(1920, 1080);
+
+        for discretize in ["4", "64", "1024"] {
+            let serial = test_options(&["luma", "-i", discretize, "-d", discretize, "--seed", "1"]);
+            let nested = test_options(&["luma", "-i", discretize, "-d", discretize, "--seed", "1", "--parallel-gather"]);
+
+            println!(
+                "-d {}: serial gather {:?}, nested gather {:?}",
+                discretize,
+                fastest_sort(&image, &serial, 5),
+                fastest_sort(&image, &nested, 5)
+            );
+        }
     }
 }
@@ -0,0 +1,191 @@
+use std::{
+    fs::File,
+    io::{BufReader, Write},
+    path::Path
+};
+
+use image::{ImageBuffer, RgbImage, RgbaImage};
+
+use crate::{
+    img::write_atomically,
+    sort::{sort_rgb8_rows, sort_rgba8_rows, SortOptions, SortReport},
+    PxsortError, SortingAlgorithm, WalkPath
+};
+
+/// Sort the PNG at `input` horizontally row by row into the PNG at `output`
+///
+/// Only one row is in memory at a time, so images too large to load can still be sorted.
+/// The input has to be a non-interlaced RGB or RGBA PNG, 16-bit channels are reduced to 8 bits
+pub fn stream_sort(input: &Path, output: &Path, mut options: SortOptions) -> Result<SortReport, PxsortError> {
+    if !matches!(options.direction, WalkPath::Horizontal) {
+        return Err(PxsortError::NotStreamable("only horizontal sorts can be streamed".into()));
+    }
+
+    if [Some(options.by), options.then, options.threshold_metric].iter().any(|by| matches!(by, Some(SortingAlgorithm::Dominant))) {
+        return Err(PxsortError::NotStreamable("the dominant colors are clustered from the whole image".into()));
+    }
+
+    if options.auto_threshold {
+        return Err(PxsortError::NotStreamable("the automatic threshold is picked from the whole image".into()));
+    }
+
+    if [Some(options.by), options.then, options.threshold_metric].iter().any(|by| matches!(by, Some(SortingAlgorithm::Texture))) {
+        return Err(PxsortError::NotStreamable("texture keys look at the rows above and below".into()));
+    }
+
+    let file = File::open(input).map_err(|source| PxsortError::Io { path: input.to_path_buf(), source })?;
+    let mut decoder = png::Decoder::new(BufReader::new(file));
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+
+    let (width, height, interlaced) = {
+        let info = reader.info();
+        (info.width, info.height, info.interlaced)
+    };
+
+    if interlaced {
+        return Err(PxsortError::NotStreamable("interlaced PNGs are not stored row by row".into()));
+    }
+
+    let color = match reader.output_color_type().0 {
+        color @ (png::ColorType::Rgb | png::ColorType::Rgba) => color,
+        other => return Err(PxsortError::NotStreamable(format!("{:?} PNGs are not supported", other)))
+    };
+
+    // warn about the discretize once, not once for every row
+    if options.discretize > width as u64 {
+        log::warn!("discretize ({}) exceeds the line length ({}), clamping it", options.discretize, width);
+        options.discretize = width as u64;
+    }
+
+    write_atomically(output, |file| {
+        let mut encoder = png::Encoder::new(file, width, height);
+        encoder.set_color(color);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        let mut stream = writer.stream_writer()?;
+
+        let mut report = SortReport::default();
+        let mut span_pixels = 0.0;
+
+        for row in 0..height {
+            let data = match reader.next_row()? {
+                Some(data) => data.data().to_vec(),
+                None => break
+            };
+
+            let (row_report, sorted) = match color {
+                png::ColorType::Rgb => {
+                    let mut pixels: RgbImage = row_image(width, data);
+                    (sort_rgb8_rows(&mut pixels, row, height, options.clone()), pixels.into_raw())
+                }
+                _ => {
+                    let mut pixels: RgbaImage = row_image(width, data);
+                    (sort_rgba8_rows(&mut pixels, row, height, options.clone()), pixels.into_raw())
+                }
+            };
+
+            stream
+                .write_all(&sorted)
+                .map_err(|source| PxsortError::Io { path: output.to_path_buf(), source })?;
+
+            report.spans_total += row_report.spans_total;
+            report.pixels_moved += row_report.pixels_moved;
+            report.elapsed += row_report.elapsed;
+            span_pixels += row_report.mean_span_len * row_report.spans_total as f64;
+        }
+
+        stream.finish()?;
+
+        report.mean_span_len = span_pixels / report.spans_total.max(1) as f64;
+        Ok(report)
+    })
+}
+
+/// A one row tall image of the decoded row `data`
+fn row_image<P: image::Pixel<Subpixel = u8>>(width: u32, data: Vec<u8>) -> ImageBuffer<P, Vec<u8>> {
+    ImageBuffer::from_raw(width, 1, data).expect("the decoder returns whole rows")
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GrayImage, Luma, Rgb, Rgba};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::{sort::test_options, PixelSort};
+
+    /// Stream `image` through a PNG on disk and decode the sorted PNG
+    fn stream(image: DynamicImage, options: SortOptions) -> DynamicImage {
+        let dir = tempfile::tempdir().unwrap();
+        let (input, output) = (dir.path().join("in.png"), dir.path().join("out.png"));
+
+        image.save(&input).unwrap();
+        stream_sort(&input, &output, options).unwrap();
+        image::open(&output).unwrap()
+    }
+
+    fn noise(width: u32, height: u32) -> RgbImage {
+        let mut rng = StdRng::seed_from_u64(2);
+        RgbImage::from_fn(width, height, |_, _| Rgb(rng.gen()))
+    }
+
+    #[test]
+    fn streamed_sorts_match_sorting_in_memory() {
+        let image = noise(40, 30);
+        let options = test_options(&["hue", "-i", "9,4", "-d", "6", "--interval-jitter", "2", "--seed", "8"]);
+
+        let mut sorted = image.clone();
+        sorted.sort_rgb8_pixels(options.clone());
+
+        assert_ne!(sorted, image);
+        assert_eq!(stream(image.into(), options).into_rgb8(), sorted);
+    }
+
+    #[test]
+    fn streamed_sorts_look_up_the_mask_row_of_the_whole_image() {
+        let image = noise(40, 30);
+        let mut options = test_options(&["luma", "-i", "10", "-d", "10", "--seed", "8"]);
+        // different on every row, so reading the first rows of the mask for every streamed row shows
+        options.mask = Some(GrayImage::from_fn(40, 30, |x, y| Luma([if (x + 3 * y) % 7 < 4 { 255 } else { 0 }])));
+
+        let mut sorted = image.clone();
+        sorted.sort_rgb8_pixels(options.clone());
+
+        assert_ne!(sorted, image);
+        assert_eq!(stream(image.into(), options).into_rgb8(), sorted);
+    }
+
+    #[test]
+    fn streamed_rgba_sorts_match_sorting_in_memory() {
+        let colors = noise(40, 30);
+        let image = RgbaImage::from_fn(40, 30, |x, y| {
+            let Rgb([red, green, blue]) = *colors.get_pixel(x, y);
+            Rgba([red, green, blue, (x * 6 + y) as u8])
+        });
+        let options = test_options(&["saturation", "-i", "8", "-d", "8", "--sort-alpha", "tiebreaker", "--seed", "8"]);
+
+        let mut sorted = image.clone();
+        sorted.sort_rgb8_pixels(options.clone());
+
+        assert_ne!(sorted, image);
+        assert_eq!(stream(image.into(), options).into_rgba8(), sorted);
+    }
+    #[test]
+    fn keys_of_the_whole_image_cannot_be_streamed() {
+        let dir = tempfile::tempdir().unwrap();
+        let (input, output) = (dir.path().join("in.png"), dir.path().join("out.png"));
+        noise(8, 8).save(&input).unwrap();
+
+        for args in [
+            &["texture"][..],
+            &["luma", "--then", "texture"],
+            &["luma", "--threshold-metric", "texture"],
+            &["dominant"],
+            &["luma", "--then", "dominant"]
+        ] {
+            let streamed = stream_sort(&input, &output, test_options(args));
+            assert!(matches!(streamed, Err(PxsortError::NotStreamable(_))), "{:?}", args);
+        }
+    }
+}